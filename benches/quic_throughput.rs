@@ -0,0 +1,172 @@
+//! Throughput of one file-sized stream between two endpoints on loopback, per [`QuicTuning`].
+//!
+//! Loopback has next to no latency, so its throughput is bound by the CPU and every profile
+//! does about the same. The flow control windows only show once a round trip takes as long as
+//! on a real LAN, so every profile also runs through a relay that delays the datagrams.
+//!
+//! Run with `cargo bench --bench quic_throughput`. `MOJIKA_BENCH_MB` sets how much is sent and
+//! `MOJIKA_BENCH_RTT_MS` the round trip through the relay.
+
+use std::{
+    env,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use mojika::request::endpoint::{create_endpoint, CongestionControl, QuicTuning};
+use quinn::Endpoint;
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    spawn,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, sleep_until},
+};
+
+const BENCH_MB_ENV: &str = "MOJIKA_BENCH_MB";
+const BENCH_RTT_ENV: &str = "MOJIKA_BENCH_RTT_MS";
+/// Same as the file chunks.
+const WRITE_LEN: usize = 200_000;
+const DATAGRAM_LEN: usize = 65_536;
+const RELAY_BUFFER_LEN: usize = 16 * 1024 * 1024;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let megabytes: usize = env_or(BENCH_MB_ENV, 256)?;
+    let rtt = Duration::from_millis(env_or(BENCH_RTT_ENV, 20)?);
+    let bbr = QuicTuning {
+        congestion: CongestionControl::Bbr,
+        ..QuicTuning::lan()
+    };
+    let profiles = [
+        ("conservative", QuicTuning::conservative()),
+        ("lan", QuicTuning::lan()),
+        ("lan + bbr", bbr),
+    ];
+    for delay in [None, Some(rtt / 2)] {
+        match delay {
+            None => println!("Sending {megabytes}MB over loopback"),
+            Some(_) => println!("Sending {megabytes}MB over loopback with a {rtt:?} round trip"),
+        }
+        for (name, tuning) in profiles.iter() {
+            let elapsed = send(tuning, megabytes * 1024 * 1024, delay).await?;
+            let rate = megabytes as f64 / elapsed.as_secs_f64();
+            println!("{name:>14}: {elapsed:>10.2?} {rate:>8.1} MB/s");
+        }
+    }
+    Ok(())
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .ok()
+            .with_context(|| format!("invalid {name}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Sends `len` bytes on one stream and returns how long until the receiver read all of it.
+async fn send(tuning: &QuicTuning, len: usize, delay: Option<Duration>) -> Result<Duration> {
+    let receiver = create_endpoint(0, tuning)?;
+    let mut receiver_addr = SocketAddr::from(([127, 0, 0, 1], receiver.local_addr()?.port()));
+    let sender = create_endpoint(0, tuning)?;
+    let receiving = spawn(receive(receiver.clone()));
+    let relay = match delay {
+        Some(delay) => {
+            let (addr, relay) = delayed_relay(receiver_addr, delay).await?;
+            receiver_addr = addr;
+            Some(relay)
+        }
+        None => None,
+    };
+
+    let connection = sender.connect(receiver_addr, "localhost")?.await?;
+    let start = Instant::now();
+    let (mut send, recv) = connection.open_bi().await?;
+    let chunk = vec![7u8; WRITE_LEN];
+    let mut sent = 0;
+    while sent < len {
+        let n = WRITE_LEN.min(len - sent);
+        send.write_all(&chunk[..n]).await?;
+        sent += n;
+    }
+    send.finish().await?;
+    // The receiver answers with how much it read once the stream ended.
+    let read = u64::from_be_bytes(recv.read_to_end(8).await?.try_into().unwrap());
+    let elapsed = start.elapsed();
+    assert_eq!(read, len as u64);
+
+    connection.close(0u32.into(), b"done");
+    receiving.await??;
+    receiver.close(0u32.into(), b"done");
+    if let Some(relay) = relay {
+        relay.abort();
+    }
+    Ok(elapsed)
+}
+
+async fn receive(endpoint: Endpoint) -> Result<()> {
+    let connection = endpoint.accept().await.context("no connection")?.await?;
+    let (mut send, mut recv) = connection.accept_bi().await?;
+    let mut read = 0u64;
+    while let Some(chunk) = recv.read_chunk(usize::MAX, true).await? {
+        read += chunk.bytes.len() as u64;
+    }
+    send.write_all(&read.to_be_bytes()).await?;
+    send.finish().await?;
+    connection.closed().await;
+    Ok(())
+}
+
+/// Passes datagrams between `server` and whoever else sends to the relay, `delay` late and
+/// in order. Returns the address of the relay.
+async fn delayed_relay(
+    server: SocketAddr,
+    delay: Duration,
+) -> Result<(SocketAddr, JoinHandle<()>)> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    // Datagrams dropped by the relay would be losses the links of a LAN don't have.
+    socket.set_recv_buffer_size(RELAY_BUFFER_LEN)?;
+    socket.set_send_buffer_size(RELAY_BUFFER_LEN)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())?;
+    let socket = Arc::new(UdpSocket::from_std(socket.into())?);
+    let addr = socket.local_addr()?;
+    let (delayed_s, mut delayed) =
+        mpsc::unbounded_channel::<(time::Instant, Vec<u8>, SocketAddr)>();
+    let sending = socket.clone();
+    let forwarding = spawn(async move {
+        while let Some((due, datagram, to)) = delayed.recv().await {
+            if due > time::Instant::now() {
+                sleep_until(due).await;
+            }
+            let _ = sending.send_to(&datagram, to).await;
+        }
+    });
+    let relay = spawn(async move {
+        let mut client = None;
+        let mut buf = vec![0u8; DATAGRAM_LEN];
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            let to = if from == server {
+                match client {
+                    Some(client) => client,
+                    None => continue,
+                }
+            } else {
+                client = Some(from);
+                server
+            };
+            let due = time::Instant::now() + delay;
+            if delayed_s.send((due, buf[..n].to_vec(), to)).is_err() {
+                break;
+            }
+        }
+        forwarding.abort();
+    });
+    Ok((addr, relay))
+}
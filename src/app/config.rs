@@ -0,0 +1,112 @@
+use std::{collections::HashMap, env, path::PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::request::{
+    download::Subfolders, endpoint::QuicTuning, file::CollisionPolicy, scheduler::TransferLimits,
+    transport::memory::MemoryNetwork,
+};
+
+const PORT_ENV: &str = "MOJIKA_PORT";
+const QUIC_PROFILE_ENV: &str = "MOJIKA_QUIC_PROFILE";
+/// Files sent at the same time, as `<per peer>,<global>`.
+const TRANSFERS_ENV: &str = "MOJIKA_TRANSFERS";
+/// What is done with a received file whose name is taken, `rename`, `overwrite` or `ask`.
+const COLLISIONS_ENV: &str = "MOJIKA_COLLISIONS";
+const DOWNLOAD_DIR_ENV: &str = "MOJIKA_DOWNLOAD_DIR";
+const STATE_DIR_ENV: &str = "MOJIKA_STATE_DIR";
+/// Dirs of their own for some peers, as `<peer id>=<dir>,…`.
+const PEER_DIRS_ENV: &str = "MOJIKA_PEER_DIRS";
+/// Subfolders received files are sorted into, `none`, `peer` or `date`.
+const SUBFOLDERS_ENV: &str = "MOJIKA_SUBFOLDERS";
+
+#[derive(Debug, Clone, Default)]
+pub struct AppConfig {
+    /// UDP port of the QUIC endpoint, `0` lets the OS pick a free one.
+    /// The TCP fallback listens on the same port number.
+    pub port: u16,
+    /// Where received files are saved, the `mojika` folder in the user's Downloads if `None`,
+    /// or in their home dir if they have no Downloads.
+    pub download_dir: Option<PathBuf>,
+    /// Where the transfers in progress are kept, the local data dir of Mojika if `None`.
+    /// Received files are never saved there.
+    pub state_dir: Option<PathBuf>,
+    /// Where the files of some peers are saved instead, by peer id.
+    pub peer_download_dirs: HashMap<String, PathBuf>,
+    pub subfolders: Subfolders,
+    pub network: Network,
+    /// Flow control of QUIC connections, [`QuicTuning::lan`] by default.
+    pub quic: QuicTuning,
+    /// How many files are sent at the same time.
+    pub transfers: TransferLimits,
+    /// How a received file is saved when a file already has its name.
+    pub collisions: CollisionPolicy,
+}
+
+/// How peers find and reach each other.
+#[derive(Debug, Clone, Default)]
+pub enum Network {
+    /// Multicast discovery, QUIC with a TCP fallback.
+    #[default]
+    Sockets,
+    /// Channels inside the process, for tests.
+    Memory(MemoryNetwork),
+}
+
+impl AppConfig {
+    /// The default config, overridden by `MOJIKA_*` environment variables.
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+        if let Some(download_dir) = env::var_os(DOWNLOAD_DIR_ENV) {
+            config.download_dir = Some(download_dir.into());
+        }
+        if let Some(state_dir) = env::var_os(STATE_DIR_ENV) {
+            config.state_dir = Some(state_dir.into());
+        }
+        if let Ok(peer_dirs) = env::var(PEER_DIRS_ENV) {
+            config.peer_download_dirs = parse_peer_dirs(&peer_dirs)
+                .with_context(|| format!("invalid {PEER_DIRS_ENV}:{peer_dirs:?}"))?;
+        }
+        if let Ok(subfolders) = env::var(SUBFOLDERS_ENV) {
+            config.subfolders = Subfolders::named(&subfolders)
+                .with_context(|| format!("unknown {SUBFOLDERS_ENV}:{subfolders:?}"))?;
+        }
+        if let Ok(port) = env::var(PORT_ENV) {
+            config.port = port
+                .parse()
+                .with_context(|| format!("invalid {PORT_ENV}:{port:?}"))?;
+        }
+        if let Ok(profile) = env::var(QUIC_PROFILE_ENV) {
+            config.quic = QuicTuning::profile(&profile)
+                .with_context(|| format!("unknown {QUIC_PROFILE_ENV}:{profile:?}"))?;
+        }
+        if let Ok(transfers) = env::var(TRANSFERS_ENV) {
+            config.transfers = parse_transfers(&transfers)
+                .with_context(|| format!("invalid {TRANSFERS_ENV}:{transfers:?}"))?;
+        }
+        if let Ok(collisions) = env::var(COLLISIONS_ENV) {
+            config.collisions = CollisionPolicy::named(&collisions)
+                .with_context(|| format!("unknown {COLLISIONS_ENV}:{collisions:?}"))?;
+        }
+        Ok(config)
+    }
+}
+
+fn parse_transfers(transfers: &str) -> Result<TransferLimits> {
+    let (per_peer, global) = transfers
+        .split_once(',')
+        .context("expected <per peer>,<global>")?;
+    TransferLimits::new(per_peer.trim().parse()?, global.trim().parse()?)
+}
+
+fn parse_peer_dirs(peer_dirs: &str) -> Result<HashMap<String, PathBuf>> {
+    peer_dirs
+        .split(',')
+        .map(|peer_dir| {
+            let (peer_id, dir) = peer_dir
+                .split_once('=')
+                .context("expected <peer id>=<dir>")?;
+            Ok((peer_id.trim().to_owned(), PathBuf::from(dir.trim())))
+        })
+        .collect()
+}
@@ -2,52 +2,77 @@ use std::{
     collections::HashMap,
     fs,
     fs::create_dir,
-    net::{SocketAddr, UdpSocket},
+    io::ErrorKind,
+    net::SocketAddr,
     ops::DerefMut,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 
 use anyhow::{bail, Error, Result};
-use directories::UserDirs;
+use directories::{ProjectDirs, UserDirs};
 use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use tokio::{
     runtime::Runtime,
     spawn,
-    sync::{RwLock, watch},
-    sync::broadcast::Receiver,
-    time::sleep,
+    sync::broadcast::{error::RecvError, Receiver},
+    sync::{watch, RwLock},
+    time::{interval, sleep},
 };
-use tokio::sync::OnceCell;
 use uuid::Uuid;
 
 use crate::{
+    app::config::{AppConfig, Network},
     app::peer::{Peer, Peers},
     app::shutdown::ShutdownWatcher,
-    discovery::{Discovery, DiscoveryResult},
+    discovery::{memory::MemoryDiscovery, Discovery, DiscoveryResult, MulticastDiscovery},
     gui,
     request::{
-        file::CreateFile,
+        control::ControlChannels,
+        download::DownloadDirs,
+        endpoint::{create_endpoint, endpoint_addr, QuicTuning},
+        error::{ErrorCode, MojikaError, RequestError},
+        event::{Event, EventBody},
         file::FileTransfer,
-        FileRequest,
-        Request,
-        RequestBody,
-        requester::Requester, responder::{server, server_addr}, response::{FileResponse, Response, ResponseBody},
+        file::{
+            entry_id, folder_of, scan_folder, CollisionPolicy, CreateFile, CreateFolder, EntryKind,
+            InfoFile, OfferBatch, OutgoingFile, PendingOffer, TransferAction,
+        },
+        registry::{MessageRegistry, OpenStream, RequestRoutes},
+        requester::Requester,
+        responder::server,
+        response::{FileResponse, Response, ResponseBody},
+        transport::{quic::QuicTransport, tcp::TcpTransport, Transport},
+        FileRequest, Request, RequestBody,
     },
 };
 
+pub mod config;
 pub mod event;
 pub mod peer;
 
 const SIGNAL_RATE: Duration = Duration::from_secs(2);
-static UUID_SINGLETON: OnceCell<Uuid> = OnceCell::const_new();
+const HEALTH_CHECK_RATE: Duration = Duration::from_secs(5);
+/// Who we are, kept in the state dir.
+const IDENTITY_FILENAME: &str = "identity.ron";
 
-pub async fn new_id() -> String {
-    let id = UUID_SINGLETON
-        .get_or_init(|| async { Uuid::new_v4() })
-        .await;
-    id.to_string()
+/// The error of a handler given a request it doesn't handle.
+fn unhandled() -> MojikaError {
+    MojikaError::new(ErrorCode::InvalidRequest, "Unhandled request body!")
+}
+
+/// A new id for a transfer, the sender and the receiver both know it by this id.
+pub fn new_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// The id and secret we keep across restarts, so peers know us by the same id.
+#[derive(Debug, Serialize, Deserialize)]
+struct Identity {
+    id: String,
+    secret: String,
 }
 
 #[derive(Debug)]
@@ -56,69 +81,135 @@ pub struct App {
     pub peers: Arc<RwLock<Peers>>,
     pub server_port: u16,
     pub(crate) self_peer: Peer,
+    transports: Vec<Arc<dyn Transport>>,
     requester: Arc<Requester>,
     mojika_dir: PathBuf,
+    state_dir: PathBuf,
     shutdown_watcher: ShutdownWatcher,
     file_transfer: Arc<FileTransfer>,
+    pub(crate) control: Arc<ControlChannels>,
+    pub(crate) messages: MessageRegistry<App>,
+    requests: RequestRoutes<App>,
+    network: Network,
 }
 
 impl App {
-    pub fn new() -> Result<Self> {
+    pub fn new(config: AppConfig) -> Result<Self> {
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap();
 
-        let requester_port = an_open_port().unwrap();
-        let server_port = an_open_port().unwrap();
-        let self_peer = Self::create_self_peer(server_port);
-        info!("Start app on ports: server:{server_port}, requester:{requester_port}");
+        let (transports, self_address) = match &config.network {
+            Network::Sockets => Self::socket_transports(&runtime, config.port, &config.quic)?,
+            Network::Memory(network) => {
+                let addr = network.add_node();
+                let transport: Arc<dyn Transport> = Arc::new(network.transport(addr));
+                (vec![transport], addr)
+            }
+        };
+        let server_port = self_address.port();
+        let mojika_dir = Self::create_mojika_dir(config.download_dir)?;
+        info!("Download dir: {mojika_dir:?}");
+        let state_dir = Self::create_state_dir(config.state_dir)?;
+        info!("State dir: {state_dir:?}");
+        let self_peer = Self::create_self_peer(&state_dir, self_address)?;
+        info!("Start app on port: {server_port}");
         info!("Self Peer:{self_peer:?}");
         let peers = Arc::new(RwLock::new(Peers::new(self_peer.clone())));
 
-        let requester: Arc<Requester> = runtime
-            .block_on(async { Requester::new(requester_port) })?
-            .into();
+        let requester: Arc<Requester> = Requester::new(transports.clone()).into();
 
-        let mojika_dir = Self::create_mojika_dir()?;
-        info!("Download dir: {mojika_dir:?}");
+        let downloads = DownloadDirs {
+            dir: mojika_dir.clone(),
+            peer_dirs: config.peer_download_dirs,
+            subfolders: config.subfolders,
+        };
 
         let shutdown_watcher = runtime.block_on(async { ShutdownWatcher::new() });
 
         let file_transfer = FileTransfer::new(
-            mojika_dir.clone(),
+            downloads,
+            state_dir.clone(),
             requester.clone(),
             peers.clone(),
             self_peer.clone(),
+            config.transfers,
+            config.collisions,
         )
         .into();
 
+        let mut messages = MessageRegistry::new();
+        messages.register(App::dispatch_request)?;
+        messages.register_stream(App::serve_control)?;
+
         Ok(Self {
             runtime,
             peers,
             server_port,
             self_peer,
+            transports,
             requester,
             mojika_dir,
+            state_dir,
             shutdown_watcher,
             file_transfer,
+            control: ControlChannels::new().into(),
+            messages,
+            requests: App::request_routes()?,
+            network: config.network,
         })
     }
 
+    /// QUIC on `port`, and TCP on the same port number if it is free.
+    fn socket_transports(
+        runtime: &Runtime,
+        port: u16,
+        quic: &QuicTuning,
+    ) -> Result<(Vec<Arc<dyn Transport>>, SocketAddr)> {
+        let endpoint = runtime.block_on(async { create_endpoint(port, quic) })?;
+        let server_port = endpoint.local_addr()?.port();
+        let mut transports: Vec<Arc<dyn Transport>> = vec![Arc::new(QuicTransport::new(endpoint))];
+        match runtime.block_on(TcpTransport::bind(server_port)) {
+            Ok(tcp) => transports.push(Arc::new(tcp)),
+            Err(e) => warn!("No TCP fallback, can't listen on TCP port {server_port}: {e:?}"),
+        }
+        Ok((transports, endpoint_addr(server_port)))
+    }
+
     pub fn get_mojika_dir(&self) -> &PathBuf {
         &self.mojika_dir
     }
 
-    fn create_mojika_dir() -> Result<PathBuf> {
-        let user_dirs = UserDirs::new().ok_or(Error::msg("Can not find the UserDirs."))?;
-        let download_dir = user_dirs
-            .download_dir()
-            .ok_or(Error::msg("Can not find the Download directory."))?;
-        let metadata = download_dir.metadata()?;
-        if !metadata.is_dir() || metadata.is_symlink() {
-            bail!("Download directory is not valid. {:?}", metadata);
+    /// Where the transfers in progress are kept.
+    pub fn get_state_dir(&self) -> &PathBuf {
+        &self.state_dir
+    }
+
+    pub fn self_peer(&self) -> &Peer {
+        &self.self_peer
+    }
+
+    fn create_mojika_dir(download_dir: Option<PathBuf>) -> Result<PathBuf> {
+        if let Some(download_dir) = download_dir {
+            fs::create_dir_all(&download_dir)?;
+            return Ok(download_dir);
         }
-        let mut mojika_dir = PathBuf::from(download_dir);
+        let user_dirs = UserDirs::new().ok_or(Error::msg("Can not find the UserDirs."))?;
+        let parent_dir = match user_dirs.download_dir() {
+            Some(download_dir) if download_dir.exists() => {
+                let metadata = download_dir.metadata()?;
+                if !metadata.is_dir() || metadata.is_symlink() {
+                    bail!("Download directory is not valid. {:?}", metadata);
+                }
+                download_dir
+            }
+            _ => {
+                warn!("Can not find the Download directory, use the home directory.");
+                user_dirs.home_dir()
+            }
+        };
+        let mut mojika_dir = PathBuf::from(parent_dir);
         mojika_dir.push("mojika");
         if !mojika_dir.exists() {
             create_dir(mojika_dir.as_path())?;
@@ -126,15 +217,45 @@ impl App {
         Ok(mojika_dir)
     }
 
-    fn create_self_peer(server_port: u16) -> Peer {
-        let id = Uuid::new_v4().to_string();
-        let secret = Uuid::new_v4().to_string();
+    fn create_state_dir(state_dir: Option<PathBuf>) -> Result<PathBuf> {
+        let state_dir = match state_dir {
+            Some(state_dir) => state_dir,
+            None => ProjectDirs::from("", "", "mojika")
+                .ok_or(Error::msg("Can not find the ProjectDirs."))?
+                .data_local_dir()
+                .to_owned(),
+        };
+        fs::create_dir_all(&state_dir)?;
+        Ok(state_dir)
+    }
+
+    /// Ourselves, with the identity of the last run if there was one.
+    fn create_self_peer(state_dir: &Path, address: SocketAddr) -> Result<Peer> {
+        let identity_path = state_dir.join(IDENTITY_FILENAME);
+        let identity = match fs::read_to_string(&identity_path) {
+            Ok(content) => ron::from_str::<Identity>(&content)
+                .map_err(|e| warn!("Invalid identity in {identity_path:?}, use a new one: {e}"))
+                .ok(),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let identity = match identity {
+            Some(identity) => identity,
+            None => {
+                let identity = Identity {
+                    id: Uuid::new_v4().to_string(),
+                    secret: Uuid::new_v4().to_string(),
+                };
+                write_private(&identity_path, &ron::to_string(&identity)?)?;
+                identity
+            }
+        };
         let name = "Buddy".to_string();
-        Peer::new(id, name, secret, server_addr(server_port))
+        Ok(Peer::new(identity.id, name, identity.secret, address))
     }
 
     pub fn start(self: Arc<Self>) -> Result<()> {
-        self.runtime.spawn(self.clone().run_core());
+        self.clone().start_headless();
 
         // spawn(self.clone().run_core(sender));
         gui::new_gui(self).unwrap();
@@ -142,6 +263,16 @@ impl App {
         Ok(())
     }
 
+    /// Runs everything but the GUI.
+    pub fn start_headless(self: Arc<Self>) {
+        self.runtime.spawn(self.clone().run_core());
+    }
+
+    /// Stops serving, discovering and sending files.
+    pub fn shutdown(&self) {
+        self.shutdown_watcher.shutdown();
+    }
+
     // todo fix
     // pub fn stop(self) {
     //     self.runtime.shutdown_timeout(Duration::from_secs(10));
@@ -151,26 +282,39 @@ impl App {
         let shutdown_rx1 = self.shutdown_watcher.subscribe_shutdown();
         let shutdown_rx2 = self.shutdown_watcher.subscribe_shutdown();
 
-        let discovery = Arc::new(Discovery::new(self.self_peer.clone()).await?);
+        let discovery: Arc<dyn Discovery> = match &self.network {
+            Network::Sockets => Arc::new(MulticastDiscovery::new(self.self_peer.clone()).await?),
+            Network::Memory(network) => {
+                Arc::new(MemoryDiscovery::new(network.clone(), &self.self_peer)?)
+            }
+        };
 
         self.clone().run_responder(shutdown_rx2).await;
 
         let app = self.clone();
-        spawn(async move {
+        spawn(
             app.file_transfer
-                .start(app.shutdown_watcher.subscribe_shutdown())
-                .await;
-        });
+                .clone()
+                .start(app.shutdown_watcher.subscribe_shutdown()),
+        );
 
         let d = discovery.clone();
         let app = self.clone();
         spawn(async move {
-            let _ = app.run_client(&d).await;
+            let _ = app.run_client(d.as_ref()).await;
         });
-        self.run_server(&discovery.clone(), shutdown_rx1).await
+
+        spawn(self.clone().run_health_check());
+        spawn(self.clone().run_progress());
+        spawn(self.clone().run_saved());
+        self.run_server(discovery.as_ref(), shutdown_rx1).await
     }
 
-    async fn run_server(&self, discovery: &Discovery, mut shutdown: Receiver<()>) -> Result<()> {
+    async fn run_server(
+        &self,
+        discovery: &dyn Discovery,
+        mut shutdown: Receiver<()>,
+    ) -> Result<()> {
         info!("Server mode.");
 
         loop {
@@ -229,24 +373,27 @@ impl App {
         let peer_id = peer_id.to_string();
 
         self.runtime.spawn(async move {
-            let read_peers = peers.read().await;
-            let peer_op = read_peers.find_by_id(&peer_id).await;
+            let peer_op = peers.read().await.find_by_id(&peer_id).await;
             match peer_op {
                 None => warn!("No peer found with this ID: {peer_id}"),
                 Some(peer) => {
                     debug!("Connecting peer: {peer:?}");
-                    let request = Request::new(
-                        self_peer.id.to_owned(),
-                        self_peer.secret.to_owned(),
-                        RequestBody::Connect,
-                    );
-                    let result = requester.request(peer.address, request).await;
-                    match result {
-                        Ok(_) => {}
+                    let request = connect_request(&self_peer);
+                    let rtt = match requester.round_trip(peer.address, request).await {
+                        Ok((response, rtt)) if response.peer_id == peer_id => Some(rtt),
+                        Ok((response, _)) => {
+                            warn!(
+                                "{} answered the connect sent to {peer_id}",
+                                response.peer_id
+                            );
+                            None
+                        }
                         Err(e) => {
                             error!("QUIC Client error {e:?}");
+                            None
                         }
-                    }
+                    };
+                    peers.write().await.record_reachability(&peer_id, rtt);
                 }
             }
         });
@@ -256,6 +403,134 @@ impl App {
         self.peers.blocking_read().watch_peers()
     }
 
+    /// The files being sent or waiting to be resumed, by id.
+    pub fn watch_outgoing_files(&self) -> watch::Receiver<HashMap<String, OutgoingFile>> {
+        self.file_transfer.watch_outgoing()
+    }
+
+    /// The files being received, by id.
+    pub fn watch_incoming_files(&self) -> watch::Receiver<HashMap<String, InfoFile>> {
+        self.file_transfer.watch_incoming()
+    }
+
+    /// Pauses, resumes or cancels a file we send, the receiver is told about it.
+    pub fn control_outgoing(&self, file_id: &str, action: TransferAction) {
+        let file_transfer = self.file_transfer.clone();
+        let file_id = file_id.to_string();
+        self.runtime.spawn(async move {
+            if let Err(e) = file_transfer.control_outgoing(&file_id, action).await {
+                warn!("Can't {action:?} the file: {e}");
+            }
+        });
+    }
+
+    /// Pauses, resumes or cancels a file we receive, the sender is told about it.
+    pub fn control_incoming(&self, file_id: &str, action: TransferAction) {
+        let file_transfer = self.file_transfer.clone();
+        let file_id = file_id.to_string();
+        self.runtime.spawn(async move {
+            if let Err(e) = file_transfer.control_incoming(&file_id, action).await {
+                warn!("Can't {action:?} the file: {e}");
+            }
+        });
+    }
+
+    /// Pauses, resumes or cancels the files of a folder or batch we send.
+    pub fn control_outgoing_folder(&self, folder_id: &str, action: TransferAction) {
+        let file_transfer = self.file_transfer.clone();
+        let folder_id = folder_id.to_string();
+        self.runtime.spawn(async move {
+            file_transfer
+                .control_outgoing_folder(&folder_id, action)
+                .await;
+        });
+    }
+
+    /// Pauses, resumes or cancels the files of a folder or batch we receive.
+    pub fn control_incoming_folder(&self, folder_id: &str, action: TransferAction) {
+        let file_transfer = self.file_transfer.clone();
+        let folder_id = folder_id.to_string();
+        self.runtime.spawn(async move {
+            file_transfer
+                .control_incoming_folder(&folder_id, action)
+                .await;
+        });
+    }
+
+    /// Saves a received file that waits for a name, see [CollisionPolicy::Ask].
+    pub fn resolve_collision(&self, file_id: &str, policy: CollisionPolicy) {
+        let file_transfer = self.file_transfer.clone();
+        let file_id = file_id.to_string();
+        self.runtime.spawn(async move {
+            if let Err(e) = file_transfer.resolve_collision(&file_id, policy).await {
+                warn!("Can't save the file with {policy:?}: {e}");
+            }
+        });
+    }
+
+    /// The batches peers offered us, waiting for [App::answer_offer].
+    pub fn watch_offers(&self) -> watch::Receiver<HashMap<String, PendingOffer>> {
+        self.file_transfer.watch_offers()
+    }
+
+    /// Accepts or declines all the files of a batch, and tells the peer that offered it.
+    pub fn answer_offer(&self, batch_id: &str, accepted: bool) {
+        let peers = self.peers.clone();
+        let requester = self.requester.clone();
+        let self_peer = self.self_peer.clone();
+        let file_transfer = self.file_transfer.clone();
+        let batch_id = batch_id.to_string();
+        self.runtime.spawn(async move {
+            let result = if accepted {
+                file_transfer.accept_batch(&batch_id).await
+            } else {
+                file_transfer.decline_batch(&batch_id)
+            };
+            let offer = match result {
+                Ok(offer) => offer,
+                Err(e) => {
+                    warn!("Can't answer the offer {batch_id}: {e}");
+                    return;
+                }
+            };
+            let peer_address = peers.read().await.find_peer_address(&offer.peer_id).await;
+            let request = Request::new(
+                self_peer.id.to_owned(),
+                self_peer.secret.to_owned(),
+                RequestBody::File(FileRequest::AnswerOffer {
+                    batch_id: batch_id.to_owned(),
+                    accepted,
+                }),
+            );
+            let answered = match peer_address {
+                Some(peer_address) => {
+                    request_as_known_peer(&requester, &self_peer, peer_address, request)
+                        .await
+                        .map_err(Error::from)
+                }
+                None => Err(MojikaError::unknown_peer(&offer.peer_id).into()),
+            };
+            let progress = match answered {
+                Ok(_) if accepted => "Accepted.",
+                Ok(_) => "Declined.",
+                Err(e) => {
+                    warn!("Can't answer the offer of {}: {e}", offer.peer_id);
+                    if !accepted {
+                        return;
+                    }
+                    // The sender won't send them.
+                    file_transfer.drop_batch(&batch_id, &offer).await;
+                    "Not accepted."
+                }
+            };
+            peers.write().await.update_file_progress(
+                &offer.peer_id,
+                &batch_id,
+                progress.to_string(),
+            );
+        });
+    }
+
     pub fn send_chat(&self, peer_id: &str, sender_id: &str, chat: String) {
         let peers = self.peers.clone();
         let requester = self.requester.clone();
@@ -263,21 +538,42 @@ impl App {
         let self_peer = self.self_peer.clone();
         let sender_id = sender_id.to_string();
         self.runtime.spawn(async move {
-            let mut write_peers = peers.write().await;
             let request = Request::new(
                 self_peer.id.to_owned(),
                 self_peer.secret.to_owned(),
                 RequestBody::Chat(chat.to_owned()),
             );
-            write_peers.add_chat(&peer_id, &sender_id, chat);
-            if let Some(peer_address) = write_peers.find_peer_address(&peer_id).await {
-                let result = requester.request(peer_address, request).await;
-                debug!("send chat result:{result:?}");
+            let peer_address = {
+                let mut write_peers = peers.write().await;
+                if let Err(e) = write_peers.add_chat(&peer_id, &sender_id, chat) {
+                    warn!("Can't add the chat: {e}");
+                    return;
+                }
+                write_peers.find_peer_address(&peer_id).await
+            };
+            if let Some(peer_address) = peer_address {
+                let result =
+                    request_as_known_peer(&requester, &self_peer, peer_address, request).await;
+                match result {
+                    Ok(_) => debug!("Chat sent to {peer_id}"),
+                    Err(RequestError::Timeout(t)) => {
+                        warn!("No answer to the chat in {t:?}, {peer_id} may not have it")
+                    }
+                    Err(RequestError::Refused(e)) => {
+                        warn!("Can't reach {peer_id}, the chat is not delivered: {e}")
+                    }
+                    Err(e) => warn!("Sending the chat to {peer_id} failed: {e}"),
+                }
             }
         });
     }
 
+    /// Offers the file, or the folder with everything in it, and sends it once the peer accepts.
     pub fn send_file(&self, peer_id: &str, sender_id: &str, file_path: PathBuf) {
+        if file_path.is_dir() {
+            self.send_folder(peer_id, sender_id, file_path);
+            return;
+        }
         let Some(filename) = file_path.file_name() else {
             warn!("cant find the filename:{file_path:?}");
             return;
@@ -287,17 +583,6 @@ impl App {
             return;
         };
 
-        // read file length
-        let Ok(metadata) = fs::metadata(&file_path) else {
-            warn!("cant read the file metadata:{file_path:?}");
-            return;
-        };
-        let file_length = metadata.len();
-
-        let file = CreateFile {
-            filename,
-            file_length,
-        };
         let peers = self.peers.clone();
         let requester = self.requester.clone();
         let peer_id = peer_id.to_string();
@@ -306,34 +591,213 @@ impl App {
         let file_path = file_path.to_owned();
         let file_transfer = self.file_transfer.clone();
         self.runtime.spawn(async move {
-            let mut write_peers = peers.write().await;
+            let file = match CreateFile::of(&file_path, filename).await {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Can't read the file {file_path:?}: {e:?}");
+                    return;
+                }
+            };
             let create_file_request = Request::new(
                 self_peer.id.to_owned(),
                 self_peer.secret.to_owned(),
                 RequestBody::File(FileRequest::CreateFile(file.clone())),
             );
-            write_peers.add_file(&peer_id, &sender_id, file);
-            if let Some(peer) = write_peers.find_by_id(&peer_id).await {
-                let result = requester.request(peer.address, create_file_request).await;
-                debug!("send chat result:{result:?}");
-                if let Ok(r) = result {
-                    if let ResponseBody::File(FileResponse::FileCreated(file_id)) = r.body {
-                        debug!("Got FileCreated response!");
-                        file_transfer
-                            .send_created_file(file_id, file_path, peer_id.clone())
-                            .await
+            let Some(peer) = peers.read().await.find_by_id(&peer_id).await else {
+                warn!("No peer found to send the file to: {peer_id}");
+                return;
+            };
+            let result =
+                request_as_known_peer(&requester, &self_peer, peer.address, create_file_request)
+                    .await;
+            debug!("send create file result:{result:?}");
+            let file_id = match result {
+                Ok(r) => match r.body {
+                    ResponseBody::File(FileResponse::FileCreated(file_id)) => file_id,
+                    body => {
+                        warn!("Unexpected response to the file:{body:?}");
+                        return;
+                    }
+                },
+                Err(e) => {
+                    warn_refused_offer(&peer_id, e);
+                    return;
+                }
+            };
+            // Added with the id the peer gave the file, so both chats show the same transfer.
+            let added = peers
+                .write()
+                .await
+                .add_file(&peer_id, &sender_id, &file_id, file.clone());
+            if let Err(e) = added {
+                warn!("Can't add the file: {e}");
+            }
+            file_transfer
+                .send_created_file(file_id, file, file_path, &peer)
+                .await
+        });
+    }
+
+    /// Sends the files as one batch the peer accepts or declines at once.
+    ///
+    /// Folders are sent on their own, a single file is sent as with [App::send_file].
+    pub fn send_files(&self, peer_id: &str, sender_id: &str, paths: Vec<PathBuf>) {
+        let (folders, mut files): (Vec<_>, Vec<_>) = paths.into_iter().partition(|p| p.is_dir());
+        for folder_path in folders {
+            self.send_folder(peer_id, sender_id, folder_path);
+        }
+        match files.len() {
+            0 => {}
+            1 => self.send_file(peer_id, sender_id, files.remove(0)),
+            _ => self.send_batch(peer_id, sender_id, files),
+        }
+    }
+
+    fn send_batch(&self, peer_id: &str, sender_id: &str, paths: Vec<PathBuf>) {
+        let peers = self.peers.clone();
+        let requester = self.requester.clone();
+        let peer_id = peer_id.to_string();
+        let self_peer = self.self_peer.clone();
+        let sender_id = sender_id.to_string();
+        let file_transfer = self.file_transfer.clone();
+        self.runtime.spawn(async move {
+            let mut files = Vec::with_capacity(paths.len());
+            for path in paths {
+                let Some(filename) = path.file_name().and_then(|f| f.to_str()) else {
+                    warn!("cant get the filename as string:{path:?}");
+                    return;
+                };
+                match CreateFile::of(&path, filename.to_string()).await {
+                    Ok(file) => files.push((file, path)),
+                    Err(e) => {
+                        warn!("Can't read the file {path:?}: {e:?}");
+                        return;
                     }
                 }
             }
+            let batch = OfferBatch {
+                id: new_id(),
+                files: files.iter().map(|(file, _)| file.clone()).collect(),
+            };
+            let batch_id = batch.id.to_owned();
+            let offer_batch_request = Request::new(
+                self_peer.id.to_owned(),
+                self_peer.secret.to_owned(),
+                RequestBody::File(FileRequest::OfferBatch(batch.clone())),
+            );
+            let Some(peer) = peers.read().await.find_by_id(&peer_id).await else {
+                warn!("No peer found to send the files to: {peer_id}");
+                return;
+            };
+            let address = peer.address;
+            // Kept before it is offered, the peer may answer before the offer returns.
+            file_transfer.offered_batch(&batch_id, peer, files).await;
+            let added = peers
+                .write()
+                .await
+                .add_batch(&peer_id, &sender_id, &batch_id, &batch);
+            if let Err(e) = added {
+                warn!("Can't add the batch: {e}");
+            }
+            let result =
+                request_as_known_peer(&requester, &self_peer, address, offer_batch_request).await;
+            debug!("send offer batch result:{result:?}");
+            match result {
+                Ok(_) => {}
+                Err(e) => {
+                    warn_refused_offer(&peer_id, e);
+                    file_transfer.forget_offered(&batch_id).await;
+                    peers.write().await.update_file_progress(
+                        &peer_id,
+                        &batch_id,
+                        "Not offered.".to_string(),
+                    );
+                }
+            }
         });
     }
 
-    async fn run_client(&self, discovery: &Discovery) -> Result<()> {
+    fn send_folder(&self, peer_id: &str, sender_id: &str, folder_path: PathBuf) {
+        let peers = self.peers.clone();
+        let requester = self.requester.clone();
+        let peer_id = peer_id.to_string();
+        let self_peer = self.self_peer.clone();
+        let sender_id = sender_id.to_string();
+        let file_transfer = self.file_transfer.clone();
+        self.runtime.spawn(async move {
+            let (folder, paths) = match scan_folder(&folder_path).await {
+                Ok(scanned) => scanned,
+                Err(e) => {
+                    warn!("Can't read the folder {folder_path:?}: {e:?}");
+                    return;
+                }
+            };
+            let create_folder_request = Request::new(
+                self_peer.id.to_owned(),
+                self_peer.secret.to_owned(),
+                RequestBody::File(FileRequest::CreateFolder(folder.clone())),
+            );
+            let Some(peer) = peers.read().await.find_by_id(&peer_id).await else {
+                warn!("No peer found to send the folder to: {peer_id}");
+                return;
+            };
+            let result =
+                request_as_known_peer(&requester, &self_peer, peer.address, create_folder_request)
+                    .await;
+            debug!("send create folder result:{result:?}");
+            let (folder_id, name) = match result {
+                Ok(r) => match r.body {
+                    ResponseBody::File(FileResponse::FolderCreated { folder_id, name }) => {
+                        (folder_id, name)
+                    }
+                    body => {
+                        warn!("Unexpected response to the folder:{body:?}");
+                        return;
+                    }
+                },
+                Err(e) => {
+                    warn_refused_offer(&peer_id, e);
+                    return;
+                }
+            };
+            let folder = CreateFolder { name, ..folder };
+            let added = peers
+                .write()
+                .await
+                .add_folder(&peer_id, &sender_id, &folder_id, &folder);
+            if let Err(e) = added {
+                warn!("Can't add the folder: {e}");
+            }
+            for (index, (entry, path)) in folder.entries.into_iter().zip(paths).enumerate() {
+                // Empty files are created with the folder.
+                let EntryKind::File { file_length, hash } = entry.kind else {
+                    continue;
+                };
+                if file_length == 0 {
+                    continue;
+                }
+                let file = CreateFile {
+                    filename: format!("{}/{}", folder.name, entry.path),
+                    file_length,
+                    hash,
+                    mode: entry.mode,
+                };
+                let file_id = entry_id(&folder_id, index);
+                file_transfer
+                    .send_created_file(file_id, file, path, &peer)
+                    .await;
+            }
+        });
+    }
+
+    async fn run_client(&self, discovery: &dyn Discovery) -> Result<()> {
         info!("Sending signal.");
         let mut shutdown = self.shutdown_watcher.subscribe_shutdown();
+        // The first signal goes out right away.
+        let mut signals = interval(SIGNAL_RATE);
         loop {
             tokio::select! {
-                _ = sleep(SIGNAL_RATE) => {
+                _ = signals.tick() => {
                     discovery.send_signal().await?;
                 }
                 res = shutdown.recv() => {
@@ -350,92 +814,483 @@ impl App {
         Ok(())
     }
 
-    async fn run_responder(self: Arc<Self>, shutdown: Receiver<()>) {
-        info!("Run Transfer");
-        spawn(server(self, shutdown));
-        // let server1 = server(shutdown).await;
+    async fn run_health_check(self: Arc<Self>) {
+        let mut shutdown = self.shutdown_watcher.subscribe_shutdown();
+        loop {
+            tokio::select! {
+                _ = sleep(HEALTH_CHECK_RATE) => {
+                    let peers = self.peers.read().await.all().await;
+                    for peer in peers {
+                        spawn(self.clone().ping_peer(peer));
+                    }
+                }
+                res = shutdown.recv() => {
+                    debug!("Got {res:?} for shutdown the health check");
+                    break
+                }
+            }
+        }
     }
 
-    pub async fn dispatch_request(self: Arc<Self>, request: Request) -> Response {
-        let peers = self.peers.clone();
-        let mut write_peers = peers.write().await;
-        match request.body {
-            RequestBody::Chat(chat) => {
-                write_peers.add_chat(&request.peer_id, &request.peer_id, chat);
-                return Response::create_ok_response(
-                    self.self_peer.id.to_owned(),
-                    self.self_peer.secret.to_owned(),
-                );
+    /// Shows the progress of the files we send and receive in their chat messages.
+    async fn run_progress(self: Arc<Self>) {
+        let mut progress = self.file_transfer.subscribe_progress();
+        let mut shutdown = self.shutdown_watcher.subscribe_shutdown();
+        loop {
+            tokio::select! {
+                res = progress.recv() => match res {
+                    Ok(progress) => self.peers.write().await.update_transfer(&progress),
+                    Err(RecvError::Lagged(missed)) => debug!("Missed {missed} progress updates"),
+                    Err(RecvError::Closed) => break,
+                },
+                res = shutdown.recv() => {
+                    debug!("Got {res:?} for shutdown the progress");
+                    break
+                }
             }
-            RequestBody::File(file) => {
-                match file {
-                    FileRequest::CreateFile(f) => {
-                        let f1 = f.to_owned();
-                        let Ok(file_id) = self
-                            .file_transfer
-                            .create_file(f.filename, f.file_length)
-                            .await
-                        else {
-                            return Response::new(
-                                self.self_peer.id.clone(),
-                                self.self_peer.secret.clone(),
-                                ResponseBody::Err("Unhandled request body!".to_string()),
-                            );
-                        };
-                        write_peers.add_file(&request.peer_id, &request.peer_id, f1);
-                        return Response::new(
-                            self.self_peer.id.to_owned(),
-                            self.self_peer.secret.to_owned(),
-                            ResponseBody::File(FileResponse::FileCreated(file_id)),
+        }
+    }
+
+    /// Shows the name each received file was saved as, and tells its sender.
+    async fn run_saved(self: Arc<Self>) {
+        let mut saved = self.file_transfer.subscribe_saved();
+        let mut shutdown = self.shutdown_watcher.subscribe_shutdown();
+        loop {
+            tokio::select! {
+                res = saved.recv() => match res {
+                    Ok(saved) => {
+                        self.peers.write().await.set_filename(
+                            &saved.peer_id,
+                            &saved.file_id,
+                            saved.filename.to_owned(),
                         );
-                    }
-                    FileRequest::FileChunk(r) => {
-                        debug!("Got file chunk request {r:?}");
-                        let result = self.file_transfer.write_file_chunk(r).await;
-                        // write_peers.update_file_status(&request.peer_id, &request.peer_id, f)
-                        return match result {
-                            Ok(_) => Response::create_ok_response(
-                                self.self_peer.id.to_owned(),
-                                self.self_peer.secret.to_owned(),
-                            ),
-                            Err(e) => {
-                                warn!("error saving the file chunk:{:?}", e);
-                                Response::new(
-                                    self.self_peer.id.to_owned(),
-                                    self.self_peer.secret.to_owned(),
-                                    ResponseBody::Err(e.to_string()),
-                                )
-                            }
+                        let body = EventBody::FileSaved {
+                            file_id: saved.file_id,
+                            filename: saved.filename,
                         };
+                        spawn(self.clone().push_event(saved.peer_id, body));
                     }
-                    _ => {}
+                    Err(RecvError::Lagged(missed)) => warn!("Missed {missed} saved files"),
+                    Err(RecvError::Closed) => break,
+                },
+                res = shutdown.recv() => {
+                    debug!("Got {res:?} for shutdown the saved files");
+                    break
                 }
             }
-            RequestBody::Connect => {
-                return Response::create_ok_response(
-                    self.self_peer.id.clone(),
-                    self.self_peer.secret.clone(),
-                );
+        }
+    }
+
+    async fn ping_peer(self: Arc<Self>, peer: Peer) {
+        let request = Request::new(
+            self.self_peer.id.to_owned(),
+            self.self_peer.secret.to_owned(),
+            RequestBody::Ping,
+        );
+        let rtt = match self.requester.ping(peer.address, request).await {
+            Ok(rtt) => Some(rtt),
+            Err(e) => {
+                debug!("Ping to {peer} failed: {e}");
+                None
             }
-            _ => {
-                return Response::new(
-                    self.self_peer.id.clone(),
-                    self.self_peer.secret.clone(),
-                    ResponseBody::Err("Unhandled request body!".to_string()),
-                );
+        };
+        self.peers.write().await.record_reachability(&peer.id, rtt);
+    }
+
+    async fn run_responder(self: Arc<Self>, shutdown: Receiver<()>) {
+        info!("Run Transfer");
+        let transports = self.transports.clone();
+        spawn(server(self, transports, shutdown));
+        // let server1 = server(shutdown).await;
+    }
+
+    /// Handles a request that came from `remote_addr`.
+    async fn dispatch_request(
+        self: Arc<Self>,
+        request: Request,
+        remote_addr: SocketAddr,
+    ) -> Result<ResponseBody> {
+        if !matches!(
+            request.body,
+            RequestBody::Connect { .. } | RequestBody::Ping
+        ) {
+            self.peers
+                .read()
+                .await
+                .authenticate(&request.peer_id, &request.secret)?;
+        }
+        // Requests are handled concurrently, only lock the peers while updating them.
+        self.requests
+            .route(self.clone(), request, remote_addr)
+            .await
+    }
+
+    /// Makes the stream a peer opened with `first` its control stream.
+    async fn serve_control(self: Arc<Self>, first: Event, stream: OpenStream) {
+        self.control.clone().serve(self, first, stream).await
+    }
+
+    /// Routes every kind of request this app handles to its handler.
+    fn request_routes() -> Result<RequestRoutes<App>> {
+        let mut routes = RequestRoutes::new();
+        routes.register("Connect", App::connected)?;
+        routes.register("Ping", |_, _, _| async { Ok(ResponseBody::Pong) })?;
+        routes.register("Chat", App::receive_chat)?;
+        routes.register("CreateFile", App::create_file)?;
+        routes.register("CreateFolder", App::create_folder)?;
+        routes.register("OfferBatch", App::offer_batch)?;
+        routes.register("AnswerOffer", App::batch_answered)?;
+        routes.register("FileChunk", App::write_file_chunk)?;
+        routes.register("SenderAction", App::sender_action)?;
+        routes.register("ReceiverAction", App::receiver_action)?;
+        routes.register("QueryOffset", App::query_offset)?;
+        Ok(routes)
+    }
+
+    async fn connected(
+        self: Arc<Self>,
+        request: Request,
+        remote_addr: SocketAddr,
+    ) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            secret,
+            body: RequestBody::Connect { name, service_port },
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        let address = SocketAddr::new(remote_addr.ip(), service_port);
+        let peer = Peer::new(peer_id.to_owned(), name, secret, address);
+        debug!("Got connect from {peer:?}");
+        let new_secret = self.peers.write().await.register_connected(peer)?;
+        if new_secret {
+            // Our secret only goes in our own connect, the peer may not have discovered us.
+            self.connect_to_peer(&peer_id);
+        }
+        Ok(ResponseBody::Ok)
+    }
+
+    async fn receive_chat(
+        self: Arc<Self>,
+        request: Request,
+        _: SocketAddr,
+    ) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            body: RequestBody::Chat(chat),
+            ..
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        self.peers
+            .write()
+            .await
+            .add_chat(&peer_id, &peer_id, chat)
+            .map(|_| ResponseBody::Ok)
+    }
+
+    async fn sender_action(
+        self: Arc<Self>,
+        request: Request,
+        _: SocketAddr,
+    ) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            body: RequestBody::File(FileRequest::SenderAction { file_id, action }),
+            ..
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        self.file_transfer
+            .sender_action(&peer_id, &file_id, action)
+            .await
+            .map(|_| ResponseBody::Ok)
+    }
+
+    async fn receiver_action(
+        self: Arc<Self>,
+        request: Request,
+        _: SocketAddr,
+    ) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            body: RequestBody::File(FileRequest::ReceiverAction { file_id, action }),
+            ..
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        self.file_transfer
+            .receiver_action(&peer_id, &file_id, action)
+            .await
+            .map(|_| ResponseBody::Ok)
+    }
+
+    async fn query_offset(
+        self: Arc<Self>,
+        request: Request,
+        _: SocketAddr,
+    ) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            body: RequestBody::File(FileRequest::QueryOffset(file_id)),
+            ..
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        self.file_transfer
+            .content_offset(&peer_id, &file_id)
+            .await
+            .map(|offset| ResponseBody::File(FileResponse::ContentOffset(offset)))
+    }
+
+    /// Answers a message with the result of handling it.
+    pub(crate) fn response(&self, result: Result<ResponseBody>) -> Response {
+        let body = result.unwrap_or_else(|e| {
+            warn!("error handling the request:{e:?}");
+            ResponseBody::Err(MojikaError::from(&e))
+        });
+        Response::new(self.self_peer.id.clone(), body)
+    }
+
+    async fn write_file_chunk(
+        self: Arc<Self>,
+        request: Request,
+        _: SocketAddr,
+    ) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            body: RequestBody::File(FileRequest::FileChunk(file_chunk)),
+            ..
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        debug!("Got file chunk request {file_chunk:?}");
+        let info_file = self
+            .file_transfer
+            .write_file_chunk(&peer_id, file_chunk)
+            .await?;
+        if info_file.content_offset < info_file.file_length {
+            return Ok(ResponseBody::Ok);
+        }
+        // A folder is completed with the last of its files.
+        let file_id = match folder_of(&info_file.id) {
+            Some((folder_id, _)) if self.file_transfer.receiving_folder(folder_id) => {
+                return Ok(ResponseBody::Ok);
             }
+            Some((folder_id, _)) => folder_id.to_owned(),
+            None => info_file.id,
+        };
+        let body = EventBody::TransferCompleted { file_id };
+        spawn(self.push_event(peer_id.to_string(), body));
+        Ok(ResponseBody::Ok)
+    }
+
+    async fn create_folder(
+        self: Arc<Self>,
+        request: Request,
+        _: SocketAddr,
+    ) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            body: RequestBody::File(FileRequest::CreateFolder(folder)),
+            ..
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        let peer_id = &peer_id;
+        if self.peers.read().await.find_by_id(peer_id).await.is_none() {
+            return Err(MojikaError::unknown_peer(peer_id).into());
         }
-        Response::new(
-            self.self_peer.id.clone(),
-            self.self_peer.secret.clone(),
-            ResponseBody::Err("Don't know how to handle your request!?".to_string()),
-        )
+        let (folder_id, name) = self
+            .file_transfer
+            .create_folder(peer_id, folder.clone())
+            .await?;
+        let folder = CreateFolder { name, ..folder };
+        self.peers
+            .write()
+            .await
+            .add_folder(peer_id, peer_id, &folder_id, &folder)?;
+        let name = folder.name;
+        Ok(ResponseBody::File(FileResponse::FolderCreated {
+            folder_id,
+            name,
+        }))
+    }
+
+    async fn offer_batch(self: Arc<Self>, request: Request, _: SocketAddr) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            body: RequestBody::File(FileRequest::OfferBatch(batch)),
+            ..
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        let peer_id = &peer_id;
+        if self.peers.read().await.find_by_id(peer_id).await.is_none() {
+            return Err(MojikaError::unknown_peer(peer_id).into());
+        }
+        self.file_transfer
+            .offer_batch(peer_id, batch.clone())
+            .await?;
+        self.peers
+            .write()
+            .await
+            .add_batch(peer_id, peer_id, &batch.id, &batch)?;
+        Ok(ResponseBody::Ok)
+    }
+
+    async fn batch_answered(
+        self: Arc<Self>,
+        request: Request,
+        _: SocketAddr,
+    ) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            body: RequestBody::File(FileRequest::AnswerOffer { batch_id, accepted }),
+            ..
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        self.file_transfer
+            .batch_answered(&peer_id, &batch_id, accepted)
+            .await?;
+        let progress = if accepted { "Accepted." } else { "Declined." };
+        self.peers
+            .write()
+            .await
+            .update_file_progress(&peer_id, &batch_id, progress.to_string());
+        Ok(ResponseBody::Ok)
+    }
+
+    async fn create_file(self: Arc<Self>, request: Request, _: SocketAddr) -> Result<ResponseBody> {
+        let Request {
+            peer_id,
+            body: RequestBody::File(FileRequest::CreateFile(file)),
+            ..
+        } = request
+        else {
+            bail!(unhandled())
+        };
+        let peer_id = &peer_id;
+        if self.peers.read().await.find_by_id(peer_id).await.is_none() {
+            return Err(MojikaError::unknown_peer(peer_id).into());
+        }
+        let file_id = self
+            .file_transfer
+            .create_file(peer_id, file.clone())
+            .await?;
+        self.peers
+            .write()
+            .await
+            .add_file(peer_id, peer_id, &file_id, file)?;
+        Ok(ResponseBody::File(FileResponse::FileCreated(file_id)))
+    }
+}
+
+impl App {
+    /// Tells the peer we are writing a chat to it.
+    pub fn send_typing(self: &Arc<Self>, peer_id: &str) {
+        self.runtime.spawn(
+            self.clone()
+                .push_event(peer_id.to_string(), EventBody::Typing),
+        );
+    }
+
+    async fn push_event(self: Arc<Self>, peer_id: String, body: EventBody) {
+        let Some(address) = self.peers.read().await.find_peer_address(&peer_id).await else {
+            warn!("No peer found to push the event to: {peer_id}");
+            return;
+        };
+        let event = Event::new(
+            self.self_peer.id.to_owned(),
+            self.self_peer.secret.to_owned(),
+            body,
+        );
+        let control = self.control.clone();
+        let result = control
+            .send(self.clone(), &self.requester, &peer_id, address, event)
+            .await;
+        if let Err(e) = result {
+            warn!("Can't push the event to {peer_id}: {e:?}");
+        }
+    }
+
+    /// Handles an event, it fails without handling it if the event doesn't carry its peer's secret.
+    pub async fn dispatch_event(self: Arc<Self>, event: Event) -> Result<()> {
+        debug!("Got event:{event:?}");
+        let mut peers = self.peers.write().await;
+        peers.authenticate(&event.peer_id, &event.secret)?;
+        match event.body {
+            EventBody::Typing => peers.set_typing(&event.peer_id),
+            EventBody::TransferCompleted { file_id } => {
+                peers.update_file_progress(&event.peer_id, &file_id, "Completed.".to_string());
+            }
+            EventBody::FileSaved { file_id, filename } => {
+                peers.set_filename(&event.peer_id, &file_id, filename);
+            }
+        }
+        Ok(())
     }
 }
 
-pub fn an_open_port() -> Result<u16> {
-    let udp_socket = UdpSocket::bind("127.0.0.1:0")?;
-    Ok(udp_socket.local_addr()?.port())
+/// Writes a file only the user can read, it holds our secret.
+fn write_private(path: &Path, content: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, content.as_bytes())?;
+    Ok(())
+}
+
+fn warn_refused_offer(peer_id: &str, e: RequestError) {
+    match e {
+        RequestError::Remote(e) => match e.code {
+            ErrorCode::DiskFull => warn!("{peer_id} has no space for the file: {e}"),
+            ErrorCode::FileExists => warn!("{peer_id} already has the file: {e}"),
+            _ => warn!("{peer_id} refused the file: {e}"),
+        },
+        e => warn!("Can't offer the file to {peer_id}: {e}"),
+    }
+}
+
+/// Introduces `self_peer` to the peer it is sent to.
+fn connect_request(self_peer: &Peer) -> Request {
+    Request::new(
+        self_peer.id.to_owned(),
+        self_peer.secret.to_owned(),
+        RequestBody::Connect {
+            name: self_peer.name.to_owned(),
+            service_port: self_peer.address.port(),
+        },
+    )
+}
+
+/// Sends `request`, introducing ourselves first if the peer doesn't know us yet.
+pub(crate) async fn request_as_known_peer(
+    requester: &Requester,
+    self_peer: &Peer,
+    address: SocketAddr,
+    request: Request,
+) -> Result<Response, RequestError> {
+    let result = requester.request(address, request.clone()).await;
+    if result.as_ref().err().and_then(RequestError::remote_code) != Some(ErrorCode::UnknownPeer) {
+        return result;
+    }
+    debug!("{address:?} doesn't know us, connecting before retrying");
+    requester
+        .request(address, connect_request(self_peer))
+        .await?;
+    requester.request(address, request).await
 }
 
 mod shutdown {
@@ -465,6 +1320,10 @@ mod shutdown {
             });
         }
 
+        pub fn shutdown(&self) {
+            let _ = self.sender.send(());
+        }
+
         pub fn subscribe_shutdown(&self) -> Receiver<()> {
             self.sender.subscribe()
         }
@@ -1,20 +1,25 @@
 use std::{
-    collections::hash_map::Entry::Vacant,
+    collections::hash_map::Entry::{Occupied, Vacant},
     collections::HashMap,
     fmt::{Display, Formatter},
     net::SocketAddr,
+    time::{Duration, Instant},
 };
 
+use anyhow::{bail, Result};
 use log::warn;
 use tokio::sync::{
     watch,
     watch::{Receiver, Sender},
 };
-use uuid::Uuid;
 
 use crate::{
-    chat::{Chat, Message},
-    request::file::CreateFile,
+    chat::{Chat, Content, Message},
+    request::{
+        error::{ErrorCode, MojikaError},
+        file::{CreateFile, CreateFolder, OfferBatch},
+        progress::TransferProgress,
+    },
 };
 
 #[derive(Debug)]
@@ -48,38 +53,144 @@ impl Peers {
         }
     }
 
+    /// Registers a peer that introduced itself on a connection, or updates the one we know,
+    /// returns whether we didn't know its secret before.
+    ///
+    /// A known peer keeps its chat and stats, and its secret once it has told us one. A peer we
+    /// only discovered tells us its secret from the address we discovered it at, so another one
+    /// can't claim its id first.
+    pub fn register_connected(&mut self, peer: Peer) -> Result<bool> {
+        if peer.id == self.self_peer.id {
+            bail!(MojikaError::new(
+                ErrorCode::InvalidRequest,
+                "A peer can't connect as ourselves!",
+            ));
+        }
+        if peer.secret.is_empty() {
+            bail!(MojikaError::new(
+                ErrorCode::InvalidRequest,
+                format!("No secret for peer:{}", peer.id),
+            ));
+        }
+        let new_secret = match self.items.entry(peer.id.to_owned()) {
+            Vacant(e) => {
+                e.insert(peer);
+                true
+            }
+            Occupied(mut e) => {
+                let known = e.get_mut();
+                let new_secret = known.secret.is_empty();
+                if new_secret && known.address.ip() != peer.address.ip() {
+                    bail!(MojikaError::new(
+                        ErrorCode::InvalidRequest,
+                        format!("Peer:{} connected from another address", peer.id),
+                    ));
+                }
+                if !new_secret && known.secret != peer.secret {
+                    bail!(MojikaError::new(
+                        ErrorCode::InvalidRequest,
+                        format!("Wrong secret for peer:{}", peer.id),
+                    ));
+                }
+                known.name = peer.name;
+                known.secret = peer.secret;
+                known.address = peer.address;
+                new_secret
+            }
+        };
+        self.items_changed();
+        Ok(new_secret)
+    }
+
+    /// Checks that a request of `peer_id` carries the secret it connected with.
+    pub fn authenticate(&self, peer_id: &str, secret: &str) -> Result<()> {
+        match self.items.get(peer_id) {
+            Some(peer) if !peer.secret.is_empty() && peer.secret == secret => Ok(()),
+            Some(peer) if !peer.secret.is_empty() => bail!(MojikaError::new(
+                ErrorCode::InvalidRequest,
+                format!("Wrong secret for peer:{peer_id}"),
+            )),
+            // A peer is only known by its secret once it has connected.
+            _ => bail!(MojikaError::unknown_peer(peer_id)),
+        }
+    }
+
     pub async fn find_by_id(&self, id: &str) -> Option<Peer> {
         let option = self.items.get(id);
         option.cloned()
     }
 
+    pub async fn all(&self) -> Vec<Peer> {
+        self.items.values().cloned().collect()
+    }
+
     pub async fn find_peer_address(&self, peer_id: &str) -> Option<SocketAddr> {
         self.find_by_id(peer_id).await.map(|p| p.address)
     }
 
-    pub fn add_chat(&mut self, peer_id: &str, sender_id: &str, chat: String) {
-        let peer_op = self.items.get_mut(peer_id);
-        match peer_op {
-            None => {}
-            Some(peer) => {
-                peer.chat.messages.push(Message::new_text(sender_id, chat));
-                self.items_changed();
-            }
-        }
+    pub fn add_chat(&mut self, peer_id: &str, sender_id: &str, chat: String) -> Result<()> {
+        let peer = self
+            .items
+            .get_mut(peer_id)
+            .ok_or_else(|| MojikaError::unknown_peer(peer_id))?;
+        peer.chat.messages.push(Message::new_text(sender_id, chat));
+        self.items_changed();
+        Ok(())
     }
 
-    pub fn add_file(&mut self, peer_id: &str, sender_id: &str, file: CreateFile) {
-        let peer_op = self.items.get_mut(peer_id);
+    /// Adds a file to the chat, `file_id` ties it to the progress of the file.
+    pub fn add_file(
+        &mut self,
+        peer_id: &str,
+        sender_id: &str,
+        file_id: &str,
+        file: CreateFile,
+    ) -> Result<()> {
+        self.add_file_message(peer_id, sender_id, file_id.to_owned(), file.filename)
+    }
 
-        if let Some(peer) = peer_op {
-            peer.chat.messages.push(Message::new_file(
-                sender_id,
-                Uuid::new_v4().to_string(),
-                file.filename,
-                "Just created.".to_string(),
-            ));
-            self.items_changed();
-        }
+    /// Adds a folder to the chat, it is shown as one file with a trailing `/`.
+    pub fn add_folder(
+        &mut self,
+        peer_id: &str,
+        sender_id: &str,
+        folder_id: &str,
+        folder: &CreateFolder,
+    ) -> Result<()> {
+        let filename = format!("{}/", folder.name);
+        self.add_file_message(peer_id, sender_id, folder_id.to_owned(), filename)
+    }
+
+    /// Adds a batch to the chat as one file, the batch id is its file id.
+    pub fn add_batch(
+        &mut self,
+        peer_id: &str,
+        sender_id: &str,
+        batch_id: &str,
+        batch: &OfferBatch,
+    ) -> Result<()> {
+        self.add_file_message(peer_id, sender_id, batch_id.to_owned(), batch.title())
+    }
+
+    fn add_file_message(
+        &mut self,
+        peer_id: &str,
+        sender_id: &str,
+        file_id: String,
+        filename: String,
+    ) -> Result<()> {
+        let peer = self
+            .items
+            .get_mut(peer_id)
+            .ok_or_else(|| MojikaError::unknown_peer(peer_id))?;
+        peer.chat.messages.push(Message::new_file(
+            sender_id,
+            file_id,
+            filename,
+            "Just created.".to_string(),
+        ));
+        self.items_changed();
+        Ok(())
     }
     // pub fn update_file_status(&mut self, peer_id: &str, sender_id: &str, file_chunk: FileChunk) {
     //     let peer_op = self.items.get_mut(peer_id);
@@ -92,6 +203,84 @@ impl Peers {
     //     }
     // }
 
+    pub fn set_typing(&mut self, peer_id: &str) {
+        if let Some(peer) = self.items.get_mut(peer_id) {
+            peer.typing_at = Some(Instant::now());
+            self.items_changed();
+        }
+    }
+
+    pub fn update_file_progress(&mut self, peer_id: &str, file_id: &str, progress: String) {
+        let Some(peer) = self.items.get_mut(peer_id) else {
+            return;
+        };
+        let message = peer
+            .chat
+            .messages
+            .iter_mut()
+            .find(|m| matches!(&m.content, Content::File { file_id: id, .. } if id == file_id));
+        if let Some(Message {
+            content: Content::File { progress: p, .. },
+            ..
+        }) = message
+        {
+            *p = progress;
+            self.items_changed();
+        }
+    }
+
+    /// Shows the name the file was saved as in its chat message.
+    pub fn set_filename(&mut self, peer_id: &str, file_id: &str, filename: String) {
+        let Some(peer) = self.items.get_mut(peer_id) else {
+            return;
+        };
+        let message = peer
+            .chat
+            .messages
+            .iter_mut()
+            .find(|m| matches!(&m.content, Content::File { file_id: id, .. } if id == file_id));
+        if let Some(Message {
+            content: Content::File { filename: f, .. },
+            ..
+        }) = message
+        {
+            *f = filename;
+            self.items_changed();
+        }
+    }
+
+    /// Shows how far the file is in its chat message.
+    pub fn update_transfer(&mut self, progress: &TransferProgress) {
+        let Some(peer) = self.items.get_mut(&progress.peer_id) else {
+            return;
+        };
+        let message = peer.chat.messages.iter_mut().find(
+            |m| matches!(&m.content, Content::File { file_id, .. } if *file_id == progress.file_id),
+        );
+        if let Some(Message {
+            content:
+                Content::File {
+                    progress: p,
+                    transfer,
+                    ..
+                },
+            ..
+        }) = message
+        {
+            *p = progress.to_string();
+            *transfer = Some(progress.clone());
+            self.items_changed();
+        }
+    }
+
+    /// Records the result of contacting a peer, `None` when it didn't answer.
+    pub fn record_reachability(&mut self, peer_id: &str, rtt: Option<Duration>) {
+        if let Some(peer) = self.items.get_mut(peer_id) {
+            peer.stats.record(rtt);
+            self.items_changed();
+        }
+    }
+
     fn items_changed(&self) {
         let _ = self.peers_watch_s.send(self.items.clone()).map_err(|e| {
             warn!("Error emitting peers:{e}");
@@ -110,6 +299,56 @@ pub struct Peer {
     pub secret: String,
     pub address: SocketAddr,
     pub chat: Chat,
+    pub stats: PeerStats,
+    /// When the peer last told us it is writing a chat.
+    pub typing_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reachability {
+    #[default]
+    Unknown,
+    Reachable,
+    Unreachable,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerStats {
+    pub reachability: Reachability,
+    /// Smoothed round trip time of the answered pings.
+    pub latency: Option<Duration>,
+    pub last_rtt: Option<Duration>,
+    pub last_seen: Option<Instant>,
+    /// Failed contacts since the last answer.
+    pub failures: u32,
+}
+
+impl PeerStats {
+    /// Failures in a row before a peer that answered before counts as unreachable.
+    const UNREACHABLE_AFTER: u32 = 2;
+
+    fn record(&mut self, rtt: Option<Duration>) {
+        match rtt {
+            Some(rtt) => {
+                self.reachability = Reachability::Reachable;
+                self.latency = Some(match self.latency {
+                    None => rtt,
+                    Some(latency) => (latency * 7 + rtt) / 8,
+                });
+                self.last_rtt = Some(rtt);
+                self.last_seen = Some(Instant::now());
+                self.failures = 0;
+            }
+            None => {
+                self.failures += 1;
+                if self.reachability != Reachability::Reachable
+                    || self.failures >= Self::UNREACHABLE_AFTER
+                {
+                    self.reachability = Reachability::Unreachable;
+                }
+            }
+        }
+    }
 }
 
 impl Peer {
@@ -120,6 +359,8 @@ impl Peer {
             address,
             secret,
             chat: Chat::new(),
+            stats: PeerStats::default(),
+            typing_at: None,
         }
     }
 }
@@ -130,3 +371,77 @@ impl Display for Peer {
         write!(f, "{} ({})", self.name, short_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::app::peer::{Peer, PeerStats, Peers, Reachability};
+
+    #[test]
+    fn peer_stats_track_reachability_and_latency() {
+        let mut stats = PeerStats::default();
+        stats.record(None);
+        assert_eq!(stats.reachability, Reachability::Unreachable);
+
+        stats.record(Some(Duration::from_millis(80)));
+        assert_eq!(stats.reachability, Reachability::Reachable);
+        assert_eq!(stats.latency, Some(Duration::from_millis(80)));
+        stats.record(Some(Duration::from_millis(160)));
+        assert_eq!(stats.latency, Some(Duration::from_millis(90)));
+        assert_eq!(stats.last_rtt, Some(Duration::from_millis(160)));
+
+        stats.record(None);
+        assert_eq!(stats.reachability, Reachability::Reachable);
+        stats.record(None);
+        assert_eq!(stats.reachability, Reachability::Unreachable);
+        assert_eq!(stats.failures, 2);
+    }
+
+    #[test]
+    fn connected_peer_is_registered_then_updated() {
+        let address = "127.0.0.1:1000".parse().unwrap();
+        let mut peers = Peers::new(Peer::new("me".into(), "Me".into(), "s".into(), address));
+        let inbound = Peer::new("a".into(), "A".into(), "secret".into(), address);
+        assert!(peers.register_connected(inbound).unwrap());
+        peers.add_chat("a", "a", "hi".into()).unwrap();
+
+        let moved = "127.0.0.2:2000".parse().unwrap();
+        let renamed = Peer::new("a".into(), "B".into(), "secret".into(), moved);
+        assert!(!peers.register_connected(renamed).unwrap());
+        let peer = &peers.items["a"];
+        assert_eq!((peer.name.as_str(), peer.address), ("B", moved));
+        assert_eq!(peer.chat.messages.len(), 1);
+
+        let impostor = Peer::new("a".into(), "A".into(), "other".into(), address);
+        assert!(peers.register_connected(impostor).is_err());
+        assert!(peers.authenticate("a", "secret").is_ok());
+        assert!(peers.authenticate("a", "other").is_err());
+        assert!(peers.authenticate("b", "secret").is_err());
+        let me = Peer::new("me".into(), "Me".into(), "s".into(), address);
+        assert!(peers.register_connected(me).is_err());
+    }
+
+    #[tokio::test]
+    async fn discovered_peer_connects_from_its_address() {
+        let address = "127.0.0.1:1000".parse().unwrap();
+        let mut peers = Peers::new(Peer::new("me".into(), "Me".into(), "s".into(), address));
+        let discovered = "127.0.0.2:2000".parse().unwrap();
+        peers
+            .register(Peer::new("a".into(), "A".into(), "".into(), discovered))
+            .await;
+        assert!(peers.authenticate("a", "").is_err());
+
+        let elsewhere = "127.0.0.3:2000".parse().unwrap();
+        let impostor = Peer::new("a".into(), "A".into(), "other".into(), elsewhere);
+        assert!(peers.register_connected(impostor).is_err());
+        let unsecret = Peer::new("a".into(), "A".into(), "".into(), discovered);
+        assert!(peers.register_connected(unsecret).is_err());
+        assert!(peers.authenticate("a", "other").is_err());
+
+        let connected = Peer::new("a".into(), "A".into(), "secret".into(), discovered);
+        assert!(peers.register_connected(connected.clone()).unwrap());
+        assert!(!peers.register_connected(connected).unwrap());
+        assert!(peers.authenticate("a", "secret").is_ok());
+    }
+}
@@ -1,5 +1,7 @@
 use uuid::Uuid;
 
+use crate::request::progress::TransferProgress;
+
 #[derive(Debug, Clone)]
 pub struct Chat {
     pub messages: Vec<Message>,
@@ -41,6 +43,7 @@ impl Message {
                 file_id,
                 filename,
                 progress,
+                transfer: None,
             },
         }
     }
@@ -55,5 +58,7 @@ pub enum Content {
         file_id: String,
         filename: String,
         progress: String,
+        /// How far the file is, once it is sent or received.
+        transfer: Option<TransferProgress>,
     },
 }
@@ -0,0 +1,65 @@
+use std::net::SocketAddr;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{debug, warn};
+use tokio::sync::{broadcast, broadcast::error::RecvError, Mutex};
+
+use crate::{
+    app::peer::Peer,
+    discovery::{Discovery, DiscoveryMessage, DiscoveryResult},
+    request::transport::memory::MemoryNetwork,
+};
+
+/// Discovery over a [`MemoryNetwork`], announcements reach every node that is online.
+pub struct MemoryDiscovery {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+    message: Bytes,
+    announcements: Mutex<broadcast::Receiver<(Bytes, SocketAddr)>>,
+}
+
+impl MemoryDiscovery {
+    /// Announces `peer` from its own address in the network.
+    pub fn new(network: MemoryNetwork, peer: &Peer) -> Result<Self> {
+        Ok(Self {
+            addr: peer.address,
+            message: DiscoveryMessage::of_peer(peer).to_bytes()?,
+            announcements: Mutex::new(network.subscribe_announcements()),
+            network,
+        })
+    }
+}
+
+#[async_trait]
+impl Discovery for MemoryDiscovery {
+    async fn receive_new_message(&self) -> Result<DiscoveryResult> {
+        let mut announcements = self.announcements.lock().await;
+        loop {
+            let (message, from) = match announcements.recv().await {
+                Ok(announcement) => announcement,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Missed {skipped} announcements");
+                    continue;
+                }
+                Err(RecvError::Closed) => bail!("the network is gone"),
+            };
+            if !self.network.is_online(self.addr) {
+                continue;
+            }
+            debug!("message form address: {from:?}");
+            return Ok(DiscoveryResult::new(
+                DiscoveryMessage::from_bytes(&message)?,
+                from,
+            ));
+        }
+    }
+
+    async fn send_signal(&self) -> Result<()> {
+        if self.network.is_online(self.addr) && !self.network.is_lost() {
+            self.network.announce(self.message.clone(), self.addr);
+        }
+        Ok(())
+    }
+}
@@ -3,6 +3,7 @@ use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
 
 use anyhow::{bail, Result};
+use async_trait::async_trait;
 use bytes::{BufMut, Bytes, BytesMut};
 use log::debug;
 use rmp_serde::{Deserializer, Serializer};
@@ -12,19 +13,30 @@ use tokio::net::UdpSocket;
 
 use crate::app::peer::Peer;
 
+pub mod memory;
+
 const DEFAULT_PORT: u16 = 10020;
 
-pub struct Discovery {
+/// Announces this peer and hears the announcements of the others.
+#[async_trait]
+pub trait Discovery: Send + Sync {
+    async fn receive_new_message(&self) -> Result<DiscoveryResult>;
+
+    async fn send_signal(&self) -> Result<()>;
+}
+
+/// Discovery with UDP multicast on the local network.
+pub struct MulticastDiscovery {
     socket: Arc<UdpSocket>,
     message: Bytes,
 }
 
-impl Discovery {
+impl MulticastDiscovery {
     pub async fn new(peer: Peer) -> Result<Self> {
         let socket = Self::create_socket()?;
         let socket = Arc::new(socket);
 
-        let message_bytes = Self::create_message(peer)?;
+        let message_bytes = DiscoveryMessage::of_peer(&peer).to_bytes()?;
         let discovery = Self {
             socket,
             message: message_bytes,
@@ -32,14 +44,6 @@ impl Discovery {
         Ok(discovery)
     }
 
-    fn create_message(peer: Peer) -> Result<Bytes> {
-        let message = DiscoveryMessage::new(peer.id, peer.name, peer.address.port());
-        debug!("Discovery Message: {message:?}");
-        let mut message_bytes = BytesMut::with_capacity(1024).writer();
-        message.serialize(&mut Serializer::new(&mut message_bytes))?;
-        Ok(message_bytes.into_inner().freeze())
-    }
-
     fn create_socket() -> Result<UdpSocket> {
         let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
         socket.set_reuse_address(true)?;
@@ -53,15 +57,17 @@ impl Discovery {
         let std_socket: std::net::UdpSocket = socket.into();
         Ok(UdpSocket::from_std(std_socket)?)
     }
+}
 
-    pub async fn receive_new_message(&self) -> Result<DiscoveryResult> {
+#[async_trait]
+impl Discovery for MulticastDiscovery {
+    async fn receive_new_message(&self) -> Result<DiscoveryResult> {
         let socket: Arc<UdpSocket> = self.socket.clone();
         let mut buf = vec![0u8; 1024];
         let result = socket.recv_from(&mut buf).await;
         match result {
             Ok((len, addr)) => {
-                let mut deserializer = Deserializer::new(&buf[..len]);
-                let discovery_msg: DiscoveryMessage = Deserialize::deserialize(&mut deserializer)?;
+                let discovery_msg = DiscoveryMessage::from_bytes(&buf[..len])?;
 
                 debug!("message form address: {addr:?}");
                 Ok(DiscoveryResult::new(discovery_msg, addr))
@@ -72,7 +78,7 @@ impl Discovery {
         }
     }
 
-    pub async fn send_signal(&self) -> Result<()> {
+    async fn send_signal(&self) -> Result<()> {
         let socket: Arc<UdpSocket> = self.socket.clone();
         let addr = SocketAddrV4::new(Ipv4Addr::new(224, 0, 1, 1), DEFAULT_PORT);
         let len = socket.send_to(&self.message, &addr).await?;
@@ -96,6 +102,27 @@ impl DiscoveryMessage {
             service_port,
         }
     }
+
+    pub fn of_peer(peer: &Peer) -> Self {
+        let message = Self::new(
+            peer.id.to_owned(),
+            peer.name.to_owned(),
+            peer.address.port(),
+        );
+        debug!("Discovery Message: {message:?}");
+        message
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let mut message_bytes = BytesMut::with_capacity(1024).writer();
+        self.serialize(&mut Serializer::new(&mut message_bytes))?;
+        Ok(message_bytes.into_inner().freeze())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut deserializer = Deserializer::new(bytes);
+        Ok(Deserialize::deserialize(&mut deserializer)?)
+    }
 }
 
 impl Display for DiscoveryMessage {
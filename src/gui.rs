@@ -1,15 +1,26 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use eframe::egui;
-use egui::Ui;
+use egui::{Color32, RichText, Ui};
 use log::debug;
 use tokio::sync::watch::Receiver;
 
-use crate::app::peer::Peer;
+use crate::app::peer::{Peer, Reachability};
 use crate::app::App;
 use crate::chat::{Content, Message};
+use crate::request::file::{
+    folder_of, CollisionPolicy, InfoFile, OutgoingFile, OutgoingState, PendingOffer, TransferAction,
+};
+
+/// Least time between two typing events sent to a peer.
+const TYPING_RATE: Duration = Duration::from_secs(2);
+/// How long a typing event from a peer is shown.
+const TYPING_SHOWN_FOR: Duration = Duration::from_secs(3);
+/// How often a running transfer is redrawn.
+const PROGRESS_REPAINT_RATE: Duration = Duration::from_millis(250);
 
 pub fn new_gui(app: Arc<App>) -> eframe::Result<()> {
     let options = eframe::NativeOptions {
@@ -21,6 +32,9 @@ pub fn new_gui(app: Arc<App>) -> eframe::Result<()> {
     let title = format!("Mojika Share ({name})");
 
     let watch_peers = app.watch_peers();
+    let watch_outgoing = app.watch_outgoing_files();
+    let watch_incoming = app.watch_incoming_files();
+    let watch_offers = app.watch_offers();
 
     let result = eframe::run_native(
         "Mojika",
@@ -31,7 +45,11 @@ pub fn new_gui(app: Arc<App>) -> eframe::Result<()> {
                 title,
                 selected_peer_id: None,
                 watch_peers,
+                watch_outgoing,
+                watch_incoming,
+                watch_offers,
                 chat_text: String::new(),
+                typing_sent_at: None,
             })
         }),
     );
@@ -44,7 +62,11 @@ struct AppUi {
     title: String,
     selected_peer_id: Option<String>,
     watch_peers: Receiver<HashMap<String, Peer>>,
+    watch_outgoing: Receiver<HashMap<String, OutgoingFile>>,
+    watch_incoming: Receiver<HashMap<String, InfoFile>>,
+    watch_offers: Receiver<HashMap<String, PendingOffer>>,
     chat_text: String,
+    typing_sent_at: Option<Instant>,
 }
 
 impl eframe::App for AppUi {
@@ -59,7 +81,10 @@ impl eframe::App for AppUi {
         egui::SidePanel::left("peers_list")
             .resizable(false)
             .exact_width(240.0)
-            .show(ctx, |ui| self.show_discoverd_peers(ui));
+            .show(ctx, |ui| {
+                self.show_discoverd_peers(ui);
+                self.show_transfers(ui);
+            });
 
         egui::CentralPanel::default().show(ctx, |ui| {
             self.show_selected_peer(ui);
@@ -79,6 +104,7 @@ impl AppUi {
                 ui.horizontal(|ui| {
                     let peer_text = peer.to_string();
                     ui.label(&peer_text);
+                    show_reachability(ui, peer);
                     if ui.button("SELECT").clicked() {
                         debug!("SELECT {peer} clicked!");
                         self.selected_peer_id = Some(peer.id.clone());
@@ -93,6 +119,127 @@ impl AppUi {
         }
     }
 
+    fn show_transfers(&mut self, ui: &mut Ui) {
+        let peers = self.watch_peers.borrow().clone();
+        let peer_name = |peer_id: &str| peers.get(peer_id).map(|p| p.to_string());
+
+        let outgoing = self.watch_outgoing.borrow().clone();
+        let mut outgoing: Vec<_> = outgoing.values().collect();
+        if !outgoing.is_empty() {
+            outgoing.sort_by(|a, b| a.filename.cmp(&b.filename));
+            ui.separator();
+            ui.label("Sending");
+        }
+        for (folder_id, files) in by_folder(outgoing, |f| &f.id) {
+            let first = files[0];
+            let to = peer_name(&first.peer_id).unwrap_or_else(|| first.address.to_string());
+            let Some(folder_id) = folder_id else {
+                self.show_outgoing_file(ui, first, &to);
+                continue;
+            };
+            ui.horizontal(|ui| {
+                let title = group_title(&first.filename, files.len());
+                ui.label(format!("{title} to {to}"));
+                let sending = files.iter().any(|f| f.state == OutgoingState::Sending);
+                let pause_or_resume = if sending { "PAUSE" } else { "RESUME" };
+                if let Some(action) = show_transfer_buttons(ui, &folder_id, pause_or_resume) {
+                    self.app.control_outgoing_folder(&folder_id, action);
+                }
+            });
+            ui.indent(&folder_id, |ui| {
+                for file in files {
+                    self.show_outgoing_file(ui, file, &to);
+                }
+            });
+        }
+
+        let incoming = self.watch_incoming.borrow().clone();
+        let mut incoming: Vec<_> = incoming.values().collect();
+        if !incoming.is_empty() {
+            incoming.sort_by(|a, b| a.filename.cmp(&b.filename));
+            ui.separator();
+            ui.label("Receiving");
+        }
+        for (folder_id, files) in by_folder(incoming, |f| &f.id) {
+            let first = files[0];
+            let from = peer_name(&first.peer_id).unwrap_or_default();
+            let Some(folder_id) = folder_id else {
+                self.show_incoming_file(ui, first, &from);
+                continue;
+            };
+            ui.horizontal(|ui| {
+                let title = group_title(&first.filename, files.len());
+                ui.label(format!("{title} from {from}"));
+                let receiving = files.iter().any(|f| !f.paused && !f.collided);
+                let pause_or_resume = if receiving { "PAUSE" } else { "RESUME" };
+                if let Some(action) = show_transfer_buttons(ui, &folder_id, pause_or_resume) {
+                    self.app.control_incoming_folder(&folder_id, action);
+                }
+            });
+            ui.indent(&folder_id, |ui| {
+                for file in files {
+                    self.show_incoming_file(ui, file, &from);
+                }
+            });
+        }
+    }
+
+    fn show_outgoing_file(&self, ui: &mut Ui, file: &OutgoingFile, to: &str) {
+        ui.horizontal(|ui| {
+            let state = match file.state {
+                OutgoingState::Sending => "",
+                OutgoingState::Paused => " (paused)",
+                OutgoingState::Interrupted => " (interrupted)",
+            };
+            ui.label(format!("{} to {to}{state}", file.filename));
+            let action = if file.state == OutgoingState::Sending {
+                show_transfer_buttons(ui, &file.id, "PAUSE")
+            } else {
+                show_transfer_buttons(ui, &file.id, "RESUME")
+            };
+            if let Some(action) = action {
+                self.app.control_outgoing(&file.id, action);
+            }
+        });
+    }
+
+    fn show_incoming_file(&self, ui: &mut Ui, file: &InfoFile, from: &str) {
+        ui.horizontal(|ui| {
+            if file.collided {
+                ui.label(format!("{} from {from} (name taken)", file.filename));
+                self.show_collision_buttons(ui, &file.id);
+                return;
+            }
+            let state = if file.paused { " (paused)" } else { "" };
+            ui.label(format!("{} from {from}{state}", file.filename));
+            let action = if file.paused {
+                show_transfer_buttons(ui, &file.id, "RESUME")
+            } else {
+                show_transfer_buttons(ui, &file.id, "PAUSE")
+            };
+            if let Some(action) = action {
+                self.app.control_incoming(&file.id, action);
+            }
+        });
+    }
+
+    /// Asks how to save a received file whose name is taken.
+    fn show_collision_buttons(&self, ui: &mut Ui, file_id: &str) {
+        if ui.button("RENAME").clicked() {
+            debug!("RENAME {file_id} clicked!");
+            self.app.resolve_collision(file_id, CollisionPolicy::Rename);
+        }
+        if ui.button("OVERWRITE").clicked() {
+            debug!("OVERWRITE {file_id} clicked!");
+            self.app
+                .resolve_collision(file_id, CollisionPolicy::Overwrite);
+        }
+        if ui.button("CANCEL").clicked() {
+            debug!("CANCEL {file_id} clicked!");
+            self.app.control_incoming(file_id, TransferAction::Cancel);
+        }
+    }
+
     fn show_selected_peer(&mut self, ui: &mut Ui) {
         let selected_peer = &self.selected_peer_id;
         match selected_peer {
@@ -139,24 +286,42 @@ impl AppUi {
                         self.show_message(ui, &p, message);
                     }
                 }
+                if p.typing_at
+                    .is_some_and(|at| at.elapsed() < TYPING_SHOWN_FOR)
+                {
+                    ui.label(RichText::new(format!("{} is typing…", p.name)).italics());
+                    ui.ctx().request_repaint_after(TYPING_SHOWN_FOR);
+                }
             }
         }
         ui.horizontal(|ui| {
             let response = ui.add(egui::TextEdit::singleline(&mut self.chat_text));
+            if response.changed() && !self.chat_text.is_empty() {
+                self.send_typing(peer_id);
+            }
             if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
                 self.send_chat(peer_id);
             }
             if ui.button("FILE(s)").clicked() {
                 debug!("open file picker");
-                let file = rfd::FileDialog::new().pick_file();
+                let files = rfd::FileDialog::new().pick_files();
 
-                if let Some(file_path) = file {
-                    debug!("selected file:{file_path:?}");
-                    self.send_file(file_path, peer_id);
+                if let Some(file_paths) = files {
+                    debug!("selected files:{file_paths:?}");
+                    self.send_files(file_paths, peer_id);
                 } else {
                     debug!("No file selected.")
                 }
             }
+            if ui.button("FOLDER").clicked() {
+                debug!("open folder picker");
+                if let Some(folder_path) = rfd::FileDialog::new().pick_folder() {
+                    debug!("selected folder:{folder_path:?}");
+                    self.send_file(folder_path, peer_id);
+                } else {
+                    debug!("No folder selected.")
+                }
+            }
             if ui.button("SEND").clicked() && !self.chat_text.is_empty() {
                 self.send_chat(peer_id);
             }
@@ -175,8 +340,36 @@ impl AppUi {
             Content::Text { text } => {
                 ui.label(format!("{name}: {text}"));
             }
-            Content::File { filename, .. } => {
-                ui.label(format!("{name}: [FILE] {filename}"));
+            Content::File {
+                file_id,
+                filename,
+                progress,
+                transfer,
+            } => {
+                let offered = self.watch_offers.borrow().contains_key(file_id);
+                ui.horizontal(|ui| {
+                    match transfer {
+                        Some(transfer) if !transfer.state.is_finished() => {
+                            ui.label(format!("{name}: [FILE] {filename}"));
+                            let bar = egui::ProgressBar::new(transfer.fraction()).text(progress);
+                            ui.add(bar);
+                            ui.ctx().request_repaint_after(PROGRESS_REPAINT_RATE);
+                        }
+                        _ => {
+                            ui.label(format!("{name}: [FILE] {filename} {progress}"));
+                        }
+                    }
+                    if offered {
+                        if ui.button("ACCEPT").clicked() {
+                            debug!("ACCEPT {file_id} clicked!");
+                            self.app.answer_offer(file_id, true);
+                        }
+                        if ui.button("DECLINE").clicked() {
+                            debug!("DECLINE {file_id} clicked!");
+                            self.app.answer_offer(file_id, false);
+                        }
+                    }
+                });
             }
         }
     }
@@ -187,9 +380,85 @@ impl AppUi {
         self.chat_text.clear();
     }
 
+    fn send_typing(&mut self, peer_id: &str) {
+        if self
+            .typing_sent_at
+            .is_some_and(|at| at.elapsed() < TYPING_RATE)
+        {
+            return;
+        }
+        self.typing_sent_at = Some(Instant::now());
+        self.app.send_typing(peer_id);
+    }
+
     fn send_file(&mut self, file_path: PathBuf, peer_id: &str) {
         self.app
             .send_file(peer_id, &self.app.self_peer.id, file_path);
         self.chat_text.clear();
     }
+
+    fn send_files(&mut self, file_paths: Vec<PathBuf>, peer_id: &str) {
+        self.app
+            .send_files(peer_id, &self.app.self_peer.id, file_paths);
+        self.chat_text.clear();
+    }
+}
+
+/// Groups the files of each folder or batch where the first of them is, others stand alone.
+fn by_folder<T>(files: Vec<T>, id: impl Fn(&T) -> &str) -> Vec<(Option<String>, Vec<T>)> {
+    let mut groups: Vec<(Option<String>, Vec<T>)> = vec![];
+    for file in files {
+        let folder_id = folder_of(id(&file)).map(|(folder_id, _)| folder_id.to_owned());
+        let group = groups
+            .iter_mut()
+            .find(|(id, _)| folder_id.is_some() && *id == folder_id);
+        match group {
+            Some((_, group)) => group.push(file),
+            None => groups.push((folder_id, vec![file])),
+        }
+    }
+    groups
+}
+
+/// The folder of the files, or how many files are left of a batch.
+fn group_title(filename: &str, count: usize) -> String {
+    match filename.split_once('/') {
+        Some((folder, _)) => format!("{folder}/"),
+        None => format!("{count} files"),
+    }
+}
+
+/// Shows the pause or resume button and the cancel button, returns the clicked one.
+fn show_transfer_buttons(
+    ui: &mut Ui,
+    file_id: &str,
+    pause_or_resume: &str,
+) -> Option<TransferAction> {
+    let mut action = None;
+    if ui.button(pause_or_resume).clicked() {
+        debug!("{pause_or_resume} {file_id} clicked!");
+        action = Some(if pause_or_resume == "PAUSE" {
+            TransferAction::Pause
+        } else {
+            TransferAction::Resume
+        });
+    }
+    if ui.button("CANCEL").clicked() {
+        debug!("CANCEL {file_id} clicked!");
+        action = Some(TransferAction::Cancel);
+    }
+    action
+}
+
+fn show_reachability(ui: &mut Ui, peer: &Peer) {
+    let stats = &peer.stats;
+    let (text, color) = match (stats.reachability, stats.latency) {
+        (Reachability::Reachable, Some(latency)) => {
+            (format!("● {} ms", latency.as_millis()), Color32::GREEN)
+        }
+        (Reachability::Reachable, None) => ("●".to_string(), Color32::GREEN),
+        (Reachability::Unreachable, _) => ("● unreachable".to_string(), Color32::RED),
+        (Reachability::Unknown, _) => ("●".to_string(), Color32::GRAY),
+    };
+    ui.label(RichText::new(text).color(color));
 }
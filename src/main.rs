@@ -3,7 +3,7 @@ use std::sync::Arc;
 use anyhow::Result;
 use log::LevelFilter;
 
-use mojika::app::App;
+use mojika::app::{config::AppConfig, App};
 
 fn main() -> Result<()> {
     env_logger::builder()
@@ -12,7 +12,7 @@ fn main() -> Result<()> {
         .filter_module("mojika::discovery", LevelFilter::Info)
         .init();
 
-    let app = Arc::new(App::new()?);
+    let app = Arc::new(App::new(AppConfig::from_env()?)?);
 
     // Arc::try_unwrap(app).unwrap().stop();
     app.start()
@@ -0,0 +1,187 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use log::{debug, warn};
+use tokio::{
+    io::AsyncWriteExt,
+    spawn,
+    sync::{mpsc, Mutex},
+};
+
+use crate::{
+    app::App,
+    request::{
+        event::Event,
+        protocol::{FrameEncoding, MojikaProtocol, MojikaProtocolReader},
+        registry::OpenStream,
+        requester::Requester,
+        transport::{RecvHalf, SendHalf},
+        Lane,
+    },
+};
+
+const EVENT_QUEUE_LEN: usize = 100;
+
+/// Long-lived bidirectional streams carrying events to and from each peer.
+///
+/// Either side may open the stream, events flow both ways on whichever one is registered.
+#[derive(Debug, Default)]
+pub struct ControlChannels {
+    writers: Mutex<HashMap<String, mpsc::Sender<Event>>>,
+    /// Held while opening a stream, so concurrent sends to a peer share one.
+    opening: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl ControlChannels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pushes `event` to `peer_id`, opening a control stream if there is none.
+    pub async fn send(
+        self: Arc<Self>,
+        app: Arc<App>,
+        requester: &Requester,
+        peer_id: &str,
+        address: SocketAddr,
+        event: Event,
+    ) -> Result<()> {
+        let event = match self.send_registered(peer_id, event).await {
+            Ok(()) => return Ok(()),
+            Err(event) => event,
+        };
+        let opening = self
+            .opening
+            .lock()
+            .await
+            .entry(peer_id.to_string())
+            .or_default()
+            .clone();
+        let _opening = opening.lock().await;
+        // Another send may have opened one while this one waited.
+        let event = match self.send_registered(peer_id, event).await {
+            Ok(()) => return Ok(()),
+            Err(event) => event,
+        };
+        debug!("Opening a control stream to {peer_id}");
+        let stream = requester.open_stream(address, Lane::Control).await?;
+        let writer = self.register(peer_id, stream.send, stream.encoding).await;
+        let reader = MojikaProtocolReader::new(stream.recv, stream.encoding);
+        spawn(self.read_events(app, peer_id.to_string(), reader, writer.clone()));
+        writer.send(event).await?;
+        Ok(())
+    }
+
+    /// Pushes `event` on the registered stream of `peer_id`, handing it back if there is no live one.
+    async fn send_registered(&self, peer_id: &str, event: Event) -> Result<(), Event> {
+        let writer = self.writers.lock().await.get(peer_id).cloned();
+        match writer {
+            Some(writer) => writer
+                .send(event)
+                .await
+                .map_err(|mpsc::error::SendError(event)| event),
+            None => Err(event),
+        }
+    }
+
+    /// Serves a control stream a peer opened with `first` as its first event.
+    pub async fn serve(self: Arc<Self>, app: Arc<App>, first: Event, stream: OpenStream) {
+        let OpenStream {
+            send,
+            reader,
+            encoding,
+        } = stream;
+        let peer_id = first.peer_id.to_owned();
+        debug!("Got a control stream from {peer_id}");
+        // Checked before the stream takes the place of the peer's one.
+        let authenticated = app.peers.read().await.authenticate(&peer_id, &first.secret);
+        if let Err(e) = authenticated {
+            warn!("Dropped a control stream of {peer_id}: {e}");
+            return;
+        }
+        let writer = self.register(&peer_id, send, encoding).await;
+        if let Err(e) = app.clone().dispatch_event(first).await {
+            warn!("Dropped an event of {peer_id}: {e}");
+        }
+        self.read_events(app, peer_id, reader, writer).await;
+    }
+
+    async fn register(
+        &self,
+        peer_id: &str,
+        send: SendHalf,
+        encoding: FrameEncoding,
+    ) -> mpsc::Sender<Event> {
+        let (writer, events) = mpsc::channel(EVENT_QUEUE_LEN);
+        spawn(write_events(send, events, encoding));
+        self.writers
+            .lock()
+            .await
+            .insert(peer_id.to_string(), writer.clone());
+        writer
+    }
+
+    async fn read_events(
+        self: Arc<Self>,
+        app: Arc<App>,
+        peer_id: String,
+        mut reader: MojikaProtocolReader<RecvHalf>,
+        writer: mpsc::Sender<Event>,
+    ) {
+        loop {
+            let event = match reader.next_frame().await {
+                Ok(Some(frame)) if frame.is::<Event>() => frame.decode::<Event>(),
+                Ok(Some(frame)) => {
+                    warn!(
+                        "Unexpected {} frame on the control stream",
+                        frame.header.content_type
+                    );
+                    break;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Error reading the control stream of {peer_id}: {e:?}");
+                    break;
+                }
+            };
+            match event {
+                Ok(event) if event.peer_id == peer_id => {
+                    if let Err(e) = app.clone().dispatch_event(event).await {
+                        warn!("Closing the control stream of {peer_id}: {e}");
+                        break;
+                    }
+                }
+                Ok(event) => warn!("Dropped an event of {} from {peer_id}", event.peer_id),
+                Err(e) => warn!("Invalid event from {peer_id}: {e:?}"),
+            }
+        }
+        debug!("Control stream of {peer_id} closed");
+        let mut writers = self.writers.lock().await;
+        if writers
+            .get(&peer_id)
+            .is_some_and(|w| w.same_channel(&writer))
+        {
+            writers.remove(&peer_id);
+        }
+    }
+}
+
+async fn write_events(
+    mut send: SendHalf,
+    mut events: mpsc::Receiver<Event>,
+    encoding: FrameEncoding,
+) {
+    while let Some(event) = events.recv().await {
+        if let Err(e) = write_event(&mut send, &event, encoding).await {
+            warn!("Error writing to the control stream: {e:?}");
+            return;
+        }
+    }
+    let _ = send.shutdown().await;
+}
+
+async fn write_event(send: &mut SendHalf, event: &Event, encoding: FrameEncoding) -> Result<()> {
+    MojikaProtocol::from_content(event)?
+        .write_to(send, encoding)
+        .await
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    iter,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How received files are sorted into subfolders of their download dir.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Subfolders {
+    #[default]
+    None,
+    /// A folder named after the peer that sent them.
+    ByPeer,
+    /// A folder for the UTC date they were received on, as `YYYY-MM-DD`.
+    ByDate,
+}
+
+impl Subfolders {
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "peer" => Some(Self::ByPeer),
+            "date" => Some(Self::ByDate),
+            _ => None,
+        }
+    }
+}
+
+/// Where the files received from each peer are saved.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadDirs {
+    /// The Mojika folder, for peers without a dir of their own.
+    pub dir: PathBuf,
+    /// Dirs of their own, by peer id, the name is picked by the peer and may change.
+    pub peer_dirs: HashMap<String, PathBuf>,
+    pub subfolders: Subfolders,
+}
+
+impl DownloadDirs {
+    /// The dir a file from peer `peer_id` called `peer_name` received at `now` is saved in.
+    pub fn dir_for(&self, peer_id: &str, peer_name: &str, now: SystemTime) -> PathBuf {
+        let dir = self.peer_dirs.get(peer_id).unwrap_or(&self.dir);
+        match self.subfolders {
+            Subfolders::None => dir.to_owned(),
+            Subfolders::ByPeer => dir.join(folder_name(peer_name)),
+            Subfolders::ByDate => dir.join(date_of(now)),
+        }
+    }
+
+    /// Whether `dir` is one [DownloadDirs::dir_for] gives, files are only saved in those.
+    pub fn is_download_dir(&self, dir: &Path) -> bool {
+        iter::once(&self.dir)
+            .chain(self.peer_dirs.values())
+            .filter_map(|base| dir.strip_prefix(base).ok())
+            .any(|subfolder| {
+                let mut components = subfolder.components();
+                match self.subfolders {
+                    Subfolders::None => components.next().is_none(),
+                    Subfolders::ByPeer | Subfolders::ByDate => {
+                        matches!(components.next(), Some(Component::Normal(_)))
+                            && components.next().is_none()
+                    }
+                }
+            })
+    }
+}
+
+/// The peer name as the name of one folder, peers pick their own names.
+fn folder_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "_".to_owned(),
+        _ => name,
+    }
+}
+
+/// The UTC date of `time`, as `YYYY-MM-DD`.
+fn date_of(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    // Days to a civil date, from https://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use crate::request::download::{date_of, DownloadDirs, Subfolders};
+
+    #[test]
+    fn files_are_sorted_by_peer_or_date() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(date_of(at(0)), "1970-01-01");
+        assert_eq!(date_of(at(951_782_400)), "2000-02-29");
+        assert_eq!(date_of(at(1_700_000_000)), "2023-11-14");
+
+        let mojika = PathBuf::from("mojika");
+        let mut dirs = DownloadDirs {
+            dir: mojika.clone(),
+            ..DownloadDirs::default()
+        };
+        dirs.peer_dirs
+            .insert("a".to_owned(), PathBuf::from("alice"));
+        assert_eq!(dirs.dir_for("b", "Bob", at(0)), mojika);
+        assert_eq!(dirs.dir_for("a", "Alice", at(0)), PathBuf::from("alice"));
+        // Another peer can call itself the same.
+        assert_eq!(dirs.dir_for("c", "Alice", at(0)), mojika);
+        assert!(dirs.is_download_dir(&PathBuf::from("alice")));
+        assert!(!dirs.is_download_dir(&mojika.join("Bob")));
+        assert!(!dirs.is_download_dir(&PathBuf::from("/etc")));
+
+        dirs.subfolders = Subfolders::ByPeer;
+        assert_eq!(dirs.dir_for("b", "Bob", at(0)), mojika.join("Bob"));
+        assert_eq!(dirs.dir_for("b", "../Bob", at(0)), mojika.join(".._Bob"));
+        assert_eq!(dirs.dir_for("b", "..", at(0)), mojika.join("_"));
+        assert!(dirs.is_download_dir(&mojika.join("Bob")));
+        assert!(!dirs.is_download_dir(&mojika.join("..").join("Bob")));
+        assert!(!dirs.is_download_dir(&mojika.join("Bob").join("Eve")));
+
+        dirs.subfolders = Subfolders::ByDate;
+        let dir = dirs.dir_for("a", "Alice", at(1_700_000_000));
+        assert_eq!(dir, PathBuf::from("alice").join("2023-11-14"));
+    }
+}
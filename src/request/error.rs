@@ -0,0 +1,190 @@
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    io,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The receiver doesn't know how to handle the request.
+    InvalidRequest,
+    /// The sender isn't a known peer of the receiver.
+    UnknownPeer,
+    /// There is no file with the given id on the receiver.
+    UnknownFile,
+    /// A file with the same id already exists on the receiver.
+    FileExists,
+    /// The chunk doesn't start where the receiver continues, see [ErrorDetails::ExpectedOffset].
+    OffsetMismatch,
+    /// The chunk doesn't match its hash, the sender reads and sends it again.
+    ChunkCorrupted,
+    /// The whole file doesn't match the hash it was offered with, the receiver discarded it.
+    HashMismatch,
+    /// The user paused the file, chunks are refused until it is resumed.
+    TransferPaused,
+    /// The receiver has no space left for the file.
+    DiskFull,
+    /// Reading or writing a file failed on the receiver.
+    Io,
+    Internal,
+}
+
+impl ErrorCode {
+    fn is_retryable(&self) -> bool {
+        matches!(self, ErrorCode::Io)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ErrorDetails {
+    ExpectedOffset(u64),
+}
+
+/// An error a peer sends back, the sender acts on the `code`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MojikaError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retryable: bool,
+    pub details: Option<ErrorDetails>,
+}
+
+impl MojikaError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retryable: code.is_retryable(),
+            details: None,
+        }
+    }
+
+    pub fn unknown_peer(peer_id: &str) -> Self {
+        Self::new(ErrorCode::UnknownPeer, format!("unknown peer:{peer_id}"))
+    }
+
+    pub fn offset_mismatch(expected: u64, actual: u64) -> Self {
+        let message = format!("expected offset:{expected}, got:{actual}");
+        Self {
+            details: Some(ErrorDetails::ExpectedOffset(expected)),
+            ..Self::new(ErrorCode::OffsetMismatch, message)
+        }
+    }
+
+    pub fn expected_offset(&self) -> Option<u64> {
+        let Some(ErrorDetails::ExpectedOffset(offset)) = self.details else {
+            return None;
+        };
+        Some(offset)
+    }
+}
+
+impl From<&anyhow::Error> for MojikaError {
+    fn from(e: &anyhow::Error) -> Self {
+        if let Some(e) = e.downcast_ref::<MojikaError>() {
+            return e.clone();
+        }
+        let message = e.to_string();
+        match e.chain().find_map(|e| e.downcast_ref::<io::Error>()) {
+            Some(io_error) => match io_error.kind() {
+                io::ErrorKind::StorageFull => Self::new(ErrorCode::DiskFull, message),
+                _ => Self::new(ErrorCode::Io, message),
+            },
+            None => Self::new(ErrorCode::Internal, message),
+        }
+    }
+}
+
+impl Display for MojikaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl Error for MojikaError {}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// No response arrived in time, the peer may or may not have handled the request.
+    Timeout(Duration),
+    /// The peer couldn't be reached, the request was never sent.
+    Refused(anyhow::Error),
+    /// The peer handled the request and answered with an error.
+    Remote(MojikaError),
+    /// The stream broke or the response couldn't be read.
+    Failed(anyhow::Error),
+}
+
+impl RequestError {
+    pub fn remote_code(&self) -> Option<ErrorCode> {
+        match self {
+            RequestError::Remote(e) => Some(e.code),
+            _ => None,
+        }
+    }
+
+    /// Whether sending the request again may succeed without doing the work twice.
+    pub fn is_retryable(&self, idempotent: bool) -> bool {
+        match self {
+            RequestError::Refused(_) => true,
+            RequestError::Timeout(_) | RequestError::Failed(_) => idempotent,
+            RequestError::Remote(e) => e.retryable && idempotent,
+        }
+    }
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout(timeout) => write!(f, "request timed out after {timeout:?}"),
+            RequestError::Refused(e) => write!(f, "peer is unreachable: {e}"),
+            RequestError::Remote(e) => write!(f, "peer answered with an error: {e}"),
+            RequestError::Failed(e) => write!(f, "request failed: {e}"),
+        }
+    }
+}
+
+impl Error for RequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RequestError::Refused(e) | RequestError::Failed(e) => Some(e.as_ref()),
+            RequestError::Remote(e) => Some(e),
+            RequestError::Timeout(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use anyhow::anyhow;
+
+    use crate::request::error::{ErrorCode, MojikaError};
+
+    #[test]
+    fn mojika_error_from_anyhow() {
+        let e: anyhow::Error = MojikaError::offset_mismatch(10, 20).into();
+        let e = MojikaError::from(&e);
+        assert_eq!(e.code, ErrorCode::OffsetMismatch);
+        assert_eq!(e.expected_offset(), Some(10));
+
+        let e = anyhow::Error::from(io::Error::from(io::ErrorKind::StorageFull));
+        let e = MojikaError::from(&e.context("writing the chunk"));
+        assert_eq!(e.code, ErrorCode::DiskFull);
+        assert!(!e.retryable);
+
+        let e = MojikaError::from(&anyhow::Error::from(io::Error::from(
+            io::ErrorKind::Interrupted,
+        )));
+        assert_eq!(e.code, ErrorCode::Io);
+        assert!(e.retryable);
+
+        let e = MojikaError::from(&anyhow!("something else"));
+        assert_eq!(e.code, ErrorCode::Internal);
+        assert_eq!(e.expected_offset(), None);
+    }
+}
@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+use crate::request::{protocol::MojikaContent, registry::MojikaMessage};
+
+/// A notification pushed to a peer over its control stream, it has no response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub peer_id: String,
+    pub secret: String,
+    pub body: EventBody,
+}
+
+impl Event {
+    pub fn new(peer_id: String, secret: String, body: EventBody) -> Self {
+        Self {
+            peer_id,
+            secret,
+            body,
+        }
+    }
+}
+
+impl MojikaContent for Event {
+    const TYPE_NAME: &'static str = "Event";
+    const TYPE_TAG: u8 = 3;
+}
+
+/// The first event on a stream makes it the control stream of its peer.
+impl MojikaMessage for Event {}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum EventBody {
+    /// The peer is writing a chat.
+    Typing,
+    /// The receiver has the whole file, or all of a folder or batch.
+    TransferCompleted { file_id: String },
+    /// The receiver saved the file as `filename`, renamed if a file had its name.
+    FileSaved { file_id: String, filename: String },
+}
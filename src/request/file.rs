@@ -1,7 +1,11 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
-use std::io::SeekFrom;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::future::pending;
+use std::io::{ErrorKind, SeekFrom};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{bail, Result};
 use bytes::Bytes;
@@ -13,30 +17,428 @@ use tokio::{
     fs::File,
     io::AsyncReadExt,
     io::{AsyncSeekExt, AsyncWriteExt},
-    sync::broadcast,
-    sync::broadcast::{Receiver, Sender},
-    sync::RwLock,
+    sync::broadcast::{self, Receiver},
+    sync::{watch, Mutex, Notify, OwnedMutexGuard, RwLock},
+    task::{spawn_blocking, JoinSet},
 };
+use uuid::Uuid;
 
 use crate::{
-    app::{new_id, peer::Peer, peer::Peers},
-    request::{requester::Requester, response::ResponseBody, FileRequest, Request, RequestBody},
+    app::{new_id, peer::Peer, peer::Peers, request_as_known_peer},
+    request::{
+        download::DownloadDirs,
+        error::{ErrorCode, MojikaError, RequestError},
+        progress::{Direction, ProgressReporter, TransferProgress, TransferState},
+        requester::{RequestOptions, Requester, RetryPolicy},
+        response::{FileResponse, ResponseBody},
+        scheduler::{TransferLimits, TransferScheduler},
+        FileRequest, Request, RequestBody,
+    },
 };
 
 const BUFFER_LEN: usize = 200_000;
+/// How many times in a row the sender follows the offset the receiver expects, or sends a chunk
+/// again that arrived corrupted.
+const MAX_RESYNCS: u32 = 3;
+/// Numbers tried for a free name before a received file is given up on.
+const MAX_RENAMES: usize = 1000;
+/// Saved files a slow subscriber can fall behind on before it misses some.
+const SAVED_QUEUE_LEN: usize = 64;
+/// Ids of the batches we accepted, in the state dir, so none of them is offered twice.
+const ACCEPTED_BATCHES_FILENAME: &str = "accepted.batches.mojika";
+
+/// Chunks are idempotent, give a slow disk on the receiver more time and retries.
+fn chunk_request_options() -> RequestOptions {
+    RequestOptions {
+        timeout: Duration::from_secs(30),
+        retry: RetryPolicy {
+            max_attempts: 6,
+            ..RetryPolicy::default()
+        },
+    }
+}
+
+/// BLAKE3 hash of a file or of a chunk of it.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ContentHash([u8; 32]);
+
+impl ContentHash {
+    pub fn of(content: &[u8]) -> Self {
+        blake3::hash(content).into()
+    }
+}
+
+impl From<blake3::Hash> for ContentHash {
+    fn from(hash: blake3::Hash) -> Self {
+        Self(hash.into())
+    }
+}
+
+impl Debug for ContentHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", blake3::Hash::from(self.0).to_hex())
+    }
+}
+
+/// Hashes the whole file, without blocking the runtime on large files.
+pub async fn hash_file(path: &Path) -> Result<ContentHash> {
+    let path = path.to_owned();
+    spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = vec![0u8; BUFFER_LEN];
+        loop {
+            let count = std::io::Read::read(&mut file, &mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            hasher.update(&buffer[..count]);
+        }
+        Ok(hasher.finalize().into())
+    })
+    .await?
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct CreateFile {
+    /// Name of the file, or its path relative to the download dir when it is part of a folder.
     pub filename: String,
     pub file_length: u64,
+    /// The receiver only keeps the file if its content has this hash.
+    pub hash: ContentHash,
+    /// Unix permission bits, kept by receivers on unix.
+    pub mode: Option<u32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl CreateFile {
+    /// Offers the file at `path` as `filename`.
+    pub async fn of(path: &Path, filename: String) -> Result<Self> {
+        let metadata = fs::metadata(path).await?;
+        Ok(Self {
+            filename,
+            file_length: metadata.len(),
+            hash: hash_file(path).await?,
+            mode: file_mode(&metadata),
+        })
+    }
+}
+
+/// A folder offered as a whole, the receiver recreates its tree under the download dir.
+///
+/// The file of entry `index` is then sent like any other file, with the id
+/// [`entry_id`]`(folder_id, index)`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CreateFolder {
+    pub name: String,
+    /// Parents come before their content.
+    pub entries: Vec<FolderEntry>,
+}
+
+impl CreateFolder {
+    /// Length of all the files in the folder.
+    pub fn content_length(&self) -> u64 {
+        content_length(&self.entries)
+    }
+}
+
+fn content_length(entries: &[FolderEntry]) -> u64 {
+    entries
+        .iter()
+        .map(|e| match e.kind {
+            EntryKind::File { file_length, .. } => file_length,
+            EntryKind::Dir => 0,
+        })
+        .sum()
+}
+
+/// Files offered together, the receiver accepts or declines all of them at once.
+///
+/// Once accepted, file `index` is sent with the id [`entry_id`]`(id, index)`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OfferBatch {
+    /// Picked by the sender, so it keeps the batch before the receiver can answer it.
+    pub id: String,
+    pub files: Vec<CreateFile>,
+}
+
+impl OfferBatch {
+    /// What the batch is called in the chat.
+    pub fn title(&self) -> String {
+        let names: Vec<_> = self.files.iter().map(|f| f.filename.as_str()).collect();
+        batch_title(&names)
+    }
+}
+
+fn batch_title(names: &[&str]) -> String {
+    format!("{} files: {}", names.len(), names.join(", "))
+}
+
+/// A batch waiting for the user to accept or decline it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingOffer {
+    pub peer_id: String,
+    pub batch: OfferBatch,
+}
+
+/// A batch we offered, sent once the peer accepts it.
+#[derive(Debug)]
+struct OfferedBatch {
+    peer: Peer,
+    files: Vec<(CreateFile, PathBuf)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FolderEntry {
+    /// Path in the folder, its components joined with `/`.
+    pub path: String,
+    pub kind: EntryKind,
+    /// Unix permission bits, kept by receivers on unix.
+    pub mode: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum EntryKind {
+    Dir,
+    File { file_length: u64, hash: ContentHash },
+}
+
+/// Id of the file of entry `index` of a folder or a batch.
+pub fn entry_id(folder_id: &str, index: usize) -> String {
+    format!("{folder_id}.{index}")
+}
+
+/// The folder or batch id and entry index of the file, if it is part of one.
+pub fn folder_of(file_id: &str) -> Option<(&str, usize)> {
+    let (folder_id, index) = file_id.split_once('.')?;
+    Some((folder_id, index.parse().ok()?))
+}
+
+/// Builds the manifest of the folder at `root`, with the path of every entry on this side.
+///
+/// Symbolic links are skipped, they may point out of the folder.
+pub async fn scan_folder(root: &Path) -> Result<(CreateFolder, Vec<PathBuf>)> {
+    let Some(name) = root.file_name().and_then(|n| n.to_str()) else {
+        bail!("cant get the folder name as string:{root:?}")
+    };
+    let mut folder = CreateFolder {
+        name: name.to_string(),
+        entries: vec![],
+    };
+    let mut paths = vec![];
+    let mut dirs = vec![(root.to_owned(), String::new())];
+    while let Some((dir, prefix)) = dirs.pop() {
+        let mut children = vec![];
+        let mut read_dir = fs::read_dir(&dir).await?;
+        while let Some(child) = read_dir.next_entry().await? {
+            children.push(child);
+        }
+        children.sort_by_key(|c| c.file_name());
+        let mut subdirs = vec![];
+        for child in children {
+            let path = child.path();
+            let Some(child_name) = child.file_name().to_str().map(|n| n.to_string()) else {
+                bail!("cant get the filename as string:{path:?}")
+            };
+            let entry_path = prefix.clone() + &child_name;
+            let metadata = fs::symlink_metadata(&path).await?;
+            let kind = if metadata.is_dir() {
+                subdirs.push((path.clone(), entry_path.clone() + "/"));
+                EntryKind::Dir
+            } else if metadata.is_file() {
+                EntryKind::File {
+                    file_length: metadata.len(),
+                    hash: hash_file(&path).await?,
+                }
+            } else {
+                warn!("skip what is neither a file nor a folder:{path:?}");
+                continue;
+            };
+            folder.entries.push(FolderEntry {
+                path: entry_path,
+                kind,
+                mode: file_mode(&metadata),
+            });
+            paths.push(path);
+        }
+        // Popped in order, so the tree is walked depth first.
+        dirs.extend(subdirs.into_iter().rev());
+    }
+    Ok((folder, paths))
+}
+
+/// Turns a `/` separated path from a peer into a relative one that stays in the dir it is
+/// joined to.
+pub(crate) fn relative_path(path: &str) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for part in path.split('/') {
+        let mut components = Path::new(part).components();
+        let safe = !part.contains(['\\', ':', '\0'])
+            && matches!(components.next(), Some(Component::Normal(_)))
+            && components.next().is_none();
+        if !safe {
+            let message = format!("unsafe path:{path:?}");
+            return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
+        }
+        relative.push(part);
+    }
+    Ok(relative)
+}
+
+/// The relative paths of the entries of a folder, each of them must come after its parent dir.
+fn entry_paths(entries: &[FolderEntry]) -> Result<Vec<PathBuf>> {
+    let mut dirs = HashSet::new();
+    let mut paths = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let path = relative_path(&entry.path)?;
+        if let Some((parent, _)) = entry.path.rsplit_once('/') {
+            if !dirs.contains(parent) {
+                let message = format!("entry before its dir:{:?}", entry.path);
+                return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
+            }
+        }
+        if entry.kind == EntryKind::Dir {
+            dirs.insert(entry.path.as_str());
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+async fn set_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn set_mode(_path: &Path, _mode: Option<u32>) -> Result<()> {
+    Ok(())
+}
+
+/// Gives the dirs of a finished folder the modes they were sent with, they were kept writable
+/// to receive its files.
+async fn restore_dir_modes(root: &Path, entries: &[FolderEntry]) -> Result<()> {
+    // Children first, a parent may lose the permission to reach them.
+    for entry in entries.iter().rev() {
+        if entry.kind == EntryKind::Dir {
+            set_mode(&root.join(relative_path(&entry.path)?), entry.mode).await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct InfoFile {
     pub id: String,
     pub filename: String,
     pub content_offset: u64,
     pub file_length: u64,
+    pub hash: ContentHash,
+    pub mode: Option<u32>,
+    /// The peer sending the file.
+    #[serde(default)]
+    pub peer_id: String,
+    /// Paused by us or the sender, chunks are refused until it is resumed.
+    #[serde(default)]
+    pub paused: bool,
+    /// Received, but a file already has its name and the user is asked how to save it.
+    #[serde(default)]
+    pub collided: bool,
+    /// Where the file is saved, the Mojika folder if `None`.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+}
+
+/// A folder or batch being received, kept in `<id>.folder.mojika` until all its files are
+/// finished.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FolderInfo {
+    id: String,
+    entries: Vec<FolderEntry>,
+    /// Where the tree of a folder is, its dirs get their modes back once it is finished.
+    #[serde(default)]
+    root: Option<PathBuf>,
+}
+
+/// A file we send, kept in `<id>.outgoing.mojika` until the receiver has all of it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OutgoingFile {
+    pub id: String,
+    pub filename: String,
+    pub file_path: PathBuf,
+    pub file_length: u64,
+    pub peer_id: String,
+    /// Where the peer was reached, used if it isn't known anymore.
+    pub address: SocketAddr,
+    #[serde(skip)]
+    pub state: OutgoingState,
+}
+
+/// Where a file we send is at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutgoingState {
+    /// Queued or being sent.
+    #[default]
+    Sending,
+    /// Paused by us or the receiver, it waits to be resumed.
+    Paused,
+    /// Sending stopped before the receiver had the whole file, it waits to be resumed.
+    Interrupted,
+}
+
+/// What the user does to a transfer, the peer on the other side is told about it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TransferAction {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// How a received file is saved when a file already has its name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Saved as `name (1).ext`, or with the first number that is free.
+    #[default]
+    Rename,
+    Overwrite,
+    /// Kept until the user picks one of the others, see [FileTransfer::resolve_collision].
+    Ask,
+}
+
+impl CollisionPolicy {
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            "rename" => Some(Self::Rename),
+            "overwrite" => Some(Self::Overwrite),
+            "ask" => Some(Self::Ask),
+            _ => None,
+        }
+    }
+}
+
+/// A received file that was saved, with the name it was saved as.
+///
+/// A file of a batch is only told if it was renamed, as the batch with its new title.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedFile {
+    pub file_id: String,
+    /// The peer that sent it.
+    pub peer_id: String,
+    pub filename: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -49,6 +451,8 @@ pub struct FileChunk {
     pub file_id: String,
     pub content_offset: u64,
     pub content: Bytes,
+    /// Hash of the content, the receiver refuses the chunk if it doesn't match.
+    pub hash: ContentHash,
 }
 
 impl FileChunk {
@@ -56,6 +460,7 @@ impl FileChunk {
         Self {
             file_id,
             content_offset,
+            hash: ContentHash::of(&content),
             content,
         }
     }
@@ -73,11 +478,51 @@ impl Debug for FileChunk {
     }
 }
 
+type IncomingLocks = StdMutex<HashMap<String, Arc<Mutex<()>>>>;
+
+/// The lock of a file we receive, see [FileTransfer::lock_incoming].
+struct IncomingGuard<'a> {
+    locks: &'a IncomingLocks,
+    file_id: String,
+    guard: OwnedMutexGuard<()>,
+}
+
+impl Drop for IncomingGuard<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap();
+        // Shared only by the map and this guard, no one else holds or waits for it.
+        if Arc::strong_count(OwnedMutexGuard::mutex(&self.guard)) == 2 {
+            locks.remove(&self.file_id);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct FileTransfer {
+    /// The files of peers without a dir of their own.
     mojika_dir: PathBuf,
-    file_queue_sender: Sender<TransferFileCommand>,
-    _file_queue_receiver: Receiver<TransferFileCommand>,
+    /// Keeps what is being sent and received, received files are never saved there.
+    state_dir: PathBuf,
+    downloads: DownloadDirs,
+    /// The files waiting to be sent, started by [FileTransfer::start].
+    scheduler: StdMutex<TransferScheduler>,
+    /// Wakes [FileTransfer::start] when a file is queued or a slot is freed.
+    queue_changed: Notify,
+    outgoing: watch::Sender<HashMap<String, OutgoingFile>>,
+    /// The files being received, by id.
+    incoming: watch::Sender<HashMap<String, InfoFile>>,
+    /// Held while the info file of a file we receive is read and changed, by file id.
+    incoming_locks: IncomingLocks,
+    collisions: CollisionPolicy,
+    saved: broadcast::Sender<SavedFile>,
+    progress: ProgressReporter,
+    /// Held while the `<id>.folder.mojika` of a folder or batch, or the accepted batches, are
+    /// changed.
+    folder_lock: Mutex<()>,
+    /// Batches peers offered us, by batch id.
+    offers: watch::Sender<HashMap<String, PendingOffer>>,
+    /// Batches we offered, by the id the receiver gave them.
+    offered: Mutex<HashMap<String, OfferedBatch>>,
     requester: Arc<Requester>,
     peers: Arc<RwLock<Peers>>,
     self_peer: Peer,
@@ -85,114 +530,1070 @@ pub struct FileTransfer {
 
 impl FileTransfer {
     pub fn new(
-        mojika_dir: PathBuf,
+        downloads: DownloadDirs,
+        state_dir: PathBuf,
         requester: Arc<Requester>,
         peers: Arc<RwLock<Peers>>,
         self_peer: Peer,
+        limits: TransferLimits,
+        collisions: CollisionPolicy,
     ) -> Self {
-        let (file_queue_sender, _file_queue_receiver) = broadcast::channel(100);
         Self {
-            mojika_dir,
-            file_queue_sender,
-            _file_queue_receiver,
+            mojika_dir: downloads.dir.to_owned(),
+            state_dir,
+            downloads,
+            scheduler: StdMutex::new(TransferScheduler::new(limits)),
+            queue_changed: Notify::new(),
+            outgoing: watch::channel(HashMap::new()).0,
+            incoming: watch::channel(HashMap::new()).0,
+            incoming_locks: StdMutex::new(HashMap::new()),
+            collisions,
+            saved: broadcast::channel(SAVED_QUEUE_LEN).0,
+            progress: ProgressReporter::new(),
+            folder_lock: Mutex::new(()),
+            offers: watch::channel(HashMap::new()).0,
+            offered: Mutex::new(HashMap::new()),
             requester,
             peers,
             self_peer,
         }
     }
 
-    pub async fn create_file(&self, filename: String, file_length: u64) -> Result<String> {
-        let file_id = new_id().await;
-        self.create_info_file(file_id.to_owned(), filename, file_length)
-            .await?;
-        self.create_download_file(file_id.to_owned()).await?;
-        Ok(file_id)
+    pub async fn create_file(&self, peer_id: &str, file: CreateFile) -> Result<String> {
+        if relative_path(&file.filename)?.components().count() != 1 {
+            let message = format!("not a filename:{:?}", file.filename);
+            return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
+        }
+        let dir = self.download_dir(peer_id).await?;
+        let file_id = new_id();
+        self.create_info_file(file_id.to_owned(), file, peer_id, &dir)
+            .await?;
+        self.create_download_file(file_id.to_owned()).await?;
+        Ok(file_id)
+    }
+
+    /// Recreates the tree of the folder and waits for its files, returns the id of the folder
+    /// and the name it got.
+    pub async fn create_folder(
+        &self,
+        peer_id: &str,
+        folder: CreateFolder,
+    ) -> Result<(String, String)> {
+        if relative_path(&folder.name)?.components().count() != 1 {
+            let message = format!("not a folder name:{:?}", folder.name);
+            return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
+        }
+        let paths = entry_paths(&folder.entries)?;
+        let dir = self.download_dir(peer_id).await?;
+        let name = self.create_folder_root(&dir, &folder.name).await?;
+        let root_path = dir.join(relative_path(&name)?);
+
+        let folder_id = new_id();
+        self.create_folder_file(&folder_id, &folder.entries, Some(&root_path))
+            .await?;
+
+        for (index, (entry, path)) in folder.entries.iter().zip(paths).enumerate() {
+            // Entries come after their parent, so it is already there.
+            let final_path = root_path.join(&path);
+            match entry.kind {
+                EntryKind::Dir => {
+                    if self.collisions == CollisionPolicy::Overwrite {
+                        fs::create_dir_all(&final_path).await?;
+                    } else {
+                        fs::create_dir(&final_path).await?;
+                    }
+                    // We still have to write the content.
+                    set_mode(&final_path, entry.mode.map(|m| m | 0o700)).await?;
+                }
+                EntryKind::File { file_length: 0, .. } => {
+                    self.save_empty_file(&root_path, &entry.path, entry.mode)
+                        .await?;
+                }
+                EntryKind::File { file_length, hash } => {
+                    let file = CreateFile {
+                        filename: format!("{name}/{}", entry.path),
+                        file_length,
+                        hash,
+                        mode: entry.mode,
+                    };
+                    let file_id = entry_id(&folder_id, index);
+                    self.create_info_file(file_id.to_owned(), file, peer_id, &dir)
+                        .await?;
+                    self.create_download_file(file_id).await?;
+                }
+            }
+        }
+        if content_length(&folder.entries) == 0 {
+            restore_dir_modes(&root_path, &folder.entries).await?;
+        }
+        Ok((folder_id, name))
+    }
+
+    /// Creates the dir a received folder is saved in, returns the name it got.
+    ///
+    /// A taken name is numbered, unless the folder is saved over it. Nothing is received yet,
+    /// so there is nothing to ask about.
+    async fn create_folder_root(&self, dir: &Path, name: &str) -> Result<String> {
+        if self.collisions == CollisionPolicy::Overwrite {
+            fs::create_dir_all(dir.join(relative_path(name)?)).await?;
+            return Ok(name.to_owned());
+        }
+        for number in 0..=MAX_RENAMES {
+            let numbered = match number {
+                0 => name.to_owned(),
+                _ => format!("{name} ({number})"),
+            };
+            match fs::create_dir(dir.join(relative_path(&numbered)?)).await {
+                Ok(()) => return Ok(numbered),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let message = format!("no free name for the folder:{name:?}");
+        Err(MojikaError::new(ErrorCode::FileExists, message).into())
+    }
+
+    /// Saves an empty file, it is never sent, returns the name it got.
+    ///
+    /// A taken name is numbered unless the file is saved over it, there is nothing to ask about.
+    async fn save_empty_file(
+        &self,
+        dir: &Path,
+        filename: &str,
+        mode: Option<u32>,
+    ) -> Result<String> {
+        let empty_path = self.get_download_file_path(new_id());
+        File::create(&empty_path).await?;
+        let saved = match set_mode(&empty_path, mode).await {
+            Ok(()) if self.collisions == CollisionPolicy::Overwrite => {
+                move_file(&empty_path, &dir.join(relative_path(filename)?))
+                    .await
+                    .map(|()| filename.to_owned())
+            }
+            Ok(()) => save_as_free_name(&empty_path, dir, filename).await,
+            Err(e) => Err(e),
+        };
+        if saved.is_err() {
+            remove_if_exists(&empty_path).await?;
+        }
+        saved
+    }
+
+    /// Keeps the entries to tell the progress of the whole folder, unless it has no content.
+    async fn create_folder_file(
+        &self,
+        folder_id: &str,
+        entries: &[FolderEntry],
+        root: Option<&Path>,
+    ) -> Result<()> {
+        if content_length(entries) == 0 {
+            return Ok(());
+        }
+        let folder_info = FolderInfo {
+            id: folder_id.to_owned(),
+            entries: entries.to_vec(),
+            root: root.map(Path::to_owned),
+        };
+        let content_str = ron::to_string(&folder_info)?;
+        fs::write(self.get_folder_file_path(folder_id.to_owned()), content_str).await?;
+        Ok(())
+    }
+
+    /// Keeps the batch until the user accepts or declines it.
+    pub async fn offer_batch(&self, peer_id: &str, batch: OfferBatch) -> Result<()> {
+        // The id names the files of the batch.
+        if Uuid::parse_str(&batch.id).is_err() {
+            let message = format!("not a batch id:{:?}", batch.id);
+            return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
+        }
+        for file in batch.files.iter() {
+            if relative_path(&file.filename)?.components().count() != 1 {
+                let message = format!("not a filename:{:?}", file.filename);
+                return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
+            }
+        }
+        let batch_id = batch.id.to_owned();
+        let _guard = self.folder_lock.lock().await;
+        let mut kept = !self.accepted_batches().await?.contains(&batch_id);
+        let offer = PendingOffer {
+            peer_id: peer_id.to_owned(),
+            batch,
+        };
+        kept &= self.offers.send_if_modified(|o| {
+            if !kept || o.contains_key(&batch_id) {
+                return false;
+            }
+            o.insert(batch_id.to_owned(), offer);
+            true
+        });
+        if !kept {
+            let message = format!("there is an existing batch:{batch_id}");
+            return Err(MojikaError::new(ErrorCode::FileExists, message).into());
+        }
+        Ok(())
+    }
+
+    pub fn watch_offers(&self) -> watch::Receiver<HashMap<String, PendingOffer>> {
+        self.offers.subscribe()
+    }
+
+    fn take_offer(&self, batch_id: &str) -> Result<PendingOffer> {
+        let mut offer = None;
+        self.offers.send_if_modified(|o| {
+            offer = o.remove(batch_id);
+            offer.is_some()
+        });
+        offer.ok_or_else(|| {
+            let message = format!("no pending offer:{batch_id}");
+            MojikaError::new(ErrorCode::UnknownFile, message).into()
+        })
+    }
+
+    /// Waits for the files of the batch, returns the offer to answer with the names its empty
+    /// files were saved as.
+    ///
+    /// Everything is dropped with [`FileTransfer::drop_batch`] if the sender is not told.
+    pub async fn accept_batch(&self, batch_id: &str) -> Result<PendingOffer> {
+        let mut offer = {
+            // Marked as the offer is taken, so it can't be offered again meanwhile.
+            let _guard = self.folder_lock.lock().await;
+            let offer = self.take_offer(batch_id)?;
+            self.set_accepted(batch_id, true).await?;
+            offer
+        };
+        // Saved first, so the batch is kept with the names they got.
+        let renamed = match self.save_empty_files(&mut offer).await {
+            Ok(renamed) => renamed,
+            Err(e) => {
+                let _guard = self.folder_lock.lock().await;
+                if let Err(e) = self.set_accepted(batch_id, false).await {
+                    warn!("can't forget the batch:{batch_id}, {e}");
+                }
+                return Err(e);
+            }
+        };
+        if let Err(e) = self.wait_for_batch(batch_id, &offer).await {
+            self.drop_batch(batch_id, &offer).await;
+            return Err(e);
+        }
+        if renamed {
+            let saved = SavedFile {
+                file_id: batch_id.to_owned(),
+                peer_id: offer.peer_id.to_owned(),
+                filename: offer.batch.title(),
+            };
+            // No one may be subscribed.
+            let _ = self.saved.send(saved);
+        }
+        Ok(offer)
+    }
+
+    /// Saves the empty files of the batch as they are never sent, returns whether any of them
+    /// was renamed. None of them is kept if one can't be saved.
+    async fn save_empty_files(&self, offer: &mut PendingOffer) -> Result<bool> {
+        let dir = self.download_dir(&offer.peer_id).await?;
+        let mut saved = vec![];
+        let mut renamed = false;
+        for file in offer.batch.files.iter_mut() {
+            if file.file_length != 0 {
+                continue;
+            }
+            match self.save_empty_file(&dir, &file.filename, file.mode).await {
+                Ok(filename) => {
+                    renamed |= filename != file.filename;
+                    file.filename = filename;
+                    saved.push(dir.join(relative_path(&file.filename)?));
+                }
+                Err(e) => {
+                    for path in saved {
+                        remove_if_exists(&path).await?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(renamed)
+    }
+
+    async fn wait_for_batch(&self, batch_id: &str, offer: &PendingOffer) -> Result<()> {
+        let entries: Vec<_> = offer
+            .batch
+            .files
+            .iter()
+            .map(|f| FolderEntry {
+                path: f.filename.to_owned(),
+                kind: EntryKind::File {
+                    file_length: f.file_length,
+                    hash: f.hash,
+                },
+                mode: f.mode,
+            })
+            .collect();
+        self.create_folder_file(batch_id, &entries, None).await?;
+        let dir = self.download_dir(&offer.peer_id).await?;
+        for (index, file) in offer.batch.files.iter().enumerate() {
+            if file.file_length == 0 {
+                continue;
+            }
+            let file_id = entry_id(batch_id, index);
+            self.create_info_file(file_id.to_owned(), file.clone(), &offer.peer_id, &dir)
+                .await?;
+            self.create_download_file(file_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes what was kept to receive an accepted batch.
+    pub async fn drop_batch(&self, batch_id: &str, offer: &PendingOffer) {
+        for (index, _) in offer.batch.files.iter().enumerate() {
+            let file_id = entry_id(batch_id, index);
+            // Files without an info file are not known, no one else has them.
+            let _guard = self.lock_incoming(&file_id).await.ok();
+            if let Err(e) = self
+                .remove_incoming_file(&file_id, TransferState::Cancelled)
+                .await
+            {
+                warn!("can't remove the file:{file_id}, {e}");
+            }
+        }
+        // Kept if none of its files got an info file.
+        let folder_file_path = self.get_folder_file_path(batch_id.to_owned());
+        let guard = self.folder_lock.lock().await;
+        if let Err(e) = remove_if_exists(&folder_file_path).await {
+            warn!("can't remove the batch file:{folder_file_path:?}, {e}");
+        }
+        if let Err(e) = self.set_accepted(batch_id, false).await {
+            warn!("can't forget the batch:{batch_id}, {e}");
+        }
+        drop(guard);
+        let dir = match self.download_dir(&offer.peer_id).await {
+            Ok(dir) => dir,
+            Err(e) => {
+                warn!("can't remove the empty files of the batch:{batch_id}, {e}");
+                return;
+            }
+        };
+        for file in offer.batch.files.iter().filter(|f| f.file_length == 0) {
+            let removed = match relative_path(&file.filename) {
+                Ok(path) => remove_if_exists(&dir.join(path)).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = removed {
+                warn!("can't remove the empty file:{:?}, {e}", file.filename);
+            }
+        }
+    }
+
+    /// Ids of the batches we accepted, they are kept once all their files are received.
+    async fn accepted_batches(&self) -> Result<Vec<String>> {
+        let path = self.state_dir.join(ACCEPTED_BATCHES_FILENAME);
+        match fs::read_to_string(&path).await {
+            Ok(content) => Ok(ron::from_str(&content)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records whether the batch was accepted, the folder lock must be held.
+    async fn set_accepted(&self, batch_id: &str, accepted: bool) -> Result<()> {
+        let mut batches = self.accepted_batches().await?;
+        batches.retain(|id| id != batch_id);
+        if accepted {
+            batches.push(batch_id.to_owned());
+        }
+        let path = self.state_dir.join(ACCEPTED_BATCHES_FILENAME);
+        fs::write(&path, ron::to_string(&batches)?).await?;
+        Ok(())
+    }
+
+    /// Forgets the batch, returns the offer to answer.
+    pub fn decline_batch(&self, batch_id: &str) -> Result<PendingOffer> {
+        self.take_offer(batch_id)
+    }
+
+    /// Keeps the files of a batch the peer has to accept before they are sent.
+    ///
+    /// Kept before the batch is offered, the peer may answer before the offer returns.
+    pub async fn offered_batch(
+        &self,
+        batch_id: &str,
+        peer: Peer,
+        files: Vec<(CreateFile, PathBuf)>,
+    ) {
+        self.offered
+            .lock()
+            .await
+            .insert(batch_id.to_owned(), OfferedBatch { peer, files });
+    }
+
+    /// Forgets a batch the peer was never offered.
+    pub async fn forget_offered(&self, batch_id: &str) {
+        self.offered.lock().await.remove(batch_id);
+    }
+
+    /// Sends the files of the batch if `peer_id` accepted it.
+    pub async fn batch_answered(
+        &self,
+        peer_id: &str,
+        batch_id: &str,
+        accepted: bool,
+    ) -> Result<()> {
+        let mut offered = self.offered.lock().await;
+        match offered.get(batch_id) {
+            Some(batch) if batch.peer.id == peer_id => {}
+            _ => {
+                let message = format!("no batch offered to {peer_id}:{batch_id}");
+                return Err(MojikaError::new(ErrorCode::UnknownFile, message).into());
+            }
+        }
+        let batch = offered.remove(batch_id).unwrap();
+        drop(offered);
+        if !accepted {
+            debug!("{peer_id} declined the batch:{batch_id}");
+            return Ok(());
+        }
+        for (index, (file, file_path)) in batch.files.into_iter().enumerate() {
+            // Empty files are created when the batch is accepted.
+            if file.file_length == 0 {
+                continue;
+            }
+            let file_id = entry_id(batch_id, index);
+            self.send_created_file(file_id, file, file_path, &batch.peer)
+                .await;
+        }
+        Ok(())
+    }
+
+    fn get_folder_file_path(&self, folder_id: String) -> PathBuf {
+        let folder_filename = folder_id + ".folder.mojika";
+        let mut folder_file_path = self.state_dir.clone();
+        folder_file_path.push(folder_filename);
+        folder_file_path
+    }
+
+    /// Whether some file of the folder or batch still has content to receive.
+    pub fn receiving_folder(&self, folder_id: &str) -> bool {
+        self.incoming.borrow().values().any(|info_file| {
+            folder_of(&info_file.id).map(|(id, _)| id) == Some(folder_id)
+                && info_file.content_offset < info_file.file_length
+        })
+    }
+
+    /// Forgets the folder or batch of `file_id` once none of its files is left, the dirs of a
+    /// folder get their modes back.
+    async fn finish_folder(&self, file_id: &str) -> Result<()> {
+        let Some((folder_id, _)) = folder_of(file_id) else {
+            return Ok(());
+        };
+        let left = self
+            .incoming
+            .borrow()
+            .keys()
+            .any(|id| folder_of(id).map(|(id, _)| id) == Some(folder_id));
+        if left {
+            return Ok(());
+        }
+        let _guard = self.folder_lock.lock().await;
+        let folder_file_path = self.get_folder_file_path(folder_id.to_owned());
+        let content = match fs::read_to_string(&folder_file_path).await {
+            // Finished with another of its files.
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            content => content?,
+        };
+        remove_if_exists(&folder_file_path).await?;
+        let folder_info = ron::from_str::<FolderInfo>(&content)?;
+        if let Some(root) = &folder_info.root {
+            restore_dir_modes(root, &folder_info.entries).await?;
+        }
+        Ok(())
+    }
+
+    /// Records the name file `index` of a batch was saved as, returns the title of the batch
+    /// with it. The files of a folder are in a tree of their own, there is nothing to tell.
+    async fn rename_in_batch(
+        &self,
+        batch_id: &str,
+        index: usize,
+        filename: &str,
+    ) -> Result<Option<String>> {
+        let _guard = self.folder_lock.lock().await;
+        let folder_file_path = self.get_folder_file_path(batch_id.to_owned());
+        let content = fs::read_to_string(&folder_file_path).await?;
+        let mut folder_info = ron::from_str::<FolderInfo>(&content)?;
+        if folder_info.root.is_some() {
+            return Ok(None);
+        }
+        let Some(entry) = folder_info.entries.get_mut(index) else {
+            bail!("no file {index} in the batch:{batch_id}")
+        };
+        entry.path = filename.to_owned();
+        fs::write(&folder_file_path, ron::to_string(&folder_info)?).await?;
+        let names: Vec<_> = folder_info
+            .entries
+            .iter()
+            .map(|e| e.path.as_str())
+            .collect();
+        Ok(Some(batch_title(&names)))
+    }
+
+    async fn create_info_file(
+        &self,
+        file_id: String,
+        file: CreateFile,
+        peer_id: &str,
+        dir: &Path,
+    ) -> Result<()> {
+        let info_file_path = self.get_info_file_path(file_id.to_owned());
+        let content = InfoFile {
+            id: file_id,
+            filename: file.filename,
+            content_offset: 0,
+            file_length: file.file_length,
+            hash: file.hash,
+            mode: file.mode,
+            peer_id: peer_id.to_owned(),
+            paused: false,
+            collided: false,
+            dir: Some(dir.to_owned()),
+        };
+        let content_str = ron::to_string(&content)?;
+        // Never takes over the info file of another transfer.
+        let mut info_file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_file_path)
+            .await
+        {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let message = format!("there is an existing info file:{info_file_path:?}");
+                return Err(MojikaError::new(ErrorCode::FileExists, message).into());
+            }
+            open => open?,
+        };
+        info_file.write_all(content_str.as_bytes()).await?;
+        self.track_incoming(&content, TransferState::Queued);
+        self.incoming.send_modify(|i| {
+            i.insert(content.id.to_owned(), content);
+        });
+        Ok(())
+    }
+
+    fn get_info_file_path(&self, file_id: String) -> PathBuf {
+        let info_filename = file_id + ".info.mojika";
+        let mut info_file_path = self.state_dir.clone();
+        info_file_path.push(info_filename);
+        info_file_path
+    }
+
+    async fn read_info_file(&self, file_id: &str) -> Result<InfoFile> {
+        let info_file_path = self.get_info_file_path(file_id.to_owned());
+        if !tokio::fs::try_exists(&info_file_path).await? {
+            let message = format!("info file not found:{info_file_path:?}");
+            return Err(MojikaError::new(ErrorCode::UnknownFile, message).into());
+        }
+        let mut info_file = File::open(&info_file_path).await?;
+        let mut content = String::new();
+        let _count = info_file.read_to_string(&mut content).await?;
+        let info_file = ron::from_str::<InfoFile>(&content)?;
+        if info_file.id != file_id {
+            bail!("info file name and content doesn't match")
+        }
+        Ok(info_file)
+    }
+
+    /// Reads the info file of a file `peer_id` sends us, to other peers it is unknown.
+    async fn read_info_file_from(&self, peer_id: &str, file_id: &str) -> Result<InfoFile> {
+        let info_file = self.read_info_file(file_id).await?;
+        if info_file.peer_id != peer_id {
+            let message = format!("no file received from {peer_id}:{file_id}");
+            return Err(MojikaError::new(ErrorCode::UnknownFile, message).into());
+        }
+        Ok(info_file)
+    }
+
+    async fn update_info_file(&self, info_file: InfoFile) -> Result<()> {
+        let info_file_path = self.get_info_file_path(info_file.id.to_owned());
+        if !tokio::fs::try_exists(&info_file_path).await? {
+            bail!("info file not found:{:?}", info_file_path)
+        }
+        let content_str = ron::to_string(&info_file)?;
+
+        // Replaced at once, it may be read meanwhile.
+        let temp_path = info_file_path.with_extension("mojika.tmp");
+        let mut file = File::create(&temp_path).await?;
+        file.write_all(content_str.as_bytes()).await?;
+        file.sync_data().await?;
+        fs::rename(&temp_path, &info_file_path).await?;
+        debug!("info file updated: {}", content_str);
+        self.incoming.send_modify(|i| {
+            i.insert(info_file.id.to_owned(), info_file);
+        });
+        Ok(())
+    }
+
+    async fn create_download_file(&self, file_id: String) -> Result<()> {
+        let file_path = self.get_download_file_path(file_id);
+        if tokio::fs::try_exists(&file_path).await? {
+            let message = format!("there is an existing file:{file_path:?}");
+            return Err(MojikaError::new(ErrorCode::FileExists, message).into());
+        }
+        File::create(&file_path).await?;
+        Ok(())
+    }
+
+    fn get_outgoing_file_path(&self, file_id: String) -> PathBuf {
+        let outgoing_filename = file_id + ".outgoing.mojika";
+        let mut outgoing_file_path = self.state_dir.clone();
+        outgoing_file_path.push(outgoing_filename);
+        outgoing_file_path
+    }
+
+    async fn save_outgoing_file(&self, outgoing: &OutgoingFile) -> Result<()> {
+        let outgoing_file_path = self.get_outgoing_file_path(outgoing.id.to_owned());
+        let content_str = ron::to_string(outgoing)?;
+        let mut file = File::create(&outgoing_file_path).await?;
+        file.write_all(content_str.as_bytes()).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+
+    /// Picks up the files that were still being received when the app stopped.
+    async fn load_incoming_files(&self) -> Result<()> {
+        let mut entries = fs::read_dir(&self.state_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(file_id) = name
+                .to_string_lossy()
+                .strip_suffix(".info.mojika")
+                .map(String::from)
+            else {
+                continue;
+            };
+            match self.read_info_file(&file_id).await {
+                Ok(info_file) if self.save_dir(&info_file).is_err() => {
+                    warn!("info file saving out of the download dirs:{name:?}")
+                }
+                Ok(info_file) => {
+                    // It waits for the user to name it, or for the sender to resume it.
+                    let state = if info_file.collided {
+                        TransferState::Waiting
+                    } else if info_file.paused {
+                        TransferState::Paused
+                    } else {
+                        TransferState::Interrupted
+                    };
+                    self.track_incoming(&info_file, state);
+                    self.incoming.send_modify(|i| {
+                        i.insert(file_id, info_file);
+                    });
+                }
+                Err(e) => warn!("invalid info file:{name:?}, {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn watch_incoming(&self) -> watch::Receiver<HashMap<String, InfoFile>> {
+        self.incoming.subscribe()
+    }
+
+    /// The files we received as they are saved.
+    pub fn subscribe_saved(&self) -> broadcast::Receiver<SavedFile> {
+        self.saved.subscribe()
+    }
+
+    /// Progress of the files we send and receive, and of their folders and batches.
+    pub fn subscribe_progress(&self) -> broadcast::Receiver<TransferProgress> {
+        self.progress.subscribe()
+    }
+
+    fn track_incoming(&self, info_file: &InfoFile, state: TransferState) {
+        self.progress.track(TransferProgress {
+            file_id: info_file.id.to_owned(),
+            peer_id: info_file.peer_id.to_owned(),
+            direction: Direction::Receiving,
+            bytes_done: info_file.content_offset,
+            bytes_total: info_file.file_length,
+            rate: 0.0,
+            state,
+        });
+    }
+
+    fn track_outgoing(&self, outgoing: &OutgoingFile, state: TransferState) {
+        self.progress.track(TransferProgress {
+            file_id: outgoing.id.to_owned(),
+            peer_id: outgoing.peer_id.to_owned(),
+            direction: Direction::Sending,
+            bytes_done: 0,
+            bytes_total: outgoing.file_length,
+            rate: 0.0,
+            state,
+        });
+    }
+
+    /// Removes what was received of the file, which ended in `state`.
+    async fn remove_incoming_file(&self, file_id: &str, state: TransferState) -> Result<()> {
+        let download_path = self.get_download_file_path(file_id.to_owned());
+        let info_path = self.get_info_file_path(file_id.to_owned());
+        for path in [download_path, info_path] {
+            if tokio::fs::try_exists(&path).await? {
+                fs::remove_file(&path).await?;
+            }
+        }
+        self.progress.set_state(file_id, state);
+        self.forget_incoming(file_id);
+        if let Err(e) = self.finish_folder(file_id).await {
+            warn!("can't finish the folder of the file:{file_id}, {e}");
+        }
+        Ok(())
+    }
+
+    fn forget_incoming(&self, file_id: &str) {
+        self.incoming.send_modify(|i| {
+            i.remove(file_id);
+        });
+    }
+
+    /// Keeps the info file of a file we receive from changing under the caller.
+    ///
+    /// The file may be finished or removed by the time the lock is taken.
+    async fn lock_incoming(&self, file_id: &str) -> Result<IncomingGuard<'_>> {
+        let lock = {
+            let mut locks = self.incoming_locks.lock().unwrap();
+            if !self.incoming.borrow().contains_key(file_id) {
+                let message = format!("no file is received:{file_id}");
+                return Err(MojikaError::new(ErrorCode::UnknownFile, message).into());
+            }
+            locks.entry(file_id.to_owned()).or_default().clone()
+        };
+        Ok(IncomingGuard {
+            locks: &self.incoming_locks,
+            file_id: file_id.to_owned(),
+            guard: lock.lock_owned().await,
+        })
+    }
+
+    /// Pauses, resumes or cancels a file we receive, and tells the sender.
+    pub async fn control_incoming(&self, file_id: &str, action: TransferAction) -> Result<()> {
+        let guard = self.lock_incoming(file_id).await?;
+        let info_file = self.read_info_file(file_id).await?;
+        let peer_id = info_file.peer_id.to_owned();
+        self.apply_to_incoming(info_file, action).await?;
+        drop(guard);
+        let body = FileRequest::ReceiverAction {
+            file_id: file_id.to_owned(),
+            action,
+        };
+        if let Err(e) = self.tell_peer(&peer_id, None, body).await {
+            warn!("can't tell {peer_id} about the file:{file_id}, {e}");
+        }
+        Ok(())
+    }
+
+    /// Pauses, resumes or cancels the files of a folder or batch we receive, those already
+    /// that way are left alone.
+    pub async fn control_incoming_folder(&self, folder_id: &str, action: TransferAction) {
+        let files: Vec<_> = self
+            .incoming
+            .borrow()
+            .values()
+            .filter(|i| folder_of(&i.id).map(|(id, _)| id) == Some(folder_id))
+            .filter(|i| match action {
+                TransferAction::Pause => !i.paused && !i.collided,
+                TransferAction::Resume => i.paused && !i.collided,
+                TransferAction::Cancel => true,
+            })
+            .map(|i| i.id.to_owned())
+            .collect();
+        for file_id in files {
+            if let Err(e) = self.control_incoming(&file_id, action).await {
+                warn!("can't {action:?} the file:{file_id}, {e}");
+            }
+        }
     }
 
-    async fn create_info_file(
+    /// Acts on what the sender did to a file we receive from it.
+    pub async fn sender_action(
         &self,
-        file_id: String,
-        filename: String,
-        file_length: u64,
+        peer_id: &str,
+        file_id: &str,
+        action: TransferAction,
     ) -> Result<()> {
-        let info_file_path = self.get_info_file_path(file_id.to_owned());
-        if tokio::fs::try_exists(&info_file_path).await? {
-            bail!("there is an existing info file:{:?}", info_file_path)
+        let locked = match self.lock_incoming(file_id).await {
+            Ok(guard) => self.read_info_file(file_id).await.map(|i| (guard, i)),
+            Err(e) => Err(e),
+        };
+        let (_guard, info_file) = match locked {
+            Ok((guard, info_file)) if info_file.peer_id == peer_id => (guard, info_file),
+            // Already finished or cancelled.
+            Err(_) if action == TransferAction::Cancel => return Ok(()),
+            _ => {
+                let message = format!("no file received from {peer_id}:{file_id}");
+                return Err(MojikaError::new(ErrorCode::UnknownFile, message).into());
+            }
+        };
+        self.apply_to_incoming(info_file, action).await
+    }
+
+    async fn apply_to_incoming(&self, info_file: InfoFile, action: TransferAction) -> Result<()> {
+        if info_file.collided && action != TransferAction::Cancel {
+            let message = format!("the file is received, it waits for a name:{}", info_file.id);
+            return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
         }
-        let content = InfoFile {
-            id: file_id,
-            filename,
-            content_offset: 0,
-            file_length,
+        let (paused, state) = match action {
+            TransferAction::Pause => (true, TransferState::Paused),
+            TransferAction::Resume => (false, TransferState::Queued),
+            TransferAction::Cancel => {
+                let file_id = &info_file.id;
+                return self
+                    .remove_incoming_file(file_id, TransferState::Cancelled)
+                    .await;
+            }
         };
-        let content_str = ron::to_string(&content)?;
-        let mut info_file = File::create(&info_file_path).await?;
-        info_file.write_all(content_str.as_bytes()).await?;
+        if info_file.paused != paused {
+            self.progress.set_state(&info_file.id, state);
+            self.update_info_file(InfoFile {
+                paused,
+                ..info_file
+            })
+            .await?;
+        }
         Ok(())
     }
 
-    fn get_info_file_path(&self, file_id: String) -> PathBuf {
-        let info_filename = file_id + ".info.mojika";
-        let mut info_file_path = self.mojika_dir.clone();
-        info_file_path.push(info_filename);
-        info_file_path
+    /// Picks up the files that were still being sent when the app stopped, as interrupted.
+    async fn load_outgoing_files(&self) -> Result<()> {
+        let mut entries = fs::read_dir(&self.state_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if !name.to_string_lossy().ends_with(".outgoing.mojika") {
+                continue;
+            }
+            let content = fs::read_to_string(entry.path()).await?;
+            match ron::from_str::<OutgoingFile>(&content) {
+                Ok(outgoing) => {
+                    debug!("loaded interrupted outgoing file:{outgoing:?}");
+                    let outgoing = OutgoingFile {
+                        state: OutgoingState::Interrupted,
+                        ..outgoing
+                    };
+                    self.track_outgoing(&outgoing, TransferState::Interrupted);
+                    self.outgoing.send_modify(|o| {
+                        o.insert(outgoing.id.to_owned(), outgoing);
+                    });
+                }
+                Err(e) => warn!("invalid outgoing file:{name:?}, {e}"),
+            }
+        }
+        Ok(())
     }
 
-    async fn read_info_file(&self, file_id: &str) -> Result<InfoFile> {
-        let info_file_path = self.get_info_file_path(file_id.to_owned());
-        if !tokio::fs::try_exists(&info_file_path).await? {
-            bail!("info file not found:{:?}", info_file_path)
-        }
-        let mut info_file = File::open(&info_file_path).await?;
-        let mut content = String::new();
-        let _count = info_file.read_to_string(&mut content).await?;
-        let info_file = ron::from_str::<InfoFile>(&content)?;
-        if info_file.id != file_id {
-            bail!("info file name and content doesn't match")
+    /// Forgets the file we send, which ended in `state`.
+    async fn remove_outgoing_file(&self, file_id: &str, state: TransferState) -> Result<()> {
+        self.progress.set_state(file_id, state);
+        self.outgoing.send_modify(|o| {
+            o.remove(file_id);
+        });
+        let outgoing_file_path = self.get_outgoing_file_path(file_id.to_owned());
+        if tokio::fs::try_exists(&outgoing_file_path).await? {
+            fs::remove_file(&outgoing_file_path).await?;
         }
-        Ok(info_file)
+        Ok(())
     }
 
-    async fn update_info_file(&self, info_file: InfoFile) -> Result<()> {
-        debug!("update 1");
+    fn set_state(&self, file_id: &str, state: OutgoingState) {
+        let modified = self
+            .outgoing
+            .send_if_modified(|o| match o.get_mut(file_id) {
+                Some(outgoing) if outgoing.state != state => {
+                    outgoing.state = state;
+                    true
+                }
+                _ => false,
+            });
+        if modified {
+            let state = match state {
+                OutgoingState::Sending => TransferState::Queued,
+                OutgoingState::Paused => TransferState::Paused,
+                OutgoingState::Interrupted => TransferState::Interrupted,
+            };
+            self.progress.set_state(file_id, state);
+        }
+    }
 
-        let info_file_path = self.get_info_file_path(info_file.id.to_owned());
-        if !tokio::fs::try_exists(&info_file_path).await? {
-            bail!("info file not found:{:?}", info_file_path)
+    /// Resolves once the file is no longer being sent, because it was paused or cancelled.
+    async fn stopped(&self, file_id: &str) {
+        let mut outgoing = self.outgoing.subscribe();
+        loop {
+            let state = outgoing.borrow_and_update().get(file_id).map(|o| o.state);
+            if state != Some(OutgoingState::Sending) {
+                return;
+            }
+            if outgoing.changed().await.is_err() {
+                // Can't happen, we own the sender.
+                return pending().await;
+            }
         }
-        let content_str = ron::to_string(&info_file)?;
-        debug!("update 2");
+    }
 
-        let mut file = OpenOptions::new().write(true).open(&info_file_path).await?;
-        debug!("update 3");
-        file.write_all(content_str.as_bytes()).await?;
-        debug!("update 4");
+    pub fn watch_outgoing(&self) -> watch::Receiver<HashMap<String, OutgoingFile>> {
+        self.outgoing.subscribe()
+    }
 
-        file.sync_data().await?;
-        debug!("info file updated: {}", content_str);
+    /// Pauses, resumes or cancels a file we send, and tells the receiver.
+    ///
+    /// A paused or interrupted file is resumed from where the receiver left off.
+    pub async fn control_outgoing(&self, file_id: &str, action: TransferAction) -> Result<()> {
+        let Some(outgoing) = self.outgoing.borrow().get(file_id).cloned() else {
+            bail!("no outgoing file:{file_id}")
+        };
+        match action {
+            TransferAction::Pause if outgoing.state != OutgoingState::Sending => {
+                bail!("the file isn't being sent:{file_id}")
+            }
+            TransferAction::Pause => self.set_state(file_id, OutgoingState::Paused),
+            TransferAction::Resume if outgoing.state == OutgoingState::Sending => {
+                bail!("the file is still being sent:{file_id}")
+            }
+            TransferAction::Resume => {}
+            TransferAction::Cancel => {
+                self.remove_outgoing_file(file_id, TransferState::Cancelled)
+                    .await?
+            }
+        }
+        // The receiver of an interrupted file never paused it.
+        if !(action == TransferAction::Resume && outgoing.state == OutgoingState::Interrupted) {
+            let body = FileRequest::SenderAction {
+                file_id: file_id.to_owned(),
+                action,
+            };
+            if let Err(e) = self
+                .tell_peer(&outgoing.peer_id, Some(outgoing.address), body)
+                .await
+            {
+                warn!(
+                    "can't tell {} about the file:{file_id}, {e}",
+                    outgoing.peer_id
+                );
+            }
+        }
+        if action == TransferAction::Resume {
+            // After the receiver is told, so it takes the chunks again.
+            self.set_state(file_id, OutgoingState::Sending);
+            self.enqueue(outgoing);
+        }
         Ok(())
     }
 
-    async fn create_download_file(&self, file_id: String) -> Result<()> {
-        let file_path = self.get_download_file_path(file_id);
-        if tokio::fs::try_exists(&file_path).await? {
-            bail!("there is an existing file:{:?}", file_path)
+    /// Pauses, resumes or cancels the files of a folder or batch we send, those already that
+    /// way are left alone.
+    pub async fn control_outgoing_folder(&self, folder_id: &str, action: TransferAction) {
+        let files: Vec<_> = self
+            .outgoing
+            .borrow()
+            .values()
+            .filter(|o| folder_of(&o.id).map(|(id, _)| id) == Some(folder_id))
+            .filter(|o| match action {
+                TransferAction::Pause => o.state == OutgoingState::Sending,
+                TransferAction::Resume => o.state != OutgoingState::Sending,
+                TransferAction::Cancel => true,
+            })
+            .map(|o| o.id.to_owned())
+            .collect();
+        for file_id in files {
+            if let Err(e) = self.control_outgoing(&file_id, action).await {
+                warn!("can't {action:?} the file:{file_id}, {e}");
+            }
         }
-        File::create(&file_path).await?;
+    }
+
+    /// Acts on what the receiver did to a file we send to it.
+    pub async fn receiver_action(
+        &self,
+        peer_id: &str,
+        file_id: &str,
+        action: TransferAction,
+    ) -> Result<()> {
+        let outgoing = self.outgoing.borrow().get(file_id).cloned();
+        let outgoing = match outgoing {
+            Some(outgoing) if outgoing.peer_id == peer_id => outgoing,
+            // Already finished or cancelled.
+            None if action == TransferAction::Cancel => return Ok(()),
+            _ => {
+                let message = format!("no file sent to {peer_id}:{file_id}");
+                return Err(MojikaError::new(ErrorCode::UnknownFile, message).into());
+            }
+        };
+        match action {
+            TransferAction::Pause => self.set_state(file_id, OutgoingState::Paused),
+            TransferAction::Resume if outgoing.state != OutgoingState::Sending => {
+                self.set_state(file_id, OutgoingState::Sending);
+                self.enqueue(outgoing);
+            }
+            TransferAction::Resume => {}
+            TransferAction::Cancel => {
+                self.remove_outgoing_file(file_id, TransferState::Cancelled)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `body` to the peer on the other side of a transfer, at `address` if it isn't
+    /// known anymore.
+    async fn tell_peer(
+        &self,
+        peer_id: &str,
+        address: Option<SocketAddr>,
+        body: FileRequest,
+    ) -> Result<()> {
+        let known_address = self.peers.read().await.find_peer_address(peer_id).await;
+        let Some(address) = known_address.or(address) else {
+            return Err(MojikaError::unknown_peer(peer_id).into());
+        };
+        let request = Request::new(
+            self.self_peer.id.to_owned(),
+            self.self_peer.secret.to_owned(),
+            RequestBody::File(body),
+        );
+        request_as_known_peer(&self.requester, &self.self_peer, address, request).await?;
         Ok(())
     }
 
+    /// How much of the file `peer_id` sends us we have, only that peer may ask.
+    pub async fn content_offset(&self, peer_id: &str, file_id: &str) -> Result<u64> {
+        let info_file = self.read_info_file_from(peer_id, file_id).await?;
+        Ok(info_file.content_offset)
+    }
+
     fn get_download_file_path(&self, file_id: String) -> PathBuf {
         let filename = file_id + ".mojika";
-        let mut file_path = self.mojika_dir.clone();
+        let mut file_path = self.state_dir.clone();
         file_path.push(filename);
         file_path
     }
 
-    fn get_final_file_path(&self, filename: String) -> PathBuf {
-        let mut file_path = self.mojika_dir.clone();
-        file_path.push(filename);
-        file_path
+    fn get_final_file_path(&self, info_file: &InfoFile, filename: &str) -> Result<PathBuf> {
+        Ok(self.save_dir(info_file)?.join(relative_path(filename)?))
+    }
+
+    /// The dir the file is saved in, it must be one of the download dirs.
+    fn save_dir<'a>(&'a self, info_file: &'a InfoFile) -> Result<&'a Path> {
+        let dir = info_file.dir.as_ref().unwrap_or(&self.mojika_dir);
+        if !self.downloads.is_download_dir(dir) {
+            let message = format!("not a download dir:{dir:?}");
+            return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
+        }
+        Ok(dir)
+    }
+
+    /// Where the files from the peer are saved from now on, it is created if it is missing.
+    async fn download_dir(&self, peer_id: &str) -> Result<PathBuf> {
+        let peer = self.peers.read().await.find_by_id(peer_id).await;
+        let name = peer.map(|p| p.name).unwrap_or_else(|| peer_id.to_owned());
+        let dir = self.downloads.dir_for(peer_id, &name, SystemTime::now());
+        fs::create_dir_all(&dir).await?;
+        Ok(dir)
     }
 
     async fn open_download_file(&self, file_id: String) -> Result<File> {
@@ -205,42 +1606,141 @@ impl FileTransfer {
         Ok(file)
     }
 
-    pub async fn start(&self, mut shutdown: Receiver<()>) {
-        let mut file_queue_receiver = self.file_queue_sender.subscribe();
+    /// Sends the queued files as slots free up, until shutdown.
+    pub async fn start(self: Arc<Self>, mut shutdown: Receiver<()>) {
+        if let Err(e) = self.load_outgoing_files().await {
+            warn!("can't load the interrupted outgoing files:{e:?}");
+        }
+        if let Err(e) = self.load_incoming_files().await {
+            warn!("can't load the incoming files:{e:?}");
+        }
+        let mut transfers = JoinSet::new();
         loop {
+            loop {
+                let next = self.scheduler.lock().unwrap().start_next();
+                let Some((peer_id, file_id)) = next else {
+                    break;
+                };
+                let slot = Slot {
+                    file_transfer: self.clone(),
+                    peer_id,
+                    file_id: file_id.to_owned(),
+                };
+                let tfc = TransferFileCommand { file_id };
+                debug!("Got tfc: {tfc:?}");
+                transfers.spawn(async move {
+                    slot.file_transfer.transfer(tfc).await;
+                    drop(slot);
+                });
+            }
             tokio::select! {
-                Ok(tfc) = file_queue_receiver.recv() => {
-                    debug!("Got tfc: {tfc:?}");
-                    if let Err(e) = self.send_file_to_peer(tfc).await {
-                        warn!("error transferring the file:{e:?}");
+                _ = self.queue_changed.notified() => {}
+                Some(result) = transfers.join_next(), if !transfers.is_empty() => {
+                    if let Err(e) = result {
+                        warn!("transfer task failed:{e:?}");
                     }
                 }
                 res = shutdown.recv() => {
                     debug!("Got {res:?} for shutdown the server");
                     break
                 }
-                else => {
-                    warn!("Both channels closed");
-                    break
+            }
+        }
+    }
+
+    /// Sends the file of `tfc`, keeping it to be resumed if it doesn't reach the receiver.
+    async fn transfer(&self, tfc: TransferFileCommand) {
+        let file_id = tfc.file_id.to_owned();
+        let state = self.outgoing.borrow().get(&file_id).map(|o| o.state);
+        if state != Some(OutgoingState::Sending) {
+            debug!("the file was paused or cancelled while queued:{file_id}");
+            return;
+        }
+        let result = tokio::select! {
+            result = self.send_file_to_peer(tfc) => result,
+            () = self.stopped(&file_id) => {
+                debug!("stopped sending the file:{file_id}");
+                return;
+            }
+        };
+        let result = match result {
+            Ok(()) => {
+                self.remove_outgoing_file(&file_id, TransferState::Completed)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let Err(e) = result else {
+            return;
+        };
+        let remote_code = e
+            .downcast_ref::<RequestError>()
+            .and_then(RequestError::remote_code);
+        match remote_code {
+            Some(ErrorCode::UnknownFile | ErrorCode::HashMismatch) => {
+                warn!("the receiver dropped the file:{file_id}, {e}");
+                let removed = self
+                    .remove_outgoing_file(&file_id, TransferState::Failed)
+                    .await;
+                if let Err(e) = removed {
+                    warn!("can't remove the outgoing file:{e:?}");
                 }
             }
+            Some(ErrorCode::TransferPaused) => {
+                debug!("the receiver paused the file:{file_id}");
+                self.set_state(&file_id, OutgoingState::Paused);
+            }
+            _ => {
+                warn!("error transferring the file:{e:?}");
+                self.set_state(&file_id, OutgoingState::Interrupted);
+            }
+        }
+    }
+
+    /// Asks the receiver how much of the file it has written for good.
+    async fn query_offset(&self, address: SocketAddr, file_id: &str) -> Result<u64> {
+        let request = Request::new(
+            self.self_peer.id.to_owned(),
+            self.self_peer.secret.to_owned(),
+            RequestBody::File(FileRequest::QueryOffset(file_id.to_owned())),
+        );
+        let response =
+            request_as_known_peer(&self.requester, &self.self_peer, address, request).await?;
+        match response.body {
+            ResponseBody::File(FileResponse::ContentOffset(offset)) => Ok(offset),
+            body => bail!("unexpected response to the offset query:{body:?}"),
         }
     }
 
     async fn send_file_to_peer(&self, tfc: TransferFileCommand) -> Result<()> {
         debug!("sending file to peer:{tfc:?}");
-        let mut file = File::open(&tfc.file_path).await?;
+        let outgoing = self.outgoing.borrow().get(&tfc.file_id).cloned();
+        let outgoing = outgoing.ok_or(anyhow::Error::msg("cant find the outgoing file"))?;
+        let mut file = File::open(&outgoing.file_path).await?;
         let file_len = file.metadata().await?.len();
-        let mut offset = file.seek(SeekFrom::Start(tfc.content_offset)).await?;
-        debug!("file seek offset:{offset}");
+        if file_len != outgoing.file_length {
+            bail!(
+                "the file changed since it was offered:{:?}",
+                outgoing.file_path
+            )
+        }
 
-        let read_peers = self.peers.read().await;
-        let address = read_peers
-            .find_peer_address(&tfc.peer_id)
+        let address = self
+            .peers
+            .read()
             .await
-            .ok_or(anyhow::Error::msg("cant find the peer address"))?;
-        drop(read_peers);
+            .find_peer_address(&outgoing.peer_id)
+            .await
+            .unwrap_or(outgoing.address);
+        let content_offset = self.query_offset(address, &outgoing.id).await?;
+        if content_offset > file_len {
+            bail!("the receiver has more than the whole file:{content_offset}")
+        }
+        let mut offset = file.seek(SeekFrom::Start(content_offset)).await?;
+        debug!("file seek offset:{offset}");
+        self.progress.advance(&outgoing.id, offset);
         let mut buffer = [0u8; BUFFER_LEN];
+        let mut resyncs = 0;
         loop {
             let count = file.read(&mut buffer).await?;
 
@@ -249,42 +1749,114 @@ impl FileTransfer {
                 break;
             }
             let content = Bytes::copy_from_slice(&buffer[..count]);
-            let file_chunk = FileChunk::new(tfc.file_id.to_owned(), offset, content);
+            let file_chunk = FileChunk::new(outgoing.id.to_owned(), offset, content);
             debug!("{file_chunk:?}");
             let request = Request::new(
                 self.self_peer.id.to_owned(),
                 self.self_peer.secret.to_owned(),
                 RequestBody::File(FileRequest::FileChunk(file_chunk)),
             );
-            let response = self.requester.request(address, request).await?;
-            if let ResponseBody::Err(e) = response.body {
-                warn!("Got error in response of file chunk: {}", e);
-                break;
+            let options = chunk_request_options();
+            let result = self
+                .requester
+                .request_with(address, request, &options)
+                .await;
+            match result {
+                Ok(_) => {}
+                Err(RequestError::Remote(e))
+                    if e.code == ErrorCode::OffsetMismatch && resyncs < MAX_RESYNCS =>
+                {
+                    let Some(expected) = e.expected_offset().filter(|o| *o <= file_len) else {
+                        return Err(e.into());
+                    };
+                    warn!("Receiver expects offset:{expected} instead of:{offset}, continue from there");
+                    resyncs += 1;
+                    offset = file.seek(SeekFrom::Start(expected)).await?;
+                    continue;
+                }
+                Err(RequestError::Remote(e))
+                    if e.code == ErrorCode::ChunkCorrupted && resyncs < MAX_RESYNCS =>
+                {
+                    warn!("Receiver got a corrupted chunk at offset:{offset}, send it again");
+                    resyncs += 1;
+                    file.seek(SeekFrom::Start(offset)).await?;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Got error in response of file chunk at offset:{offset}: {e}");
+                    return Err(e.into());
+                }
             }
 
+            resyncs = 0;
             offset += count as u64;
-            let percent = offset as f64 / file_len as f64 * 100.0;
-            debug!("transferring percent: {percent:.1}%");
+            self.progress.advance(&outgoing.id, offset);
         }
         Ok(())
     }
 
-    pub async fn send_created_file(&self, file_id: String, file_path: PathBuf, peer_id: String) {
-        let tfc = TransferFileCommand {
-            file_id,
+    /// Starts sending a file the peer created, it is kept until the peer has all of it.
+    pub async fn send_created_file(
+        &self,
+        file_id: String,
+        file: CreateFile,
+        file_path: PathBuf,
+        peer: &Peer,
+    ) {
+        let outgoing = OutgoingFile {
+            id: file_id,
+            filename: file.filename,
             file_path,
-            peer_id,
-            content_offset: 0,
+            file_length: file.file_length,
+            peer_id: peer.id.to_owned(),
+            address: peer.address,
+            state: OutgoingState::Sending,
         };
-        if let Err(e) = self.file_queue_sender.send(tfc) {
-            warn!("send error in file_queue_sender {e:?}");
+        if let Err(e) = self.save_outgoing_file(&outgoing).await {
+            warn!("can't save the outgoing file, it can't be resumed after a restart:{e:?}");
         }
+        self.track_outgoing(&outgoing, TransferState::Queued);
+        self.outgoing.send_modify(|o| {
+            o.insert(outgoing.id.to_owned(), outgoing.clone());
+        });
+        self.enqueue(outgoing);
     }
 
-    pub async fn write_file_chunk(&self, file_chunk: FileChunk) -> Result<()> {
+    fn enqueue(&self, outgoing: OutgoingFile) {
+        self.scheduler
+            .lock()
+            .unwrap()
+            .push(&outgoing.peer_id, &outgoing.id);
+        self.queue_changed.notify_one();
+    }
+
+    /// Writes the chunk `peer_id` sent and returns the updated info of the file.
+    pub async fn write_file_chunk(&self, peer_id: &str, file_chunk: FileChunk) -> Result<InfoFile> {
         let file_id = file_chunk.file_id.as_str();
-        let mut info_file = self.read_info_file(file_id).await?;
+        if ContentHash::of(&file_chunk.content) != file_chunk.hash {
+            let message = format!("chunk doesn't match its hash:{file_chunk:?}");
+            return Err(MojikaError::new(ErrorCode::ChunkCorrupted, message).into());
+        }
+        let _guard = self.lock_incoming(file_id).await?;
+        let mut info_file = self.read_info_file_from(peer_id, file_id).await?;
         debug!("read info file: {:?}", info_file);
+        if info_file.paused {
+            let message = format!("the file is paused:{file_id}");
+            return Err(MojikaError::new(ErrorCode::TransferPaused, message).into());
+        }
+        let chunk_end = file_chunk
+            .content_offset
+            .checked_add(file_chunk.content.len() as u64)
+            .filter(|end| *end <= info_file.file_length);
+        let Some(chunk_end) = chunk_end else {
+            let message = format!("chunk goes past the end of the file:{file_chunk:?}");
+            return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
+        };
+        if chunk_end <= info_file.content_offset {
+            // A retried chunk that was already written.
+            debug!("skip already written file chunk:{file_chunk:?}");
+            return Ok(info_file);
+        }
         // check offset
         if file_chunk.content_offset == info_file.content_offset {
             let mut file = self.open_download_file(file_id.to_string()).await?;
@@ -304,16 +1876,16 @@ impl FileTransfer {
             if info_file.file_length == info_file.content_offset {
                 self.finish_download_file(&info_file, file).await?;
             } else {
-                self.update_info_file(info_file).await?;
+                self.update_info_file(info_file.clone()).await?;
+                self.progress
+                    .advance(&info_file.id, info_file.content_offset);
             }
         } else {
-            bail!(
-                "file chunk and info file offset missmatch file_chunk:{}, info_file:{}",
-                file_chunk.content_offset,
-                info_file.content_offset
-            )
+            let error =
+                MojikaError::offset_mismatch(info_file.content_offset, file_chunk.content_offset);
+            return Err(error.into());
         }
-        Ok(())
+        Ok(info_file)
     }
 
     async fn finish_download_file(&self, info_file: &InfoFile, file: File) -> Result<()> {
@@ -328,22 +1900,558 @@ impl FileTransfer {
         drop(file);
 
         let download_path = self.get_download_file_path(info_file.id.to_owned());
-        let final_path = self.get_final_file_path(info_file.filename.to_owned());
-        fs::rename(&download_path, &final_path).await?;
+        let hash = hash_file(&download_path).await?;
+        if hash != info_file.hash {
+            // The content can't be trusted, the sender has to send the whole file again.
+            self.remove_incoming_file(&info_file.id, TransferState::Failed)
+                .await?;
+            let message = format!("file hash:{hash:?} doesn't match, info_file:{info_file:?}");
+            return Err(MojikaError::new(ErrorCode::HashMismatch, message).into());
+        }
+
+        // Set before the file is moved, so it never shows up without its mode.
+        set_mode(&download_path, info_file.mode).await?;
+        self.save_download_file(info_file, self.collisions).await
+    }
+
+    /// Saves a received file that waits for a name, by renaming it or overwriting the other.
+    pub async fn resolve_collision(&self, file_id: &str, policy: CollisionPolicy) -> Result<()> {
+        let _guard = self.lock_incoming(file_id).await?;
+        let info_file = self.read_info_file(file_id).await?;
+        if !info_file.collided || policy == CollisionPolicy::Ask {
+            let message = format!("can't save {file_id} with {policy:?}");
+            return Err(MojikaError::new(ErrorCode::InvalidRequest, message).into());
+        }
+        self.save_download_file(&info_file, policy).await
+    }
 
-        let info_path = self.get_info_file_path(info_file.id.to_owned());
-        fs::remove_file(&info_path).await?;
+    /// Moves the finished file to its final path, `policy` tells what to do if it is taken.
+    async fn save_download_file(
+        &self,
+        info_file: &InfoFile,
+        policy: CollisionPolicy,
+    ) -> Result<()> {
+        let download_path = self.get_download_file_path(info_file.id.to_owned());
+        let filename = match policy {
+            CollisionPolicy::Overwrite => {
+                let final_path = self.get_final_file_path(info_file, &info_file.filename)?;
+                move_file(&download_path, &final_path).await?;
+                info_file.filename.to_owned()
+            }
+            CollisionPolicy::Rename => {
+                let dir = self.save_dir(info_file)?;
+                save_as_free_name(&download_path, dir, &info_file.filename).await?
+            }
+            CollisionPolicy::Ask => {
+                let final_path = self.get_final_file_path(info_file, &info_file.filename)?;
+                if !save_new(&download_path, &final_path).await? {
+                    debug!("file is waiting for a name:{info_file:?}");
+                    let file_id = &info_file.id;
+                    self.progress.advance(file_id, info_file.file_length);
+                    self.progress.set_state(file_id, TransferState::Waiting);
+                    let collided = InfoFile {
+                        collided: true,
+                        ..info_file.clone()
+                    };
+                    return self.update_info_file(collided).await;
+                }
+                info_file.filename.to_owned()
+            }
+        };
 
+        // Told for the whole batch, its chat has no message for each of its files.
+        let saved = match folder_of(&info_file.id) {
+            Some((batch_id, index)) if filename != info_file.filename => {
+                match self.rename_in_batch(batch_id, index, &filename).await {
+                    Ok(title) => title.map(|title| (batch_id.to_owned(), title)),
+                    Err(e) => {
+                        warn!("can't rename the file in its batch:{}, {e}", info_file.id);
+                        None
+                    }
+                }
+            }
+            Some(_) => None,
+            None => Some((info_file.id.to_owned(), filename)),
+        };
+        fs::remove_file(self.get_info_file_path(info_file.id.to_owned())).await?;
+        self.progress
+            .set_state(&info_file.id, TransferState::Completed);
+        self.forget_incoming(&info_file.id);
+        if let Err(e) = self.finish_folder(&info_file.id).await {
+            warn!("can't finish the folder of the file:{}, {e}", info_file.id);
+        }
+        if let Some((file_id, filename)) = saved {
+            let saved = SavedFile {
+                file_id,
+                peer_id: info_file.peer_id.to_owned(),
+                filename,
+            };
+            // No one may be subscribed.
+            let _ = self.saved.send(saved);
+        }
         Ok(())
     }
 }
 
+/// Saves `from` in `dir` with `filename`, or numbered if that is taken, returns the name it got.
+async fn save_as_free_name(from: &Path, dir: &Path, filename: &str) -> Result<String> {
+    for number in 0..=MAX_RENAMES {
+        let name = numbered(filename, number);
+        if save_new(from, &dir.join(relative_path(&name)?)).await? {
+            return Ok(name);
+        }
+    }
+    let message = format!("no free name for the file:{filename:?}");
+    Err(MojikaError::new(ErrorCode::FileExists, message).into())
+}
+
+/// Moves `from` to `to` unless something is at `to` already, returns whether it was moved.
+async fn save_new(from: &Path, to: &Path) -> Result<bool> {
+    match fs::hard_link(from, to).await {
+        Ok(()) => {
+            fs::remove_file(from).await?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+        // Some file systems have no hard links or `to` is on another one, there it is only
+        // checked right before.
+        Err(e) => {
+            debug!("can't link {from:?} to {to:?}, move it:{e}");
+            if tokio::fs::try_exists(to).await? {
+                return Ok(false);
+            }
+            move_file(from, to).await?;
+            Ok(true)
+        }
+    }
+}
+
+/// Removes the file, it may be gone already.
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Renames `from` to `to`, or copies it over if `to` is on another file system.
+async fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Err(e) = fs::rename(from, to).await {
+        debug!("can't rename {from:?} to {to:?}, copy it:{e}");
+        let mut temp_path = to.as_os_str().to_owned();
+        temp_path.push(".mojika.tmp");
+        fs::copy(from, &temp_path).await?;
+        fs::rename(&temp_path, to).await?;
+        fs::remove_file(from).await?;
+    }
+    Ok(())
+}
+
+/// The `/` separated `filename` with ` (number)` before its extension, unless `number` is `0`.
+fn numbered(filename: &str, number: usize) -> String {
+    if number == 0 {
+        return filename.to_owned();
+    }
+    let (dir, name) = match filename.rsplit_once('/') {
+        Some((dir, name)) => (format!("{dir}/"), name),
+        None => (String::new(), filename),
+    };
+    match name.rfind('.') {
+        // A leading dot is part of the name, not an extension.
+        Some(dot) if dot > 0 => {
+            let (stem, extension) = name.split_at(dot);
+            format!("{dir}{stem} ({number}){extension}")
+        }
+        _ => format!("{dir}{name} ({number})"),
+    }
+}
+
 #[derive(Debug, Clone)]
 struct TransferFileCommand {
     file_id: String,
-    file_path: PathBuf,
+}
+
+/// Frees the slot of a running transfer when it ends, even if it panics or is aborted.
+struct Slot {
+    file_transfer: Arc<FileTransfer>,
     peer_id: String,
-    content_offset: u64,
+    file_id: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let file_transfer = &self.file_transfer;
+        file_transfer
+            .scheduler
+            .lock()
+            .unwrap()
+            .finished(&self.peer_id, &self.file_id);
+        file_transfer.queue_changed.notify_one();
+    }
 }
 
-pub fn file_progress() {}
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use bytes::Bytes;
+    use tokio::{fs, sync::RwLock};
+    use uuid::Uuid;
+
+    use crate::{
+        app::peer::{Peer, Peers},
+        request::{
+            download::DownloadDirs,
+            error::{ErrorCode, MojikaError},
+            file::{
+                entry_id, numbered, relative_path, CollisionPolicy, ContentHash, CreateFile,
+                CreateFolder, EntryKind, FileChunk, FileTransfer, FolderEntry, OfferBatch,
+                TransferAction,
+            },
+            requester::Requester,
+            scheduler::TransferLimits,
+        },
+    };
+
+    async fn new_file_transfer(collisions: CollisionPolicy) -> (FileTransfer, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("mojika-file-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let self_peer = Peer::new(
+            "a".into(),
+            "A".into(),
+            "s".into(),
+            "127.0.0.1:1".parse().unwrap(),
+        );
+        let peers = Arc::new(RwLock::new(Peers::new(self_peer.clone())));
+        let requester = Arc::new(Requester::new(vec![]));
+        let limits = TransferLimits::default();
+        let downloads = DownloadDirs {
+            dir: dir.clone(),
+            ..DownloadDirs::default()
+        };
+        let state_dir = dir.join("state");
+        fs::create_dir(&state_dir).await.unwrap();
+        let file_transfer = FileTransfer::new(
+            downloads, state_dir, requester, peers, self_peer, limits, collisions,
+        );
+        (file_transfer, dir)
+    }
+
+    fn error_code(e: &anyhow::Error) -> ErrorCode {
+        MojikaError::from(e).code
+    }
+
+    #[test]
+    fn paths_from_peers_stay_relative() {
+        let path = relative_path("photos/2023/cat.png").unwrap();
+        assert_eq!(path, PathBuf::from("photos").join("2023").join("cat.png"));
+        for unsafe_path in [
+            "",
+            "/etc/passwd",
+            "../secret",
+            "photos/../../secret",
+            "photos//cat.png",
+            "./cat.png",
+            "photos/",
+            "C:/Windows",
+            "photos\\..\\..\\secret",
+        ] {
+            let e = relative_path(unsafe_path).unwrap_err();
+            assert_eq!(error_code(&e), ErrorCode::InvalidRequest, "{unsafe_path:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_and_files_are_verified() {
+        let content = Bytes::from_static(b"hello mojika");
+        let (file_transfer, dir) = new_file_transfer(CollisionPolicy::default()).await;
+        let file = CreateFile {
+            filename: "hello.txt".into(),
+            file_length: content.len() as u64,
+            hash: ContentHash::of(&content),
+            mode: None,
+        };
+        let file_id = file_transfer.create_file("b", file).await.unwrap();
+
+        let mut corrupted = FileChunk::new(file_id.clone(), 0, content.clone());
+        corrupted.content = Bytes::from_static(b"hello mojikA");
+        let e = file_transfer
+            .write_file_chunk("b", corrupted)
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::ChunkCorrupted);
+        assert_eq!(
+            file_transfer.content_offset("b", &file_id).await.unwrap(),
+            0
+        );
+        let e = file_transfer
+            .content_offset("c", &file_id)
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::UnknownFile);
+        let chunk = FileChunk::new(file_id.clone(), 0, content.clone());
+        let e = file_transfer
+            .write_file_chunk("c", chunk)
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::UnknownFile);
+        let past_end = FileChunk::new(file_id.clone(), 1, content.clone());
+        let e = file_transfer
+            .write_file_chunk("b", past_end)
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::InvalidRequest);
+
+        let chunk = FileChunk::new(file_id, 0, content.clone());
+        file_transfer.write_file_chunk("b", chunk).await.unwrap();
+        assert_eq!(fs::read(dir.join("hello.txt")).await.unwrap(), content);
+        fs::remove_dir_all(dir).await.unwrap();
+
+        let (file_transfer, dir) = new_file_transfer(CollisionPolicy::default()).await;
+        let file = CreateFile {
+            filename: "tampered.txt".into(),
+            file_length: content.len() as u64,
+            hash: ContentHash::of(b"something else"),
+            mode: None,
+        };
+        let file_id = file_transfer.create_file("b", file).await.unwrap();
+        let chunk = FileChunk::new(file_id.clone(), 0, content);
+        let e = file_transfer
+            .write_file_chunk("b", chunk)
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::HashMismatch);
+        assert!(!dir.join("tampered.txt").exists());
+        let e = file_transfer
+            .content_offset("b", &file_id)
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::UnknownFile);
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn taken_names_are_renamed_or_asked_about() {
+        assert_eq!(numbered("photos/cat.tar.gz", 2), "photos/cat.tar (2).gz");
+        assert_eq!(numbered(".profile", 1), ".profile (1)");
+        assert_eq!(numbered("notes", 0), "notes");
+
+        let content = Bytes::from_static(b"new content");
+        let file = CreateFile {
+            filename: "taken.txt".into(),
+            file_length: content.len() as u64,
+            hash: ContentHash::of(&content),
+            mode: None,
+        };
+        let (file_transfer, dir) = new_file_transfer(CollisionPolicy::Rename).await;
+        fs::write(dir.join("taken.txt"), "old").await.unwrap();
+        fs::write(dir.join("taken (1).txt"), "older").await.unwrap();
+        let mut saved = file_transfer.subscribe_saved();
+        let file_id = file_transfer.create_file("b", file.clone()).await.unwrap();
+        let chunk = FileChunk::new(file_id, 0, content.clone());
+        file_transfer.write_file_chunk("b", chunk).await.unwrap();
+        assert_eq!(saved.try_recv().unwrap().filename, "taken (2).txt");
+        assert_eq!(fs::read(dir.join("taken (2).txt")).await.unwrap(), content);
+        assert_eq!(fs::read(dir.join("taken.txt")).await.unwrap(), b"old");
+        fs::remove_dir_all(dir).await.unwrap();
+
+        let (file_transfer, dir) = new_file_transfer(CollisionPolicy::Ask).await;
+        fs::write(dir.join("taken.txt"), "old").await.unwrap();
+        let file_id = file_transfer.create_file("b", file).await.unwrap();
+        let chunk = FileChunk::new(file_id.clone(), 0, content.clone());
+        file_transfer.write_file_chunk("b", chunk).await.unwrap();
+        assert!(file_transfer.watch_incoming().borrow()[&file_id].collided);
+        assert_eq!(fs::read(dir.join("taken.txt")).await.unwrap(), b"old");
+        let e = file_transfer
+            .resolve_collision(&file_id, CollisionPolicy::Ask)
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::InvalidRequest);
+        file_transfer
+            .resolve_collision(&file_id, CollisionPolicy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(dir.join("taken.txt")).await.unwrap(), content);
+        assert!(file_transfer.watch_incoming().borrow().is_empty());
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn folders_are_finished_by_their_last_file() {
+        let content = Bytes::from_static(b"kept");
+        let file = |path: &str| FolderEntry {
+            path: path.into(),
+            kind: EntryKind::File {
+                file_length: content.len() as u64,
+                hash: ContentHash::of(&content),
+            },
+            mode: None,
+        };
+        let folder = CreateFolder {
+            name: "project".into(),
+            entries: vec![file("kept.txt"), file("cancelled.txt")],
+        };
+        let (file_transfer, dir) = new_file_transfer(CollisionPolicy::default()).await;
+        let (folder_id, name) = file_transfer
+            .create_folder("b", folder.clone())
+            .await
+            .unwrap();
+        assert_eq!(name, "project");
+        let folder_file_path = dir.join("state").join(format!("{folder_id}.folder.mojika"));
+        assert!(folder_file_path.exists());
+
+        file_transfer
+            .control_incoming(&entry_id(&folder_id, 1), TransferAction::Cancel)
+            .await
+            .unwrap();
+        assert!(file_transfer.receiving_folder(&folder_id));
+        assert!(folder_file_path.exists());
+        let chunk = FileChunk::new(entry_id(&folder_id, 0), 0, content.clone());
+        file_transfer.write_file_chunk("b", chunk).await.unwrap();
+        assert!(!file_transfer.receiving_folder(&folder_id));
+        assert!(!folder_file_path.exists());
+        let kept = dir.join("project").join("kept.txt");
+        assert_eq!(fs::read(kept).await.unwrap(), content);
+
+        let unknown = FileChunk::new(entry_id(&folder_id, 1), 0, content);
+        let e = file_transfer
+            .write_file_chunk("b", unknown)
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::UnknownFile);
+        assert!(file_transfer.incoming_locks.lock().unwrap().is_empty());
+
+        let (_, name) = file_transfer.create_folder("b", folder).await.unwrap();
+        assert_eq!(name, "project (1)");
+        assert!(dir.join("project (1)").is_dir());
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn folder_manifests_are_checked() {
+        let entry = |path: &str, kind: EntryKind| FolderEntry {
+            path: path.into(),
+            kind,
+            mode: None,
+        };
+        let empty = EntryKind::File {
+            file_length: 0,
+            hash: ContentHash::of(b""),
+        };
+        let folder = |name: &str, entries: Vec<FolderEntry>| CreateFolder {
+            name: name.into(),
+            entries,
+        };
+        let (file_transfer, dir) = new_file_transfer(CollisionPolicy::default()).await;
+        for rejected in [
+            folder("project/src", vec![]),
+            folder("project", vec![entry("src/main.rs", empty.clone())]),
+            folder(
+                "project",
+                vec![
+                    entry("src/main.rs", empty.clone()),
+                    entry("src", EntryKind::Dir),
+                ],
+            ),
+            folder(
+                "project",
+                vec![
+                    entry("src", empty.clone()),
+                    entry("src/main.rs", empty.clone()),
+                ],
+            ),
+        ] {
+            let e = file_transfer
+                .create_folder("b", rejected.clone())
+                .await
+                .unwrap_err();
+            assert_eq!(error_code(&e), ErrorCode::InvalidRequest, "{rejected:?}");
+        }
+        assert!(!dir.join("project").exists());
+
+        let accepted = folder(
+            "project",
+            vec![
+                entry("src", EntryKind::Dir),
+                entry("src/bin", EntryKind::Dir),
+                entry("src/bin/main.rs", empty),
+            ],
+        );
+        file_transfer.create_folder("b", accepted).await.unwrap();
+        assert!(dir.join("project/src/bin/main.rs").exists());
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepted_batches_are_not_offered_again() {
+        let empty = CreateFile {
+            filename: "empty.txt".into(),
+            file_length: 0,
+            hash: ContentHash::of(b""),
+            mode: None,
+        };
+        let batch = OfferBatch {
+            id: Uuid::new_v4().to_string(),
+            files: vec![empty],
+        };
+        let (file_transfer, dir) = new_file_transfer(CollisionPolicy::default()).await;
+        file_transfer.offer_batch("b", batch.clone()).await.unwrap();
+        let e = file_transfer
+            .offer_batch("b", batch.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::FileExists);
+        let offer = file_transfer.accept_batch(&batch.id).await.unwrap();
+        assert!(dir.join("empty.txt").exists());
+
+        // Nothing is left to receive, the batch is still known.
+        let e = file_transfer
+            .offer_batch("b", batch.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::FileExists);
+        assert!(file_transfer.watch_offers().borrow().is_empty());
+
+        // Unless the sender was never told.
+        file_transfer.drop_batch(&batch.id, &offer).await;
+        assert!(!dir.join("empty.txt").exists());
+        file_transfer.offer_batch("b", batch).await.unwrap();
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn batch_files_with_taken_names_retitle_the_batch() {
+        let content = Bytes::from_static(b"new content");
+        let file = |filename: &str, content: &[u8]| CreateFile {
+            filename: filename.into(),
+            file_length: content.len() as u64,
+            hash: ContentHash::of(content),
+            mode: None,
+        };
+        let batch = OfferBatch {
+            id: Uuid::new_v4().to_string(),
+            files: vec![file("taken.txt", &content), file("empty.txt", b"")],
+        };
+        let (file_transfer, dir) = new_file_transfer(CollisionPolicy::Rename).await;
+        fs::write(dir.join("taken.txt"), "old").await.unwrap();
+        fs::write(dir.join("empty.txt"), "old").await.unwrap();
+        let mut saved = file_transfer.subscribe_saved();
+        file_transfer.offer_batch("b", batch.clone()).await.unwrap();
+        let offer = file_transfer.accept_batch(&batch.id).await.unwrap();
+        assert_eq!(offer.batch.title(), "2 files: taken.txt, empty (1).txt");
+        assert_eq!(saved.try_recv().unwrap().filename, offer.batch.title());
+        assert!(fs::read(dir.join("empty (1).txt"))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(fs::read(dir.join("empty.txt")).await.unwrap(), b"old");
+
+        let chunk = FileChunk::new(entry_id(&batch.id, 0), 0, content.clone());
+        file_transfer.write_file_chunk("b", chunk).await.unwrap();
+        let renamed = saved.try_recv().unwrap();
+        assert_eq!(renamed.file_id, batch.id);
+        assert_eq!(renamed.filename, "2 files: taken (1).txt, empty (1).txt");
+        assert_eq!(fs::read(dir.join("taken (1).txt")).await.unwrap(), content);
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
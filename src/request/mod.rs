@@ -1,16 +1,27 @@
-use bytes::{Buf, Bytes};
 use serde::{Deserialize, Serialize};
 
-use crate::request::file::{CreateFile, FileChunk};
+use crate::request::error::MojikaError;
+use crate::request::file::{CreateFile, CreateFolder, FileChunk, OfferBatch, TransferAction};
+use crate::request::protocol::MojikaContent;
+use crate::request::registry::MojikaMessage;
 
 mod certificate_verifier;
+pub mod control;
+pub mod download;
+pub mod endpoint;
+pub mod error;
+pub mod event;
 pub mod file;
+pub mod progress;
 pub mod protocol;
+pub mod registry;
 pub mod requester;
 pub mod responder;
 pub mod response;
+pub mod scheduler;
+pub mod transport;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request {
     pub peer_id: String,
     pub secret: String,
@@ -27,27 +38,103 @@ impl Request {
     }
 }
 
-impl TryFrom<Bytes> for Request {
-    type Error = anyhow::Error;
+impl MojikaContent for Request {
+    const TYPE_NAME: &'static str = "Request";
+    const TYPE_TAG: u8 = 1;
+}
+
+impl MojikaMessage for Request {
+    fn lane(&self) -> Lane {
+        self.body.lane()
+    }
 
-    fn try_from(value: Bytes) -> Result<Self, Self::Error> {
-        let mut deserializer = rmp_serde::Deserializer::new(value.reader());
-        Ok(Deserialize::deserialize(&mut deserializer)?)
+    fn is_idempotent(&self) -> bool {
+        self.body.is_idempotent()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum RequestBody {
-    Connect,
+    /// Introduces the caller, it is reachable on `service_port` of the connection's address.
+    Connect {
+        name: String,
+        service_port: u16,
+    },
+    Ping,
     Chat(String),
     File(FileRequest),
     Ok,
-    Err(String),
+    Err(MojikaError),
+}
+
+impl RequestBody {
+    /// Whether handling the request twice has the same effect as handling it once.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            RequestBody::Chat(_) => false,
+            RequestBody::File(
+                FileRequest::CreateFile(_)
+                | FileRequest::CreateFolder(_)
+                | FileRequest::OfferBatch(_)
+                | FileRequest::AnswerOffer { .. },
+            ) => false,
+            RequestBody::File(_) => true,
+            RequestBody::Connect { .. }
+            | RequestBody::Ping
+            | RequestBody::Ok
+            | RequestBody::Err(_) => true,
+        }
+    }
+
+    pub fn lane(&self) -> Lane {
+        match self {
+            RequestBody::File(FileRequest::FileChunk(_)) => Lane::Bulk,
+            _ => Lane::Control,
+        }
+    }
+}
+
+/// Traffic class of a request, each lane has its own connection to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lane {
+    /// Chats, connects, pings and offers, small and latency sensitive.
+    Control,
+    /// File content.
+    Bulk,
+}
+
+impl Lane {
+    /// QUIC stream priority, streams with a higher one are sent first.
+    pub fn priority(&self) -> i32 {
+        match self {
+            Lane::Control => 10,
+            Lane::Bulk => 0,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum FileRequest {
     CreateFile(CreateFile),
+    CreateFolder(CreateFolder),
+    OfferBatch(OfferBatch),
+    /// The receiver's answer to the batch, sent back to the peer that offered it.
+    AnswerOffer {
+        batch_id: String,
+        accepted: bool,
+    },
     FileCreated(String),
     FileChunk(FileChunk),
+    /// The sender paused, resumed or cancelled the file, sent to the receiver.
+    SenderAction {
+        file_id: String,
+        action: TransferAction,
+    },
+    /// The receiver paused, resumed or cancelled the file, sent to the sender.
+    ReceiverAction {
+        file_id: String,
+        action: TransferAction,
+    },
+    /// Asks how much of the file with the id the receiver has, to resume sending it.
+    QueryOffset(String),
 }
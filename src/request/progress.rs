@@ -0,0 +1,420 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Display, Formatter},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast;
+
+use crate::request::file::folder_of;
+
+/// The rate is measured over this much of the latest progress.
+const RATE_WINDOW: Duration = Duration::from_secs(3);
+/// Progress a slow subscriber can fall behind on before it misses some.
+const PROGRESS_QUEUE_LEN: usize = 1024;
+/// A running transfer tells its progress at most this often, the last bytes are always told.
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sending,
+    Receiving,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    Queued,
+    Running,
+    Paused,
+    Interrupted,
+    /// Received, the user is asked how to save it over a file with the same name.
+    Waiting,
+    Completed,
+    Cancelled,
+    /// The receiver dropped the file, it has to be sent again.
+    Failed,
+}
+
+impl TransferState {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TransferState::Completed | TransferState::Cancelled | TransferState::Failed
+        )
+    }
+
+    /// Which state of its files a folder is shown with, the highest one.
+    fn rank(&self) -> u8 {
+        match self {
+            TransferState::Completed => 0,
+            TransferState::Cancelled => 1,
+            TransferState::Failed => 2,
+            TransferState::Interrupted => 3,
+            TransferState::Waiting => 4,
+            TransferState::Paused => 5,
+            TransferState::Queued => 6,
+            TransferState::Running => 7,
+        }
+    }
+}
+
+/// How far a file we send or receive is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferProgress {
+    /// Id of the file, or of the folder or batch for all of its files.
+    pub file_id: String,
+    /// The peer on the other side.
+    pub peer_id: String,
+    pub direction: Direction,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    /// Bytes per second over the last few seconds, `0` unless running.
+    pub rate: f64,
+    pub state: TransferState,
+}
+
+impl TransferProgress {
+    pub fn fraction(&self) -> f32 {
+        if self.bytes_total == 0 {
+            return 1.0;
+        }
+        (self.bytes_done as f64 / self.bytes_total as f64) as f32
+    }
+
+    /// Time left at the current rate.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.state != TransferState::Running || self.rate <= 0.0 {
+            return None;
+        }
+        let left = self.bytes_total.saturating_sub(self.bytes_done);
+        Some(Duration::from_secs_f64(left as f64 / self.rate))
+    }
+}
+
+impl Display for TransferProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let done = human_bytes(self.bytes_done as f64);
+        let total = human_bytes(self.bytes_total as f64);
+        match self.state {
+            TransferState::Queued => write!(f, "Queued."),
+            TransferState::Running => {
+                write!(f, "{done} of {total}, {}/s", human_bytes(self.rate))?;
+                if let Some(eta) = self.eta() {
+                    write!(f, ", {}s left", eta.as_secs())?;
+                }
+                Ok(())
+            }
+            TransferState::Paused => write!(f, "Paused at {done} of {total}."),
+            TransferState::Interrupted => write!(f, "Interrupted at {done} of {total}."),
+            TransferState::Waiting => write!(f, "Received, a file has the same name."),
+            TransferState::Completed => write!(f, "Completed."),
+            TransferState::Cancelled => write!(f, "Cancelled."),
+            TransferState::Failed => write!(f, "Failed."),
+        }
+    }
+}
+
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000.0 {
+        return format!("{bytes:.0} B");
+    }
+    let mut value = bytes / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Measures the rate of the bytes done over the last [RATE_WINDOW].
+#[derive(Debug, Default)]
+struct RateMeter {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateMeter {
+    fn sample(&mut self, at: Instant, bytes_done: u64) -> f64 {
+        self.samples.push_back((at, bytes_done));
+        // Keeps the last sample from before the window, to measure all of it.
+        while self.samples.len() > 2 && at.duration_since(self.samples[1].0) >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+        let (first_at, first_bytes) = self.samples[0];
+        let elapsed = at.duration_since(first_at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        bytes_done.saturating_sub(first_bytes) as f64 / elapsed
+    }
+}
+
+/// Whether progress last told `published` is due again at `now`, it is then told at `now`.
+fn publish_due(published: &mut Option<Instant>, now: Instant) -> bool {
+    if published.is_some_and(|at| now.duration_since(at) < PUBLISH_INTERVAL) {
+        return false;
+    }
+    *published = Some(now);
+    true
+}
+
+#[derive(Debug)]
+struct Tracked {
+    progress: TransferProgress,
+    meter: RateMeter,
+    /// When its running progress was last told.
+    published: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Folder {
+    files: Vec<String>,
+    meter: RateMeter,
+    published: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Transfers {
+    files: HashMap<String, Tracked>,
+    /// The files of each folder or batch, kept until all of them are finished.
+    folders: HashMap<String, Folder>,
+}
+
+/// Keeps the last progress of each transfer and tells the subscribers about every change of
+/// state, and about running ones every [PUBLISH_INTERVAL].
+///
+/// The files of a folder or batch are also added up into the progress of the folder.
+#[derive(Debug)]
+pub(crate) struct ProgressReporter {
+    sender: broadcast::Sender<TransferProgress>,
+    transfers: Mutex<Transfers>,
+}
+
+impl ProgressReporter {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(PROGRESS_QUEUE_LEN).0,
+            transfers: Mutex::new(Transfers::default()),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TransferProgress> {
+        self.sender.subscribe()
+    }
+
+    /// Starts tracking the file, from where `progress` is.
+    pub fn track(&self, progress: TransferProgress) {
+        let mut transfers = self.transfers.lock().unwrap();
+        let file_id = progress.file_id.to_owned();
+        if let Some((folder_id, _)) = folder_of(&file_id) {
+            let folder = transfers.folders.entry(folder_id.to_owned()).or_default();
+            if !folder.files.contains(&file_id) {
+                folder.files.push(file_id.to_owned());
+            }
+        }
+        let tracked = Tracked {
+            progress,
+            meter: RateMeter::default(),
+            published: None,
+        };
+        transfers.files.insert(file_id.to_owned(), tracked);
+        self.report(&mut transfers, &file_id, true);
+    }
+
+    /// The file is running and got to `bytes_done`, unless it was stopped meanwhile.
+    pub fn advance(&self, file_id: &str, bytes_done: u64) {
+        let mut transfers = self.transfers.lock().unwrap();
+        let Some(tracked) = transfers.files.get_mut(file_id) else {
+            return;
+        };
+        if !matches!(
+            tracked.progress.state,
+            TransferState::Queued | TransferState::Running
+        ) {
+            return;
+        }
+        let now = Instant::now();
+        tracked.progress.bytes_done = bytes_done;
+        tracked.progress.state = TransferState::Running;
+        tracked.progress.rate = tracked.meter.sample(now, bytes_done);
+        let last = bytes_done >= tracked.progress.bytes_total;
+        if !publish_due(&mut tracked.published, now) && !last {
+            return;
+        }
+        self.report(&mut transfers, file_id, last);
+    }
+
+    /// The file is no longer running, it is forgotten once it is finished.
+    pub fn set_state(&self, file_id: &str, state: TransferState) {
+        let mut transfers = self.transfers.lock().unwrap();
+        let Some(tracked) = transfers.files.get_mut(file_id) else {
+            return;
+        };
+        tracked.progress.state = state;
+        tracked.progress.rate = 0.0;
+        tracked.meter = RateMeter::default();
+        if state == TransferState::Completed {
+            tracked.progress.bytes_done = tracked.progress.bytes_total;
+        }
+        self.report(&mut transfers, file_id, true);
+    }
+
+    /// Tells the progress of the file, and of its folder if `changed` or it is due.
+    fn report(&self, transfers: &mut Transfers, file_id: &str, changed: bool) {
+        let progress = transfers.files[file_id].progress.clone();
+        let finished = progress.state.is_finished();
+        // No one may be subscribed.
+        let _ = self.sender.send(progress);
+        let Some((folder_id, _)) = folder_of(file_id) else {
+            if finished {
+                transfers.files.remove(file_id);
+            }
+            return;
+        };
+        let Some(folder) = self.folder_progress(transfers, folder_id, changed) else {
+            return;
+        };
+        if folder.state.is_finished() {
+            if let Some(finished) = transfers.folders.remove(folder_id) {
+                for file_id in finished.files {
+                    transfers.files.remove(&file_id);
+                }
+            }
+        }
+        let _ = self.sender.send(folder);
+    }
+
+    /// The progress of the folder, `None` if it isn't `changed` and isn't due.
+    fn folder_progress(
+        &self,
+        transfers: &mut Transfers,
+        folder_id: &str,
+        changed: bool,
+    ) -> Option<TransferProgress> {
+        let Folder {
+            files,
+            meter,
+            published,
+        } = transfers.folders.get_mut(folder_id)?;
+        let now = Instant::now();
+        if !publish_due(published, now) && !changed {
+            return None;
+        }
+        let mut tracked = files.iter().filter_map(|id| transfers.files.get(id));
+        let mut folder = TransferProgress {
+            file_id: folder_id.to_owned(),
+            ..tracked.next()?.progress.clone()
+        };
+        for file in tracked {
+            folder.bytes_done += file.progress.bytes_done;
+            folder.bytes_total += file.progress.bytes_total;
+            if file.progress.state.rank() > folder.state.rank() {
+                folder.state = file.progress.state;
+            }
+        }
+        folder.rate = match folder.state {
+            TransferState::Running => meter.sample(now, folder.bytes_done),
+            _ => {
+                *meter = RateMeter::default();
+                0.0
+            }
+        };
+        Some(folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::request::{
+        file::entry_id,
+        progress::{Direction, ProgressReporter, RateMeter, TransferProgress, TransferState},
+    };
+
+    fn progress(file_id: &str, bytes_total: u64) -> TransferProgress {
+        TransferProgress {
+            file_id: file_id.to_owned(),
+            peer_id: "b".to_owned(),
+            direction: Direction::Sending,
+            bytes_done: 0,
+            bytes_total,
+            rate: 0.0,
+            state: TransferState::Queued,
+        }
+    }
+
+    #[test]
+    fn rate_is_measured_over_the_latest_window() {
+        let start = Instant::now();
+        let mut meter = RateMeter::default();
+        assert_eq!(meter.sample(start, 0), 0.0);
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(meter.sample(at(1), 1000), 1000.0);
+        assert_eq!(meter.sample(at(2), 2000), 1000.0);
+        // Only the last three seconds are measured.
+        assert_eq!(meter.sample(at(5), 3500), 500.0);
+
+        let running = TransferProgress {
+            bytes_done: 500,
+            rate: 100.0,
+            state: TransferState::Running,
+            ..progress("f", 1500)
+        };
+        assert_eq!(running.eta(), Some(Duration::from_secs(10)));
+        assert_eq!(running.to_string(), "500 B of 1.5 KB, 100 B/s, 10s left");
+    }
+
+    #[test]
+    fn files_of_a_folder_add_up() {
+        let reporter = ProgressReporter::new();
+        let mut events = reporter.subscribe();
+        let (a, b) = (entry_id("d", 0), entry_id("d", 2));
+        reporter.track(progress(&a, 100));
+        reporter.track(progress(&b, 300));
+        reporter.advance(&a, 100);
+        reporter.set_state(&a, TransferState::Completed);
+        let mut last_folder = None;
+        while let Ok(p) = events.try_recv() {
+            if p.file_id == "d" {
+                last_folder = Some(p);
+            }
+        }
+        let folder = last_folder.unwrap();
+        assert_eq!((folder.bytes_done, folder.bytes_total), (100, 400));
+        assert_eq!(folder.state, TransferState::Queued);
+
+        reporter.set_state(&b, TransferState::Cancelled);
+        let folder = std::iter::from_fn(|| events.try_recv().ok())
+            .last()
+            .unwrap();
+        assert_eq!(folder.file_id, "d");
+        assert_eq!(folder.state, TransferState::Cancelled);
+        assert!(reporter.transfers.lock().unwrap().files.is_empty());
+    }
+
+    #[test]
+    fn running_progress_is_told_at_most_every_interval() {
+        let reporter = ProgressReporter::new();
+        let mut events = reporter.subscribe();
+        reporter.track(progress("f", 1000));
+        reporter.advance("f", 100);
+        reporter.advance("f", 200);
+        reporter.advance("f", 1000);
+        reporter.set_state("f", TransferState::Completed);
+        let told: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|p| (p.bytes_done, p.state))
+            .collect();
+        assert_eq!(
+            told,
+            [
+                (0, TransferState::Queued),
+                (100, TransferState::Running),
+                (1000, TransferState::Running),
+                (1000, TransferState::Completed),
+            ]
+        );
+    }
+}
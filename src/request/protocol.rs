@@ -1,9 +1,50 @@
 use anyhow::{bail, Result};
-use bytes::Bytes;
-use log::{debug, error, warn};
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::{debug, warn};
+use rmp_serde::Serializer;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ErrorKind,
+};
+
+use std::fmt::{Display, Formatter};
 
 pub const PROTOCOL_HEADER_MAX_LEN: usize = 2048;
+pub const PROTOCOL_CONTENT_MAX_LEN: usize = 16 * 1024 * 1024;
+
+pub const BINARY_MAGIC: [u8; 2] = *b"MJ";
+pub const BINARY_VERSION: u8 = 1;
+const VARINT_MAX_LEN: usize = 10;
+
+/// How frame headers are written on a connection, negotiated with ALPN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrameEncoding {
+    /// `type_name=…,len=…\n` followed by the content.
+    #[default]
+    Text,
+    /// Magic, version, type tag and a varint len followed by the content.
+    Binary,
+}
+
+impl FrameEncoding {
+    /// Supported encodings, the most preferred first.
+    pub const SUPPORTED: [FrameEncoding; 2] = [FrameEncoding::Binary, FrameEncoding::Text];
+
+    pub fn alpn(&self) -> &'static [u8] {
+        match self {
+            FrameEncoding::Text => b"mojika-text/1",
+            FrameEncoding::Binary => b"mojika-bin/1",
+        }
+    }
+
+    pub fn from_alpn(protocol: &[u8]) -> Option<Self> {
+        Self::SUPPORTED.into_iter().find(|e| e.alpn() == protocol)
+    }
+
+    pub fn alpn_protocols() -> Vec<Vec<u8>> {
+        Self::SUPPORTED.iter().map(|e| e.alpn().to_vec()).collect()
+    }
+}
 
 #[derive(Debug)]
 pub struct MojikaProtocol {
@@ -13,47 +54,263 @@ pub struct MojikaProtocol {
 
 #[derive(Debug)]
 pub struct MojikaProtocolHeader {
-    pub type_name: String,
+    pub content_type: ContentType,
     pub len: usize,
 }
 
+/// What a frame header calls the type of its content.
+///
+/// Text headers name it and binary ones tag it, the receiver looks the type up in its own
+/// registry of the types it handles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentType {
+    /// A frame made from its content, it can be written in either encoding.
+    Known { name: &'static str, tag: u8 },
+    /// Read from a text header.
+    Name(String),
+    /// Read from a binary header.
+    Tag(u8),
+}
+
+impl ContentType {
+    pub fn of<T: MojikaContent>() -> Self {
+        ContentType::Known {
+            name: T::TYPE_NAME,
+            tag: T::TYPE_TAG,
+        }
+    }
+
+    pub fn is<T: MojikaContent>(&self) -> bool {
+        match self {
+            ContentType::Known { name, .. } => *name == T::TYPE_NAME,
+            ContentType::Name(name) => name == T::TYPE_NAME,
+            ContentType::Tag(tag) => *tag == T::TYPE_TAG,
+        }
+    }
+}
+
+impl Display for ContentType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContentType::Known { name, .. } => write!(f, "{name}"),
+            ContentType::Name(name) => write!(f, "{name}"),
+            ContentType::Tag(tag) => write!(f, "tag:{tag}"),
+        }
+    }
+}
+
 impl MojikaProtocol {
     pub fn new(header: &str, content: Bytes) -> Result<Self> {
         let header = parse_header(header)?;
+        if header.len != content.len() {
+            bail!(
+                "header len:{} doesn't match the content len:{}",
+                header.len,
+                content.len()
+            )
+        }
         Ok(Self { header, content })
     }
-    pub async fn from_read(read: impl AsyncRead + Unpin) -> Result<Self> {
-        let mut buf_reader = BufReader::with_capacity(PROTOCOL_HEADER_MAX_LEN, read);
-        debug!("from_read");
-        let mut header = vec![];
-        let header_len = buf_reader.read_until(b'\n', &mut header).await;
-        match header_len {
-            Ok(header_len) => debug!("header_len:{:?}", header_len),
-            Err(e) => {
-                error!("errrrr:{:?}", e)
-            }
+
+    /// A frame of `T` whose content is already encoded.
+    pub fn with_content<T: MojikaContent>(content: Bytes) -> Self {
+        let header = MojikaProtocolHeader {
+            content_type: ContentType::of::<T>(),
+            len: content.len(),
         };
-        debug!(" buf_reader.read_until");
-        let header = String::from_utf8(header)?;
-        debug!("header:{header}");
+        Self { header, content }
+    }
 
-        let header = parse_header(&header)?;
-        debug!("header:{:?}", &header);
+    pub fn from_content<T: MojikaContent>(content: &T) -> Result<Self> {
+        Ok(Self::with_content::<T>(content.encode()?))
+    }
 
-        let mut content = Vec::with_capacity(header.len);
+    pub fn is<T: MojikaContent>(&self) -> bool {
+        self.header.content_type.is::<T>()
+    }
 
-        let _content_len = buf_reader.read_to_end(&mut content).await?;
-        debug!("buf_reader.read_to_end:{:?}", &header);
-        Ok(Self {
+    pub fn decode<T: MojikaContent>(self) -> Result<T> {
+        if !self.is::<T>() {
+            bail!(
+                "expected a {} frame, got:{}",
+                T::TYPE_NAME,
+                self.header.content_type
+            )
+        }
+        T::decode(self.content)
+    }
+
+    /// Reads exactly one frame, the stream must end right after its content.
+    pub async fn from_read(read: impl AsyncRead + Unpin, encoding: FrameEncoding) -> Result<Self> {
+        let mut reader = MojikaProtocolReader::new(read, encoding);
+        let Some(protocol) = reader.next_frame().await? else {
+            bail!("stream ended before the frame header")
+        };
+        if !reader.is_at_end().await? {
+            bail!("unexpected trailing data after the frame content")
+        }
+        Ok(protocol)
+    }
+
+    pub fn to_bytes(&self, encoding: FrameEncoding) -> Result<Bytes> {
+        let header = match encoding {
+            FrameEncoding::Text => Bytes::from(self.header.serialize()?),
+            FrameEncoding::Binary => self.header.serialize_binary()?,
+        };
+        let mut all = BytesMut::with_capacity(header.len() + self.content.len());
+        all.put(header);
+        all.put(self.content.as_ref());
+        Ok(all.freeze())
+    }
+
+    pub async fn write_to(
+        &self,
+        write: &mut (impl AsyncWrite + Unpin),
+        encoding: FrameEncoding,
+    ) -> Result<()> {
+        write.write_all(&self.to_bytes(encoding)?).await?;
+        Ok(())
+    }
+}
+
+/// Reads consecutive frames from a long-lived stream.
+pub struct MojikaProtocolReader<R> {
+    reader: BufReader<R>,
+    encoding: FrameEncoding,
+}
+
+impl<R: AsyncRead + Unpin> MojikaProtocolReader<R> {
+    pub fn new(read: R, encoding: FrameEncoding) -> Self {
+        Self {
+            reader: BufReader::with_capacity(PROTOCOL_HEADER_MAX_LEN, read),
+            encoding,
+        }
+    }
+
+    /// Returns `None` when the stream ends cleanly on a frame boundary.
+    pub async fn next_frame(&mut self) -> Result<Option<MojikaProtocol>> {
+        let header = match self.encoding {
+            FrameEncoding::Text => self.read_text_header().await?,
+            FrameEncoding::Binary => self.read_binary_header().await?,
+        };
+        let Some(header) = header else {
+            return Ok(None);
+        };
+        debug!("header:{:?}", &header);
+
+        if header.len > PROTOCOL_CONTENT_MAX_LEN {
+            bail!(
+                "frame content len:{} is bigger than {PROTOCOL_CONTENT_MAX_LEN}",
+                header.len
+            )
+        }
+        let mut content = vec![0u8; header.len];
+        if let Err(e) = self.reader.read_exact(&mut content).await {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                bail!(
+                    "stream ended before reading {} bytes of content",
+                    header.len
+                )
+            }
+            return Err(e.into());
+        }
+        Ok(Some(MojikaProtocol {
             header,
             content: Bytes::from(content),
-        })
+        }))
+    }
+
+    async fn read_text_header(&mut self) -> Result<Option<MojikaProtocolHeader>> {
+        let mut header = Vec::with_capacity(64);
+        let header_len = (&mut self.reader)
+            .take(PROTOCOL_HEADER_MAX_LEN as u64)
+            .read_until(b'\n', &mut header)
+            .await?;
+        if header_len == 0 {
+            return Ok(None);
+        }
+        if header.last() != Some(&b'\n') {
+            if header_len >= PROTOCOL_HEADER_MAX_LEN {
+                bail!("frame header is longer than {PROTOCOL_HEADER_MAX_LEN} bytes")
+            }
+            bail!("stream ended inside the frame header")
+        }
+        let header = String::from_utf8(header)?;
+        Ok(Some(parse_header(&header)?))
+    }
+
+    async fn read_binary_header(&mut self) -> Result<Option<MojikaProtocolHeader>> {
+        if self.is_at_end().await? {
+            return Ok(None);
+        }
+        let mut fixed = [0u8; 4];
+        if let Err(e) = self.reader.read_exact(&mut fixed).await {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                bail!("stream ended inside the frame header")
+            }
+            return Err(e.into());
+        }
+        if fixed[..2] != BINARY_MAGIC {
+            bail!("invalid frame magic:{:?}", &fixed[..2])
+        }
+        if fixed[2] != BINARY_VERSION {
+            bail!("unsupported binary frame version:{}", fixed[2])
+        }
+
+        let mut len: u64 = 0;
+        for i in 0..VARINT_MAX_LEN {
+            let byte = match self.reader.read_u8().await {
+                Ok(b) => b,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    bail!("stream ended inside the frame header")
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let value = u64::from(byte & 0x7f);
+            if i == VARINT_MAX_LEN - 1 && value > 1 {
+                bail!("frame len varint overflows u64")
+            }
+            len |= value << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(Some(MojikaProtocolHeader {
+                    content_type: ContentType::Tag(fixed[3]),
+                    len: usize::try_from(len)?,
+                }));
+            }
+        }
+        bail!("frame len varint is longer than {VARINT_MAX_LEN} bytes")
+    }
+
+    async fn is_at_end(&mut self) -> Result<bool> {
+        Ok(self.reader.fill_buf().await?.is_empty())
     }
 }
 
 impl MojikaProtocolHeader {
-    pub fn serialize(&self) -> String {
-        format!("type_name={},len={}\n", &self.type_name, &self.len)
+    pub fn serialize(&self) -> Result<String> {
+        let type_name = match &self.content_type {
+            ContentType::Known { name, .. } => name,
+            ContentType::Name(name) => name.as_str(),
+            ContentType::Tag(tag) => bail!("no type_name for binary type tag:{tag}"),
+        };
+        Ok(format!("type_name={type_name},len={}\n", &self.len))
+    }
+
+    pub fn serialize_binary(&self) -> Result<Bytes> {
+        let mut header = BytesMut::with_capacity(4 + VARINT_MAX_LEN);
+        header.put(&BINARY_MAGIC[..]);
+        header.put_u8(BINARY_VERSION);
+        header.put_u8(match &self.content_type {
+            ContentType::Known { tag, .. } | ContentType::Tag(tag) => *tag,
+            ContentType::Name(name) => bail!("no binary type tag for type_name:{name}"),
+        });
+        let mut len = self.len as u64;
+        while len >= 0x80 {
+            header.put_u8((len as u8 & 0x7f) | 0x80);
+            len >>= 7;
+        }
+        header.put_u8(len as u8);
+        Ok(header.freeze())
     }
 }
 
@@ -67,54 +324,282 @@ fn parse_header(header: &str) -> Result<MojikaProtocolHeader> {
             None => bail!("key not found in header"),
             Some(k) => k,
         };
-        debug!("header key:{key}");
         let value = match key_value.next() {
             None => bail!("value not found in header"),
             Some(v) => v,
         };
-        debug!("header value:{value}");
 
         match key {
-            "type_name" => type_name = Some(value.to_string()),
+            "type_name" if !value.is_empty() => type_name = Some(value.to_string()),
+            "type_name" => bail!("header 'type_name' is empty"),
             "len" => len = Some(value.parse::<usize>()?),
             _ => warn!("invalid header key:{key}"),
         }
     }
 
-    if type_name.is_none() {
+    let Some(type_name) = type_name else {
         bail!("header 'type_name' is missing")
     };
-    if len.is_none() {
+    let Some(len) = len else {
         bail!("header 'len' is missing")
     };
     Ok(MojikaProtocolHeader {
-        type_name: type_name.unwrap(),
-        len: len.unwrap(),
+        content_type: ContentType::Name(type_name),
+        len,
     })
 }
 
-pub trait MojikaContent {
-    fn type_name() -> String;
-    fn len() -> usize;
+/// A type carried as the content of a frame, serialized with MessagePack.
+///
+/// The name and the tag identify the type in frame headers, they are part of the wire format.
+pub trait MojikaContent: Serialize + DeserializeOwned {
+    const TYPE_NAME: &'static str;
+    const TYPE_TAG: u8;
+
+    fn encode(&self) -> Result<Bytes> {
+        let mut bytes = BytesMut::with_capacity(1024).writer();
+        self.serialize(&mut Serializer::new(&mut bytes))?;
+        Ok(bytes.into_inner().freeze())
+    }
+
+    fn decode(content: Bytes) -> Result<Self> {
+        let mut deserializer = rmp_serde::Deserializer::new(content.reader());
+        Ok(Self::deserialize(&mut deserializer)?)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::request::protocol::{MojikaProtocol, MojikaProtocolHeader};
+    use bytes::Bytes;
+
+    use crate::request::protocol::{
+        ContentType, FrameEncoding, MojikaProtocol, MojikaProtocolHeader, MojikaProtocolReader,
+        PROTOCOL_CONTENT_MAX_LEN, PROTOCOL_HEADER_MAX_LEN,
+    };
+    use crate::request::{response::Response, Request, RequestBody};
 
     #[tokio::test]
     async fn protocol_header_from_read() {
         let content = "test content";
         let header = MojikaProtocolHeader {
-            type_name: "Request".to_string(),
+            content_type: ContentType::Name("Request".to_string()),
             len: content.len(),
         };
-        let mut header_str = header.serialize();
+        let mut header_str = header.serialize().unwrap();
         header_str.push_str(content);
-        let result = MojikaProtocol::from_read(header_str.as_bytes()).await;
+        let result = MojikaProtocol::from_read(header_str.as_bytes(), FrameEncoding::Text).await;
         assert!(result.is_ok());
-        let header_result = result.unwrap().header;
-        assert_eq!(header_result.type_name, "Request");
-        assert_eq!(header_result.len, content.len());
+        let protocol = result.unwrap();
+        assert_eq!(
+            protocol.header.content_type,
+            ContentType::Name("Request".to_string())
+        );
+        assert_eq!(protocol.header.len, content.len());
+        assert_eq!(protocol.content, content.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn from_read_rejects_short_content() {
+        let input = "type_name=Request,len=20\nshort";
+        assert!(
+            MojikaProtocol::from_read(input.as_bytes(), FrameEncoding::Text)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn from_read_rejects_trailing_garbage() {
+        let input = "type_name=Request,len=4\ntestgarbage";
+        assert!(
+            MojikaProtocol::from_read(input.as_bytes(), FrameEncoding::Text)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn from_read_rejects_empty_stream() {
+        assert!(MojikaProtocol::from_read(&b""[..], FrameEncoding::Text)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn from_read_rejects_malformed_headers() {
+        let inputs = [
+            "type_name=Request\ntest",
+            "len=4\ntest",
+            "type_name=Request,len=four\ntest",
+            "type_name=,len=4\ntest",
+            "type_name=Request,len\ntest",
+            "type_name=Request,len=4",
+        ];
+        for input in inputs {
+            let result = MojikaProtocol::from_read(input.as_bytes(), FrameEncoding::Text).await;
+            assert!(result.is_err(), "accepted malformed input:{input:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn from_read_rejects_too_long_header() {
+        let input = format!("type_name={},len=0\n", "a".repeat(PROTOCOL_HEADER_MAX_LEN));
+        assert!(
+            MojikaProtocol::from_read(input.as_bytes(), FrameEncoding::Text)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn from_read_rejects_too_big_content_len() {
+        let input = format!("type_name=Request,len={}\n", PROTOCOL_CONTENT_MAX_LEN + 1);
+        assert!(
+            MojikaProtocol::from_read(input.as_bytes(), FrameEncoding::Text)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn reader_reads_consecutive_frames() {
+        let first = MojikaProtocol::with_content::<Request>(Bytes::from_static(b"first"));
+        let second = MojikaProtocol::with_content::<Response>(Bytes::from_static(b"2nd\n"));
+        let mut input = first.to_bytes(FrameEncoding::Text).unwrap().to_vec();
+        input.extend_from_slice(&second.to_bytes(FrameEncoding::Text).unwrap());
+
+        let mut reader = MojikaProtocolReader::new(input.as_slice(), FrameEncoding::Text);
+        let frame = reader.next_frame().await.unwrap().unwrap();
+        assert!(frame.is::<Request>());
+        assert_eq!(frame.content, "first");
+        let frame = reader.next_frame().await.unwrap().unwrap();
+        assert!(frame.is::<Response>());
+        assert_eq!(frame.content, "2nd\n");
+        assert!(reader.next_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reader_rejects_truncated_second_frame() {
+        let first = MojikaProtocol::with_content::<Request>(Bytes::from_static(b"first"));
+        let mut input = first.to_bytes(FrameEncoding::Text).unwrap().to_vec();
+        input.extend_from_slice(b"type_name=Request,len=10\nabc");
+
+        let mut reader = MojikaProtocolReader::new(input.as_slice(), FrameEncoding::Text);
+        assert!(reader.next_frame().await.unwrap().is_some());
+        assert!(reader.next_frame().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_in_both_encodings() {
+        let big = Bytes::from(vec![7u8; 200_000]);
+        for encoding in FrameEncoding::SUPPORTED {
+            let frames = [
+                MojikaProtocol::with_content::<Request>(Bytes::new()),
+                MojikaProtocol::with_content::<Response>(Bytes::from_static(b"ok\n")),
+                MojikaProtocol::with_content::<Request>(big.clone()),
+            ];
+            let mut input = vec![];
+            for frame in frames.iter() {
+                input.extend_from_slice(&frame.to_bytes(encoding).unwrap());
+            }
+
+            let mut reader = MojikaProtocolReader::new(input.as_slice(), encoding);
+            for frame in frames.iter() {
+                let read = reader.next_frame().await.unwrap().unwrap();
+                assert_eq!(read.is::<Request>(), frame.is::<Request>());
+                assert_eq!(read.is::<Response>(), frame.is::<Response>());
+                assert_eq!(read.header.len, frame.header.len);
+                assert_eq!(read.content, frame.content);
+            }
+            assert!(reader.next_frame().await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_header_is_compact() {
+        let frame = MojikaProtocol::with_content::<Request>(Bytes::from(vec![0u8; 200_000]));
+        let bytes = frame.to_bytes(FrameEncoding::Binary).unwrap();
+        assert_eq!(&bytes[..4], b"MJ\x01\x01");
+        assert_eq!(bytes.len() - frame.content.len(), 7);
+    }
+
+    #[tokio::test]
+    async fn binary_rejects_malformed_headers() {
+        let inputs: [&[u8]; 5] = [
+            b"XX\x01\x01\x04test",
+            b"MJ\x02\x01\x04test",
+            b"MJ\x01",
+            b"MJ\x01\x01\x84",
+            b"MJ\x01\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01",
+        ];
+        for input in inputs {
+            let result = MojikaProtocol::from_read(input, FrameEncoding::Binary).await;
+            assert!(result.is_err(), "accepted malformed input:{input:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_rejects_short_content_and_trailing_garbage() {
+        let short = b"MJ\x01\x01\x0atest";
+        assert!(MojikaProtocol::from_read(&short[..], FrameEncoding::Binary)
+            .await
+            .is_err());
+        let trailing = b"MJ\x01\x01\x04testgarbage";
+        assert!(
+            MojikaProtocol::from_read(&trailing[..], FrameEncoding::Binary)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn content_round_trips_through_a_frame() {
+        let request = Request::new("id".into(), "secret".into(), RequestBody::Ping);
+        for encoding in FrameEncoding::SUPPORTED {
+            let bytes = MojikaProtocol::from_content(&request)
+                .unwrap()
+                .to_bytes(encoding)
+                .unwrap();
+            let frame = MojikaProtocol::from_read(bytes.as_ref(), encoding)
+                .await
+                .unwrap();
+            assert!(frame.is::<Request>());
+            let decoded: Request = frame.decode().unwrap();
+            assert_eq!(decoded.peer_id, "id");
+            assert_eq!(decoded.body, RequestBody::Ping);
+        }
+        let frame = MojikaProtocol::with_content::<Response>(Bytes::new());
+        assert!(frame.decode::<Request>().is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_are_typed_by_their_header() {
+        // An unknown tag is read, it is up to the receiver to handle its type or not.
+        let frame = MojikaProtocol::from_read(&b"MJ\x01\xff\x00"[..], FrameEncoding::Binary)
+            .await
+            .unwrap();
+        assert_eq!(frame.header.content_type, ContentType::Tag(0xff));
+        assert!(frame.decode::<Request>().is_err());
+
+        // A frame keeps the name or the tag it was read with, so it can't switch encodings.
+        let frame = MojikaProtocol::from_read(&b"MJ\x01\x01\x00"[..], FrameEncoding::Binary)
+            .await
+            .unwrap();
+        assert!(frame.is::<Request>());
+        assert!(frame.to_bytes(FrameEncoding::Text).is_err());
+        let frame =
+            MojikaProtocol::from_read(&b"type_name=Request,len=0\n"[..], FrameEncoding::Text)
+                .await
+                .unwrap();
+        assert!(frame.is::<Request>());
+        assert!(frame.to_bytes(FrameEncoding::Binary).is_err());
+    }
+
+    #[test]
+    fn encoding_from_alpn() {
+        for encoding in FrameEncoding::SUPPORTED {
+            assert_eq!(FrameEncoding::from_alpn(encoding.alpn()), Some(encoding));
+        }
+        assert_eq!(FrameEncoding::from_alpn(b"h3"), None);
     }
 }
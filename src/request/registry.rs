@@ -0,0 +1,495 @@
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use serde::{
+    ser::{self, Impossible},
+    Serialize, Serializer,
+};
+
+use crate::request::{
+    error::{ErrorCode, MojikaError},
+    protocol::{ContentType, FrameEncoding, MojikaContent, MojikaProtocol, MojikaProtocolReader},
+    response::{Response, ResponseBody},
+    transport::{RecvHalf, SendHalf},
+    Lane, Request,
+};
+
+/// A message a peer sends on its own stream, handled by its registered handler and answered
+/// with a `Response`.
+pub trait MojikaMessage: MojikaContent + Send + 'static {
+    fn lane(&self) -> Lane {
+        Lane::Control
+    }
+
+    /// Whether handling the message twice has the same effect as handling it once.
+    fn is_idempotent(&self) -> bool {
+        false
+    }
+}
+
+pub type Handling = Pin<Box<dyn Future<Output = Result<ResponseBody>> + Send>>;
+
+pub type Serving = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The rest of a stream whose first message takes it over.
+pub struct OpenStream {
+    pub send: SendHalf,
+    pub reader: MojikaProtocolReader<RecvHalf>,
+    pub encoding: FrameEncoding,
+}
+
+/// A decoded message, ready to be handled.
+pub enum Dispatch {
+    /// Answered with a `Response`, the stream ends with the message.
+    Answer { lane: Lane, handling: Handling },
+    /// Serves the rest of the stream.
+    Stream(Box<dyn FnOnce(OpenStream) -> Serving + Send>),
+}
+
+type Handler<C> = Box<dyn Fn(Arc<C>, Bytes, SocketAddr) -> Result<Dispatch> + Send + Sync>;
+
+/// Handlers of the messages peers send, looked up by the type name or tag of the frame.
+///
+/// A new kind of message implements [`MojikaMessage`] and registers its own handler.
+pub struct MessageRegistry<C> {
+    handlers: HashMap<&'static str, Handler<C>>,
+    /// Type names by binary tag, the one of `Response` is taken as every message is answered
+    /// with it.
+    tags: HashMap<u8, &'static str>,
+}
+
+impl<C: Send + Sync + 'static> MessageRegistry<C> {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            tags: HashMap::from([(Response::TYPE_TAG, Response::TYPE_NAME)]),
+        }
+    }
+
+    /// Registers the handler answering messages of `M`.
+    pub fn register<M, F, Fut>(&mut self, handler: F) -> Result<()>
+    where
+        M: MojikaMessage,
+        F: Fn(Arc<C>, M, SocketAddr) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ResponseBody>> + Send + 'static,
+    {
+        self.insert::<M>(Box::new(move |context, content, remote_addr| {
+            let message = decode::<M>(content)?;
+            Ok(Dispatch::Answer {
+                lane: message.lane(),
+                handling: Box::pin(handler(context, message, remote_addr)),
+            })
+        }))
+    }
+
+    /// Registers the handler serving the streams a message of `M` opens.
+    pub fn register_stream<M, F, Fut>(&mut self, handler: F) -> Result<()>
+    where
+        M: MojikaMessage,
+        F: Fn(Arc<C>, M, OpenStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.insert::<M>(Box::new(move |context, content, _| {
+            let message = decode::<M>(content)?;
+            let handler = handler.clone();
+            Ok(Dispatch::Stream(Box::new(move |stream| {
+                Box::pin(handler(context, message, stream))
+            })))
+        }))
+    }
+
+    fn insert<M: MojikaMessage>(&mut self, handler: Handler<C>) -> Result<()> {
+        if self.handlers.contains_key(M::TYPE_NAME) {
+            bail!("a handler for {} is already registered", M::TYPE_NAME)
+        }
+        if let Some(name) = self.tags.get(&M::TYPE_TAG) {
+            bail!(
+                "{} can't be tagged {}, {name} is",
+                M::TYPE_NAME,
+                M::TYPE_TAG
+            )
+        }
+        self.handlers.insert(M::TYPE_NAME, handler);
+        self.tags.insert(M::TYPE_TAG, M::TYPE_NAME);
+        Ok(())
+    }
+
+    /// Decodes `frame` for the handler registered to its type.
+    pub fn dispatch(
+        &self,
+        context: Arc<C>,
+        frame: MojikaProtocol,
+        remote_addr: SocketAddr,
+    ) -> Result<Dispatch> {
+        let content_type = &frame.header.content_type;
+        let type_name = match content_type {
+            ContentType::Known { name, .. } => Some(*name),
+            ContentType::Name(name) => Some(name.as_str()),
+            ContentType::Tag(tag) => self.tags.get(tag).copied(),
+        };
+        let Some(handler) = type_name.and_then(|name| self.handlers.get(name)) else {
+            let message = format!("no handler for {content_type} messages");
+            bail!(MojikaError::new(ErrorCode::InvalidRequest, message))
+        };
+        handler(context, frame.content, remote_addr)
+    }
+}
+
+fn decode<M: MojikaMessage>(content: Bytes) -> Result<M> {
+    M::decode(content).map_err(|e| {
+        let message = format!("invalid {} message: {e}", M::TYPE_NAME);
+        MojikaError::new(ErrorCode::InvalidRequest, message).into()
+    })
+}
+
+impl<C: Send + Sync + 'static> Default for MessageRegistry<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> Debug for MessageRegistry<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MessageRegistry")
+            .field("types", &self.handlers.keys())
+            .finish()
+    }
+}
+
+type Route<C> = Box<dyn Fn(Arc<C>, Request, SocketAddr) -> Handling + Send + Sync>;
+
+/// Handlers of the requests peers send, looked up by the kind of their body.
+///
+/// The kind is the name of the body's variant, the innermost one for nested bodies like
+/// `File(CreateFile(..))`. A new kind of request adds its variant and routes it to its own
+/// handler.
+pub struct RequestRoutes<C> {
+    routes: HashMap<&'static str, Route<C>>,
+}
+
+impl<C: Send + Sync + 'static> RequestRoutes<C> {
+    pub fn new() -> Self {
+        Self {
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn register<F, Fut>(&mut self, kind: &'static str, handler: F) -> Result<()>
+    where
+        F: Fn(Arc<C>, Request, SocketAddr) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ResponseBody>> + Send + 'static,
+    {
+        if self.routes.contains_key(kind) {
+            bail!("a handler for {kind} requests is already registered")
+        }
+        let route: Route<C> = Box::new(move |context, request, remote_addr| {
+            Box::pin(handler(context, request, remote_addr))
+        });
+        self.routes.insert(kind, route);
+        Ok(())
+    }
+
+    /// Hands `request` to the handler of its kind.
+    pub fn route(&self, context: Arc<C>, request: Request, remote_addr: SocketAddr) -> Handling {
+        let kind = variant_name(&request.body).unwrap_or_default();
+        match self.routes.get(kind) {
+            Some(route) => route(context, request, remote_addr),
+            None => Box::pin(async move {
+                let message = format!("no handler for {kind} requests");
+                bail!(MojikaError::new(ErrorCode::InvalidRequest, message))
+            }),
+        }
+    }
+}
+
+impl<C: Send + Sync + 'static> Default for RequestRoutes<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> Debug for RequestRoutes<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestRoutes")
+            .field("kinds", &self.routes.keys())
+            .finish()
+    }
+}
+
+/// Name of the innermost enum variant `value` is serialized as, if it is one.
+fn variant_name<T: Serialize>(value: &T) -> Option<&'static str> {
+    let mut name = None;
+    // Fails on the first value that isn't a variant, the name is whatever was found before.
+    let _ = value.serialize(VariantName(&mut name));
+    name
+}
+
+/// Serializes nothing but the names of nested variants.
+struct VariantName<'a>(&'a mut Option<&'static str>);
+
+#[derive(Debug)]
+struct NotAVariant;
+
+impl std::fmt::Display for NotAVariant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not an enum variant")
+    }
+}
+
+impl std::error::Error for NotAVariant {}
+
+impl ser::Error for NotAVariant {
+    fn custom<T: std::fmt::Display>(_: T) -> Self {
+        NotAVariant
+    }
+}
+
+macro_rules! not_a_variant {
+    ($($method:ident($($arg:ty),*) -> $ok:ty;)*) => {
+        $(fn $method(self, $(_: $arg),*) -> Result<$ok, NotAVariant> {
+            Err(NotAVariant)
+        })*
+    };
+}
+
+impl<'a> Serializer for VariantName<'a> {
+    type Ok = ();
+    type Error = NotAVariant;
+    type SerializeSeq = Impossible<(), NotAVariant>;
+    type SerializeTuple = Impossible<(), NotAVariant>;
+    type SerializeTupleStruct = Impossible<(), NotAVariant>;
+    type SerializeTupleVariant = Impossible<(), NotAVariant>;
+    type SerializeMap = Impossible<(), NotAVariant>;
+    type SerializeStruct = Impossible<(), NotAVariant>;
+    type SerializeStructVariant = Impossible<(), NotAVariant>;
+
+    fn serialize_unit_variant(
+        self,
+        _: &str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<(), NotAVariant> {
+        *self.0 = Some(variant);
+        Ok(())
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), NotAVariant> {
+        *self.0 = Some(variant);
+        value.serialize(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, NotAVariant> {
+        *self.0 = Some(variant);
+        Err(NotAVariant)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, NotAVariant> {
+        *self.0 = Some(variant);
+        Err(NotAVariant)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _: &T) -> Result<(), NotAVariant> {
+        Err(NotAVariant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: &T,
+    ) -> Result<(), NotAVariant> {
+        Err(NotAVariant)
+    }
+
+    not_a_variant! {
+        serialize_bool(bool) -> ();
+        serialize_i8(i8) -> ();
+        serialize_i16(i16) -> ();
+        serialize_i32(i32) -> ();
+        serialize_i64(i64) -> ();
+        serialize_u8(u8) -> ();
+        serialize_u16(u16) -> ();
+        serialize_u32(u32) -> ();
+        serialize_u64(u64) -> ();
+        serialize_f32(f32) -> ();
+        serialize_f64(f64) -> ();
+        serialize_char(char) -> ();
+        serialize_str(&str) -> ();
+        serialize_bytes(&[u8]) -> ();
+        serialize_none() -> ();
+        serialize_unit() -> ();
+        serialize_unit_struct(&'static str) -> ();
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use tokio::sync::Mutex;
+
+    use crate::request::{
+        error::{ErrorCode, MojikaError},
+        protocol::{FrameEncoding, MojikaContent, MojikaProtocol},
+        registry::{variant_name, Dispatch, MessageRegistry, MojikaMessage, RequestRoutes},
+        response::{Response, ResponseBody},
+        FileRequest, Lane, Request, RequestBody,
+    };
+
+    #[tokio::test]
+    async fn frames_are_dispatched_to_the_registered_handler() {
+        let mut registry = MessageRegistry::<Mutex<Vec<String>>>::new();
+        registry
+            .register(
+                |handled: Arc<Mutex<Vec<_>>>, request: Request, _| async move {
+                    handled.lock().await.push(request.peer_id);
+                    Ok(ResponseBody::Pong)
+                },
+            )
+            .unwrap();
+        assert!(registry
+            .register(|_, _: Request, _| async { Ok(ResponseBody::Ok) })
+            .is_err());
+
+        let handled = Arc::new(Mutex::new(vec![]));
+        let remote_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let request = Request::new("a".into(), "s".into(), RequestBody::Ping);
+        let frame = MojikaProtocol::from_content(&request).unwrap();
+        let Dispatch::Answer { lane, handling } = registry
+            .dispatch(handled.clone(), frame, remote_addr)
+            .unwrap()
+        else {
+            panic!("a request is answered")
+        };
+        assert_eq!(lane, Lane::Control);
+        assert_eq!(handling.await.unwrap(), ResponseBody::Pong);
+        assert_eq!(*handled.lock().await, ["a"]);
+
+        // Binary frames only carry the tag of their type.
+        let bytes = MojikaProtocol::from_content(&request)
+            .unwrap()
+            .to_bytes(FrameEncoding::Binary)
+            .unwrap();
+        let frame = MojikaProtocol::from_read(bytes.as_ref(), FrameEncoding::Binary)
+            .await
+            .unwrap();
+        let dispatch = registry.dispatch(handled.clone(), frame, remote_addr);
+        assert!(matches!(dispatch, Ok(Dispatch::Answer { .. })));
+
+        let response = Response::create_ok_response("a".into());
+        let frame = MojikaProtocol::from_content(&response).unwrap();
+        let error = registry
+            .dispatch(handled, frame, remote_addr)
+            .err()
+            .unwrap();
+        assert_eq!(MojikaError::from(&error).code, ErrorCode::InvalidRequest);
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Impostor;
+
+    impl MojikaContent for Impostor {
+        const TYPE_NAME: &'static str = "Impostor";
+        const TYPE_TAG: u8 = Request::TYPE_TAG;
+    }
+
+    impl MojikaMessage for Impostor {}
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Unanswerable;
+
+    impl MojikaContent for Unanswerable {
+        const TYPE_NAME: &'static str = "Unanswerable";
+        const TYPE_TAG: u8 = Response::TYPE_TAG;
+    }
+
+    impl MojikaMessage for Unanswerable {}
+
+    #[test]
+    fn message_types_have_their_own_tag() {
+        let mut registry = MessageRegistry::<()>::new();
+        registry
+            .register(|_, _: Request, _| async { Ok(ResponseBody::Ok) })
+            .unwrap();
+        assert!(registry
+            .register(|_, _: Impostor, _| async { Ok(ResponseBody::Ok) })
+            .is_err());
+        assert!(registry
+            .register(|_, _: Unanswerable, _| async { Ok(ResponseBody::Ok) })
+            .is_err());
+        // Each registry has its own types.
+        let mut other = MessageRegistry::<()>::new();
+        assert!(other
+            .register(|_, _: Impostor, _| async { Ok(ResponseBody::Ok) })
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn requests_are_routed_by_their_kind() {
+        let mut routes = RequestRoutes::<()>::new();
+        routes
+            .register("Ping", |_, _, _| async { Ok(ResponseBody::Pong) })
+            .unwrap();
+        assert!(routes
+            .register("Ping", |_, _, _| async { Ok(ResponseBody::Ok) })
+            .is_err());
+
+        let remote_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let ping = Request::new("a".into(), "s".into(), RequestBody::Ping);
+        let routed = routes.route(Arc::new(()), ping, remote_addr).await;
+        assert_eq!(routed.unwrap(), ResponseBody::Pong);
+
+        let chat = Request::new("a".into(), "s".into(), RequestBody::Chat("hi".into()));
+        let error = routes.route(Arc::new(()), chat, remote_addr).await;
+        let error = error.err().unwrap();
+        assert_eq!(MojikaError::from(&error).code, ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn kinds_are_the_innermost_variant_names() {
+        assert_eq!(variant_name(&RequestBody::Ping), Some("Ping"));
+        assert_eq!(variant_name(&RequestBody::Chat("hi".into())), Some("Chat"));
+        let answer = FileRequest::AnswerOffer {
+            batch_id: "b".into(),
+            accepted: true,
+        };
+        assert_eq!(
+            variant_name(&RequestBody::File(answer)),
+            Some("AnswerOffer")
+        );
+        let query = FileRequest::QueryOffset("f".into());
+        assert_eq!(variant_name(&RequestBody::File(query)), Some("QueryOffset"));
+        assert_eq!(variant_name(&"not a variant"), None);
+    }
+}
@@ -1,87 +1,262 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
-use bytes::{BufMut, BytesMut};
-use log::{debug, error};
-use quinn::{ClientConfig, Connection, Endpoint, RecvStream, SendStream};
-use rmp_serde::Serializer;
-use serde::Serialize;
+use anyhow::{anyhow, Result};
+use log::debug;
+use rand::Rng;
+use tokio::{io::AsyncWriteExt, sync::Mutex, time::sleep, time::timeout};
 
 use crate::{
-    request::certificate_verifier::SkipServerVerification,
-    request::protocol::{MojikaProtocol, MojikaProtocolHeader},
-    request::Request,
-    request::response::Response
+    request::error::RequestError,
+    request::protocol::{FrameEncoding, MojikaProtocol},
+    request::registry::MojikaMessage,
+    request::response::{Response, ResponseBody},
+    request::transport::{RecvHalf, SendHalf, Stream, Transport, TransportKind},
+    request::{Lane, Request},
 };
 
+const PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone)]
+pub struct RequestOptions {
+    /// Time allowed for each attempt, including connecting to the peer.
+    pub timeout: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Exponential backoff with jitter between attempts.
+///
+/// Requests that are not idempotent are only retried when they were never sent.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// The delay before the attempt following `attempt`, half of it is random.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self
+            .initial_backoff
+            .mul_f64(self.multiplier.powi(exponent))
+            .min(self.max_backoff);
+        let half = backoff / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Requester {
-    endpoint: Endpoint,
+    /// In order of preference, the next one is tried when a peer can't be reached on one.
+    transports: Vec<Arc<dyn Transport>>,
+    /// The transport that last reached each peer, it is tried first.
+    preferred: Mutex<HashMap<SocketAddr, TransportKind>>,
 }
 
 impl Requester {
-    pub fn new(port: u16) -> Result<Self> {
-        // Bind this endpoint to a UDP socket on the given client address.
-        let mut endpoint = Endpoint::client(Self::client_addr(port))?;
-        endpoint.set_default_client_config(Self::configure_client());
-        Ok(Self { endpoint })
+    pub fn new(transports: Vec<Arc<dyn Transport>>) -> Self {
+        Self {
+            transports,
+            preferred: Mutex::new(HashMap::new()),
+        }
     }
 
-    fn client_addr(port: u16) -> SocketAddr {
-        format!("0.0.0.0:{port}").parse::<SocketAddr>().unwrap()
+    pub async fn request(
+        &self,
+        remote_addr: SocketAddr,
+        request: Request,
+    ) -> Result<Response, RequestError> {
+        self.request_with(remote_addr, request, &RequestOptions::default())
+            .await
     }
-    fn configure_client() -> ClientConfig {
-        let crypto = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_custom_certificate_verifier(SkipServerVerification::new())
-            .with_no_client_auth();
 
-        ClientConfig::new(Arc::new(crypto))
+    /// Sends any registered kind of message, `Request`s included.
+    pub async fn request_with<M: MojikaMessage>(
+        &self,
+        remote_addr: SocketAddr,
+        request: M,
+        options: &RequestOptions,
+    ) -> Result<Response, RequestError> {
+        let idempotent = request.is_idempotent();
+        let mut attempt = 1;
+        loop {
+            let result = timeout(options.timeout, self.request_once(remote_addr, &request))
+                .await
+                .unwrap_or(Err(RequestError::Timeout(options.timeout)));
+            match result {
+                Err(e) if attempt < options.retry.max_attempts && e.is_retryable(idempotent) => {
+                    if let RequestError::Timeout(_) = e {
+                        // The peer may be gone, the next attempt shouldn't wait on the same connection.
+                        for transport in self.transports.iter() {
+                            transport.forget(remote_addr, request.lane()).await;
+                        }
+                    }
+                    let backoff = options.retry.backoff(attempt);
+                    debug!("Retrying request to {remote_addr:?} in {backoff:?} after: {e}");
+                    sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
     }
 
-    pub async fn request(&self, remote_addr: SocketAddr, request: Request) -> Result<Response> {
-        debug!("Connecting server:{remote_addr:?}");
-        // Connect to the server passing in the server name which is supposed to be in the server certificate.
-        let connecting = self.endpoint.connect(remote_addr, "localhost")?;
-        let connection = connecting.await?;
-        // Start transferring, receiving data, see data transfer page.
-        let response = Self::open_bidirectional_stream(connection, request).await?;
-        Ok(response)
+    /// Measures the round trip of a `Ping`, a lost peer fails fast instead of being retried.
+    pub async fn ping(
+        &self,
+        remote_addr: SocketAddr,
+        request: Request,
+    ) -> Result<Duration, RequestError> {
+        let (_, rtt) = self.round_trip(remote_addr, request).await?;
+        Ok(rtt)
     }
 
-    async fn open_bidirectional_stream(
-        connection: Connection,
+    /// Like [`Self::ping`], for requests whose response is needed too.
+    pub async fn round_trip(
+        &self,
+        remote_addr: SocketAddr,
         request: Request,
+    ) -> Result<(Response, Duration), RequestError> {
+        let options = RequestOptions {
+            timeout: PING_TIMEOUT,
+            retry: RetryPolicy::none(),
+        };
+        let start = Instant::now();
+        let response = self.request_with(remote_addr, request, &options).await?;
+        Ok((response, start.elapsed()))
+    }
+
+    async fn request_once<M: MojikaMessage>(
+        &self,
+        remote_addr: SocketAddr,
+        request: &M,
+    ) -> Result<Response, RequestError> {
+        let stream = self.open_stream(remote_addr, request.lane()).await?;
+        let response = Self::open_bidirectional_stream(stream, request)
+            .await
+            .map_err(RequestError::Failed)?;
+        if let ResponseBody::Err(e) = response.body {
+            return Err(RequestError::Remote(e));
+        }
+        Ok(response)
+    }
+
+    /// Opens a stream for `lane` on the first transport that reaches the peer.
+    pub async fn open_stream(
+        &self,
+        remote_addr: SocketAddr,
+        lane: Lane,
+    ) -> Result<Stream, RequestError> {
+        let preferred = self.preferred.lock().await.get(&remote_addr).copied();
+        let mut transports = self.transports.clone();
+        // Stable, so the rest keep their order of preference.
+        transports.sort_by_key(|t| Some(t.kind()) != preferred);
+
+        let mut last_error = None;
+        for transport in transports {
+            let kind = transport.kind();
+            match transport.open_stream(remote_addr, lane).await {
+                Ok(stream) => {
+                    if preferred != Some(kind) {
+                        debug!("Reached {remote_addr:?} over {kind:?}");
+                        self.preferred.lock().await.insert(remote_addr, kind);
+                    }
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    debug!("Can't reach {remote_addr:?} over {kind:?}: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| RequestError::Refused(anyhow!("no transport"))))
+    }
+
+    async fn open_bidirectional_stream<M: MojikaMessage>(
+        mut stream: Stream,
+        request: &M,
     ) -> Result<Response> {
-        let (mut send, mut recv) = connection.open_bi().await?;
-        send_request(&mut send, &request).await?;
-        let response = receive_response(&mut recv).await?;
+        send_request(&mut stream.send, request, stream.encoding).await?;
+        let response = receive_response(&mut stream.recv, stream.encoding).await?;
         debug!("Client got response: {response:?}");
         Ok(response)
     }
 }
 
-async fn send_request(send: &mut SendStream, request: &Request) -> Result<()> {
-    let mut bytes = BytesMut::with_capacity(1024).writer();
-    request.serialize(&mut Serializer::new(&mut bytes))?;
-    let bytes = bytes.into_inner();
-
-    let header = MojikaProtocolHeader {
-        type_name: "Request".to_string(),
-        len: bytes.len(),
-    };
-    let mut all = BytesMut::from(header.serialize().as_bytes());
-    all.put(bytes);
-    send.write_all(all.as_ref()).await?;
-    send.finish().await?;
+async fn send_request<M: MojikaMessage>(
+    send: &mut SendHalf,
+    request: &M,
+    encoding: FrameEncoding,
+) -> Result<()> {
+    MojikaProtocol::from_content(request)?
+        .write_to(send, encoding)
+        .await?;
+    send.shutdown().await?;
     Ok(())
 }
 
-async fn receive_response(recv: &mut RecvStream) -> Result<Response> {
-    let protocol = MojikaProtocol::from_read(recv).await?;
-    if protocol.header.type_name != "Response" {
-        error!("Invalid type_name expect 'Response'");
-    };
-    let response = protocol.content.try_into()?;
-    Ok(response)
+async fn receive_response(recv: &mut RecvHalf, encoding: FrameEncoding) -> Result<Response> {
+    MojikaProtocol::from_read(recv, encoding).await?.decode()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::request::requester::RetryPolicy;
+
+    #[test]
+    fn backoff_grows_with_jitter_and_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            multiplier: 2.0,
+        };
+        for (attempt, full) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (9, 1000)] {
+            let backoff = policy.backoff(attempt);
+            assert!(
+                backoff >= Duration::from_millis(full / 2),
+                "{attempt}:{backoff:?}"
+            );
+            assert!(
+                backoff <= Duration::from_millis(full),
+                "{attempt}:{backoff:?}"
+            );
+        }
+    }
 }
@@ -1,43 +1,47 @@
-use bytes::{Buf, Bytes};
 use serde::{Deserialize, Serialize};
 
+use crate::request::error::MojikaError;
+use crate::request::protocol::MojikaContent;
+
+/// The answer to a message, it carries no secret as the peer learns ours from our `Connect`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response {
     pub peer_id: String,
-    pub secret: String,
     pub body: ResponseBody,
 }
 
 impl Response {
-    pub fn new(peer_id: String, secret: String, body: ResponseBody) -> Self {
-        Self {
-            peer_id,
-            secret,
-            body,
-        }
+    pub fn new(peer_id: String, body: ResponseBody) -> Self {
+        Self { peer_id, body }
     }
 
-    pub fn create_ok_response(peer_id: String, secret: String) -> Self {
-        Self::new(peer_id, secret, ResponseBody::Ok)
+    pub fn create_ok_response(peer_id: String) -> Self {
+        Self::new(peer_id, ResponseBody::Ok)
     }
 }
 
-impl TryFrom<Bytes> for Response {
-    type Error = anyhow::Error;
-    fn try_from(value: Bytes) -> std::result::Result<Self, Self::Error> {
-        let mut deserializer = rmp_serde::Deserializer::new(value.reader());
-        Ok(Deserialize::deserialize(&mut deserializer)?)
-    }
+impl MojikaContent for Response {
+    const TYPE_NAME: &'static str = "Response";
+    const TYPE_TAG: u8 = 2;
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum ResponseBody {
     File(FileResponse),
+    Pong,
     Ok,
-    Err(String),
+    Err(MojikaError),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum FileResponse {
     FileCreated(String),
+    /// Id of the created folder, see [crate::request::file::entry_id] for the ids of its files.
+    FolderCreated {
+        folder_id: String,
+        /// The name it was saved as, numbered if a folder had its name.
+        name: String,
+    },
+    /// The receiver has the file up to the offset, see [crate::request::FileRequest::QueryOffset].
+    ContentOffset(u64),
 }
@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use anyhow::{bail, Result};
+
+/// How many files are sent at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferLimits {
+    /// Files sent to one peer at the same time.
+    pub per_peer: usize,
+    /// Files sent to all the peers at the same time.
+    pub global: usize,
+}
+
+impl TransferLimits {
+    pub fn new(per_peer: usize, global: usize) -> Result<Self> {
+        if per_peer == 0 || global == 0 {
+            bail!("transfer limits must be at least 1:{per_peer}, {global}")
+        }
+        Ok(Self { per_peer, global })
+    }
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        Self {
+            per_peer: 2,
+            global: 4,
+        }
+    }
+}
+
+/// Queue of the files waiting to be sent, by peer.
+///
+/// Peers take turns for free slots, so a big file to one peer doesn't hold back the files to
+/// the others. The files of one peer are started in the order they were queued.
+#[derive(Debug)]
+pub struct TransferScheduler {
+    limits: TransferLimits,
+    queues: HashMap<String, VecDeque<String>>,
+    /// Peers with queued files, the first one gets the next free slot.
+    turns: VecDeque<String>,
+    running: HashMap<String, usize>,
+    running_files: HashSet<String>,
+    /// Running files pushed again, they are queued once they end so only one sends a file.
+    pushed_again: HashSet<String>,
+}
+
+impl TransferScheduler {
+    pub fn new(limits: TransferLimits) -> Self {
+        Self {
+            limits,
+            queues: HashMap::new(),
+            turns: VecDeque::new(),
+            running: HashMap::new(),
+            running_files: HashSet::new(),
+            pushed_again: HashSet::new(),
+        }
+    }
+
+    /// Queues the file, it is ignored if it is already queued, or queued once it ends if it is
+    /// running.
+    pub fn push(&mut self, peer_id: &str, file_id: &str) {
+        if self.running_files.contains(file_id) {
+            self.pushed_again.insert(file_id.to_owned());
+            return;
+        }
+        let queue = self.queues.entry(peer_id.to_owned()).or_default();
+        if queue.iter().any(|id| id == file_id) {
+            return;
+        }
+        queue.push_back(file_id.to_owned());
+        if !self.turns.iter().any(|p| p == peer_id) {
+            self.turns.push_back(peer_id.to_owned());
+        }
+    }
+
+    /// The next file to start sending, as `(peer_id, file_id)`, if there is a free slot for it.
+    ///
+    /// The file counts as running until [TransferScheduler::finished] is called for it.
+    pub fn start_next(&mut self) -> Option<(String, String)> {
+        if self.running.values().sum::<usize>() >= self.limits.global {
+            return None;
+        }
+        let turn = self.turns.iter().position(|peer_id| {
+            self.running.get(peer_id).copied().unwrap_or_default() < self.limits.per_peer
+        })?;
+        let peer_id = self.turns.remove(turn)?;
+        let queue = self.queues.get_mut(&peer_id)?;
+        let file_id = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&peer_id);
+        } else {
+            // Waits for the other peers before its next file.
+            self.turns.push_back(peer_id.to_owned());
+        }
+        *self.running.entry(peer_id.to_owned()).or_default() += 1;
+        self.running_files.insert(file_id.to_owned());
+        Some((peer_id, file_id))
+    }
+
+    /// Frees the slot of a file sent to `peer_id`, whether it was sent or not.
+    pub fn finished(&mut self, peer_id: &str, file_id: &str) {
+        if let Some(running) = self.running.get_mut(peer_id) {
+            *running -= 1;
+            if *running == 0 {
+                self.running.remove(peer_id);
+            }
+        }
+        self.running_files.remove(file_id);
+        if self.pushed_again.remove(file_id) {
+            self.push(peer_id, file_id);
+        }
+    }
+
+    /// How many files are queued, not counting the running ones.
+    pub fn queued(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::request::scheduler::{TransferLimits, TransferScheduler};
+
+    fn start_all(scheduler: &mut TransferScheduler) -> Vec<String> {
+        std::iter::from_fn(|| scheduler.start_next())
+            .map(|(_, file_id)| file_id)
+            .collect()
+    }
+
+    #[test]
+    fn peers_take_turns_within_the_limits() {
+        let mut scheduler = TransferScheduler::new(TransferLimits::new(2, 3).unwrap());
+        for file_id in ["a1", "a2", "a3"] {
+            scheduler.push("a", file_id);
+        }
+        scheduler.push("b", "b1");
+        scheduler.push("b", "b2");
+        scheduler.push("a", "a1");
+
+        // "a" queued first but "b" doesn't wait for all of its files.
+        assert_eq!(start_all(&mut scheduler), ["a1", "b1", "a2"]);
+        assert_eq!(scheduler.queued(), 2);
+
+        // The free slot goes to the peer under its own limit.
+        scheduler.finished("b", "b1");
+        assert_eq!(start_all(&mut scheduler), ["b2"]);
+        scheduler.finished("a", "a1");
+        assert_eq!(start_all(&mut scheduler), ["a3"]);
+        assert_eq!(scheduler.queued(), 0);
+        assert!(TransferLimits::new(0, 1).is_err());
+    }
+
+    #[test]
+    fn running_file_is_started_again_only_once_it_ends() {
+        let mut scheduler = TransferScheduler::new(TransferLimits::default());
+        scheduler.push("a", "a1");
+        assert_eq!(start_all(&mut scheduler), ["a1"]);
+        // Resumed before the running one saw it was paused.
+        scheduler.push("a", "a1");
+        assert!(start_all(&mut scheduler).is_empty());
+        scheduler.finished("a", "a1");
+        assert_eq!(start_all(&mut scheduler), ["a1"]);
+        scheduler.finished("a", "a1");
+        assert!(start_all(&mut scheduler).is_empty());
+    }
+}
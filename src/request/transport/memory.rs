@@ -0,0 +1,371 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::debug;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    io::{copy_bidirectional, duplex, split, DuplexStream},
+    spawn,
+    sync::{broadcast, mpsc, watch},
+    time::sleep,
+};
+
+use crate::request::{
+    error::RequestError,
+    protocol::FrameEncoding,
+    transport::{Incoming, Stream, Transport, TransportKind},
+    Lane,
+};
+
+const STREAM_BUFFER_LEN: usize = 256 * 1024;
+const ANNOUNCEMENT_QUEUE_LEN: usize = 100;
+/// Every node listens on the same port of its own address.
+const NODE_PORT: u16 = 7000;
+
+/// Conditions of every link in a [`MemoryNetwork`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConditions {
+    /// The chance, from `0` to `1`, of a stream breaking before it reaches the peer or of an
+    /// announcement being lost.
+    pub loss: f64,
+    /// How long a new stream takes to reach the peer.
+    pub delay: Duration,
+}
+
+/// Peers in one process connected with channels instead of sockets, for tests.
+///
+/// Losses are drawn from a seeded generator, on a current-thread runtime with a paused clock
+/// a run only depends on the order of the streams. Apps run on runtimes of their own, tests of
+/// them are only as repeatable as the scheduling of those.
+#[derive(Debug, Clone)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+#[derive(Debug)]
+struct NetworkState {
+    nodes: HashMap<SocketAddr, Node>,
+    conditions: LinkConditions,
+    rng: StdRng,
+    announcements: broadcast::Sender<(Bytes, SocketAddr)>,
+}
+
+#[derive(Debug)]
+struct Node {
+    /// Where the streams opened to the node go while it is serving.
+    incoming: Option<mpsc::Sender<Incoming>>,
+    online: watch::Sender<bool>,
+}
+
+impl MemoryNetwork {
+    pub fn new(seed: u64) -> Self {
+        let (announcements, _) = broadcast::channel(ANNOUNCEMENT_QUEUE_LEN);
+        let state = NetworkState {
+            nodes: HashMap::new(),
+            conditions: LinkConditions::default(),
+            rng: StdRng::seed_from_u64(seed),
+            announcements,
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Adds a node with an address of its own.
+    pub fn add_node(&self) -> SocketAddr {
+        let mut state = self.state.lock().unwrap();
+        let host = u32::from(Ipv4Addr::new(10, 0, 0, 1)) + state.nodes.len() as u32;
+        let addr = SocketAddr::new(Ipv4Addr::from(host).into(), NODE_PORT);
+        let node = Node {
+            incoming: None,
+            online: watch::channel(true).0,
+        };
+        state.nodes.insert(addr, node);
+        addr
+    }
+
+    pub fn transport(&self, addr: SocketAddr) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            addr,
+        }
+    }
+
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.state.lock().unwrap().conditions = conditions;
+    }
+
+    /// Cuts the node off, its open streams break and nothing reaches it until [`Self::reconnect`].
+    pub fn disconnect(&self, addr: SocketAddr) {
+        self.set_online(addr, false);
+    }
+
+    pub fn reconnect(&self, addr: SocketAddr) {
+        self.set_online(addr, true);
+    }
+
+    fn set_online(&self, addr: SocketAddr, online: bool) {
+        if let Some(node) = self.state.lock().unwrap().nodes.get(&addr) {
+            node.online.send_replace(online);
+        }
+    }
+
+    pub(crate) fn is_online(&self, addr: SocketAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        state.nodes.get(&addr).is_some_and(|n| *n.online.borrow())
+    }
+
+    /// Draws whether the next stream or announcement is lost.
+    pub(crate) fn is_lost(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let loss = state.conditions.loss.clamp(0.0, 1.0);
+        state.rng.gen_bool(loss)
+    }
+
+    pub(crate) fn announce(&self, message: Bytes, from: SocketAddr) {
+        let _ = self
+            .state
+            .lock()
+            .unwrap()
+            .announcements
+            .send((message, from));
+    }
+
+    pub(crate) fn subscribe_announcements(&self) -> broadcast::Receiver<(Bytes, SocketAddr)> {
+        self.state.lock().unwrap().announcements.subscribe()
+    }
+
+    fn delay(&self) -> Duration {
+        self.state.lock().unwrap().conditions.delay
+    }
+
+    fn listen(&self, addr: SocketAddr, incoming: Option<mpsc::Sender<Incoming>>) {
+        if let Some(node) = self.state.lock().unwrap().nodes.get_mut(&addr) {
+            node.incoming = incoming;
+        }
+    }
+
+    fn incoming(&self, addr: SocketAddr) -> Option<mpsc::Sender<Incoming>> {
+        let state = self.state.lock().unwrap();
+        state.nodes.get(&addr).and_then(|n| n.incoming.clone())
+    }
+
+    fn watch_online(&self, addr: SocketAddr) -> Option<watch::Receiver<bool>> {
+        let state = self.state.lock().unwrap();
+        state.nodes.get(&addr).map(|n| n.online.subscribe())
+    }
+}
+
+/// A node of a [`MemoryNetwork`].
+#[derive(Debug)]
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Memory
+    }
+
+    async fn open_stream(
+        &self,
+        remote_addr: SocketAddr,
+        _lane: Lane,
+    ) -> Result<Stream, RequestError> {
+        let unreachable = || RequestError::Refused(anyhow!("{remote_addr:?} is unreachable"));
+        if !self.network.is_online(self.addr) || !self.network.is_online(remote_addr) {
+            return Err(unreachable());
+        }
+        let (Some(incoming), Some(local_online), Some(remote_online)) = (
+            self.network.incoming(remote_addr),
+            self.network.watch_online(self.addr),
+            self.network.watch_online(remote_addr),
+        ) else {
+            return Err(unreachable());
+        };
+        sleep(self.network.delay()).await;
+
+        let (local, local_relay) = duplex(STREAM_BUFFER_LEN);
+        if self.network.is_lost() {
+            debug!("Lost a stream to {remote_addr:?}");
+            return Ok(stream_of(local));
+        }
+        let (remote_relay, remote) = duplex(STREAM_BUFFER_LEN);
+        spawn(relay(
+            local_relay,
+            remote_relay,
+            local_online,
+            remote_online,
+        ));
+        let stream = stream_of(remote);
+        let incoming_stream = Incoming {
+            stream,
+            remote_addr: self.addr,
+        };
+        if incoming.send(incoming_stream).await.is_err() {
+            return Err(unreachable());
+        }
+        Ok(stream_of(local))
+    }
+
+    async fn forget(&self, _remote_addr: SocketAddr, _lane: Lane) {}
+
+    async fn serve(
+        self: Arc<Self>,
+        incoming: mpsc::Sender<Incoming>,
+        mut shutdown: broadcast::Receiver<()>,
+    ) -> Result<()> {
+        self.network.listen(self.addr, Some(incoming));
+        let _ = shutdown.recv().await;
+        self.network.listen(self.addr, None);
+        Ok(())
+    }
+}
+
+fn stream_of(duplex: DuplexStream) -> Stream {
+    let (recv, send) = split(duplex);
+    Stream::new(Box::new(send), Box::new(recv), FrameEncoding::Binary)
+}
+
+/// Passes the data of a stream until it ends or one of its ends goes offline.
+async fn relay(
+    mut local: DuplexStream,
+    mut remote: DuplexStream,
+    mut local_online: watch::Receiver<bool>,
+    mut remote_online: watch::Receiver<bool>,
+) {
+    tokio::select! {
+        _ = copy_bidirectional(&mut local, &mut remote) => {}
+        _ = went_offline(&mut local_online) => {}
+        _ = went_offline(&mut remote_online) => {}
+    }
+}
+
+async fn went_offline(online: &mut watch::Receiver<bool>) {
+    while *online.borrow_and_update() {
+        if online.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        spawn,
+        sync::{broadcast, mpsc},
+        time::Instant,
+    };
+
+    use crate::request::{
+        protocol::MojikaProtocol,
+        requester::{RequestOptions, Requester, RetryPolicy},
+        response::{Response, ResponseBody},
+        transport::{
+            memory::{LinkConditions, MemoryNetwork},
+            Incoming, Transport,
+        },
+        Lane, Request, RequestBody,
+    };
+
+    /// Answers every request of `incoming` with a `Pong`.
+    async fn pong(mut incoming: mpsc::Receiver<Incoming>) {
+        while let Some(Incoming { mut stream, .. }) = incoming.recv().await {
+            spawn(async move {
+                let mut request = vec![];
+                stream.recv.read_to_end(&mut request).await?;
+                let response = Response::new("b".into(), ResponseBody::Pong);
+                MojikaProtocol::from_content(&response)?
+                    .write_to(&mut stream.send, stream.encoding)
+                    .await?;
+                stream.send.shutdown().await?;
+                anyhow::Ok(())
+            });
+        }
+    }
+
+    fn ping() -> Request {
+        Request::new("a".into(), "s".into(), RequestBody::Ping)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_follow_the_link_conditions() {
+        let network = MemoryNetwork::new(7);
+        let (a, b) = (network.add_node(), network.add_node());
+        let (_shutdown_s, shutdown) = broadcast::channel(1);
+        let (incoming_s, incoming) = mpsc::channel(10);
+        spawn(Arc::new(network.transport(b)).serve(incoming_s, shutdown));
+        spawn(pong(incoming));
+        tokio::task::yield_now().await;
+        let requester = Requester::new(vec![Arc::new(network.transport(a))]);
+
+        let response = requester.request(b, ping()).await.unwrap();
+        assert_eq!(response.body, ResponseBody::Pong);
+
+        let delay = Duration::from_millis(50);
+        network.set_conditions(LinkConditions { loss: 0.0, delay });
+        let start = Instant::now();
+        requester.request(b, ping()).await.unwrap();
+        assert!(start.elapsed() >= delay);
+
+        network.set_conditions(LinkConditions {
+            loss: 0.5,
+            delay: Duration::ZERO,
+        });
+        let options = RequestOptions {
+            timeout: Duration::from_secs(1),
+            retry: RetryPolicy {
+                max_attempts: 30,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                multiplier: 1.0,
+            },
+        };
+        for _ in 0..10 {
+            requester.request_with(b, ping(), &options).await.unwrap();
+        }
+        network.set_conditions(LinkConditions {
+            loss: 1.0,
+            delay: Duration::ZERO,
+        });
+        assert!(requester.request_with(b, ping(), &options).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnect_breaks_open_streams() {
+        let network = MemoryNetwork::new(7);
+        let (a, b) = (network.add_node(), network.add_node());
+        let (_shutdown_s, shutdown) = broadcast::channel(1);
+        let (incoming_s, mut incoming) = mpsc::channel(10);
+        spawn(Arc::new(network.transport(b)).serve(incoming_s, shutdown));
+        tokio::task::yield_now().await;
+        let transport = network.transport(a);
+
+        let mut stream = transport.open_stream(b, Lane::Control).await.unwrap();
+        let mut accepted = incoming.recv().await.unwrap().stream;
+        stream.send.write_all(b"hi").await.unwrap();
+        let mut received = [0u8; 2];
+        accepted.recv.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hi");
+
+        network.disconnect(b);
+        let mut rest = vec![];
+        assert_eq!(stream.recv.read_to_end(&mut rest).await.unwrap(), 0);
+        assert!(transport.open_stream(b, Lane::Control).await.is_err());
+
+        network.reconnect(b);
+        assert!(transport.open_stream(b, Lane::Control).await.is_ok());
+    }
+}
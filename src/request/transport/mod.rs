@@ -0,0 +1,72 @@
+use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{broadcast, mpsc},
+};
+
+use crate::request::{error::RequestError, protocol::FrameEncoding, Lane};
+
+pub mod memory;
+pub mod quic;
+pub mod tcp;
+
+pub type SendHalf = Box<dyn AsyncWrite + Send + Unpin>;
+pub type RecvHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A bidirectional stream with a peer, whatever transport carries it.
+///
+/// Shutting down `send` ends the stream for the peer.
+pub struct Stream {
+    pub send: SendHalf,
+    pub recv: RecvHalf,
+    pub encoding: FrameEncoding,
+}
+
+impl Stream {
+    pub fn new(send: SendHalf, recv: RecvHalf, encoding: FrameEncoding) -> Self {
+        Self {
+            send,
+            recv,
+            encoding,
+        }
+    }
+}
+
+/// A stream a peer opened to us.
+pub struct Incoming {
+    pub stream: Stream,
+    pub remote_addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    Quic,
+    Tcp,
+    Memory,
+}
+
+/// Carries streams of `MojikaProtocol` frames between peers.
+#[async_trait]
+pub trait Transport: Debug + Send + Sync {
+    fn kind(&self) -> TransportKind;
+
+    /// Opens a stream to the peer listening on `remote_addr`.
+    async fn open_stream(
+        &self,
+        remote_addr: SocketAddr,
+        lane: Lane,
+    ) -> Result<Stream, RequestError>;
+
+    /// Drops any connection kept for `lane`, the next stream connects again.
+    async fn forget(&self, remote_addr: SocketAddr, lane: Lane);
+
+    /// Passes the streams peers open to `incoming` until `shutdown`.
+    async fn serve(
+        self: Arc<Self>,
+        incoming: mpsc::Sender<Incoming>,
+        shutdown: broadcast::Receiver<()>,
+    ) -> Result<()>;
+}
@@ -0,0 +1,219 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use log::{debug, warn};
+use quinn::{Connecting, Connection, ConnectionError, Endpoint};
+use tokio::{
+    spawn,
+    sync::{broadcast, mpsc, Mutex},
+    time::timeout,
+};
+
+use crate::request::{
+    error::RequestError,
+    protocol::FrameEncoding,
+    transport::{Incoming, Stream, Transport, TransportKind},
+    Lane,
+};
+
+/// A peer that doesn't answer the handshake by then may only be reachable on another transport.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Lets one request at a time connect to a peer on a lane.
+type DialLock = Arc<Mutex<()>>;
+
+/// Streams over QUIC, both accepted and initiated on the same endpoint.
+#[derive(Debug)]
+pub struct QuicTransport {
+    endpoint: Endpoint,
+    /// Live connections to peers, every stream is opened on one of them.
+    /// Each lane has its own connection, so chats don't queue behind file chunks.
+    connections: Mutex<HashMap<(SocketAddr, Lane), Connection>>,
+    /// Held while dialing, so concurrent requests share one connection instead of each pooling their own.
+    dials: Mutex<HashMap<(SocketAddr, Lane), DialLock>>,
+}
+
+impl QuicTransport {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            connections: Mutex::new(HashMap::new()),
+            dials: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the pooled connection to `remote_addr`, connecting if there is no live one.
+    async fn connection(&self, remote_addr: SocketAddr, lane: Lane) -> Result<Connection> {
+        if let Some(connection) = self.pooled(remote_addr, lane).await {
+            return Ok(connection);
+        }
+        let dial = self
+            .dials
+            .lock()
+            .await
+            .entry((remote_addr, lane))
+            .or_default()
+            .clone();
+        let _dial = dial.lock().await;
+        // Another request may have connected while this one waited.
+        if let Some(connection) = self.pooled(remote_addr, lane).await {
+            return Ok(connection);
+        }
+        debug!("Connecting server:{remote_addr:?} for {lane:?}");
+        // Connect to the server passing in the server name which is supposed to be in the server certificate.
+        let connecting = self.endpoint.connect(remote_addr, "localhost")?;
+        let Ok(connection) = timeout(CONNECT_TIMEOUT, connecting).await else {
+            bail!("QUIC handshake with {remote_addr:?} timed out")
+        };
+        let connection = connection?;
+        self.connections
+            .lock()
+            .await
+            .insert((remote_addr, lane), connection.clone());
+        Ok(connection)
+    }
+
+    async fn pooled(&self, remote_addr: SocketAddr, lane: Lane) -> Option<Connection> {
+        let connections = self.connections.lock().await;
+        let connection = connections.get(&(remote_addr, lane))?;
+        connection
+            .close_reason()
+            .is_none()
+            .then(|| connection.clone())
+    }
+
+    /// Drops the pooled connection to `remote_addr`, if it is still `connection`.
+    async fn forget_connection(
+        &self,
+        remote_addr: SocketAddr,
+        lane: Lane,
+        connection: Option<&Connection>,
+    ) {
+        let mut connections = self.connections.lock().await;
+        if let Some(pooled) = connections.get(&(remote_addr, lane)) {
+            if connection.is_none_or(|c| c.stable_id() == pooled.stable_id()) {
+                connections.remove(&(remote_addr, lane));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for QuicTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Quic
+    }
+
+    /// Opens a stream on the pooled connection of `lane`, reconnecting if it was closed.
+    async fn open_stream(
+        &self,
+        remote_addr: SocketAddr,
+        lane: Lane,
+    ) -> Result<Stream, RequestError> {
+        let mut connection = self
+            .connection(remote_addr, lane)
+            .await
+            .map_err(RequestError::Refused)?;
+        let (send, recv) = match connection.open_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                // The pooled connection was closed or timed out, nothing was sent on it yet.
+                debug!("Reconnecting to {remote_addr:?} after: {e:?}");
+                self.forget_connection(remote_addr, lane, Some(&connection))
+                    .await;
+                connection = self
+                    .connection(remote_addr, lane)
+                    .await
+                    .map_err(RequestError::Refused)?;
+                connection
+                    .open_bi()
+                    .await
+                    .map_err(|e| RequestError::Refused(e.into()))?
+            }
+        };
+        let _ = send.set_priority(lane.priority());
+        let encoding = frame_encoding(&connection);
+        Ok(Stream::new(Box::new(send), Box::new(recv), encoding))
+    }
+
+    async fn forget(&self, remote_addr: SocketAddr, lane: Lane) {
+        self.forget_connection(remote_addr, lane, None).await;
+    }
+
+    async fn serve(
+        self: Arc<Self>,
+        incoming: mpsc::Sender<Incoming>,
+        mut shutdown: broadcast::Receiver<()>,
+    ) -> Result<()> {
+        debug!("Start QUIC server on:{:?}", self.endpoint.local_addr());
+        loop {
+            tokio::select! {
+                Some(connecting) = self.endpoint.accept() => {
+                    // Handshakes and requesters keeping their connection open must not block the accept loop.
+                    spawn(handle_connection(connecting, incoming.clone()));
+                }
+                res = shutdown.recv() => {
+                    debug!("Got {res:?} for shutdown the server");
+                    break
+                }
+                else => {
+                    warn!("Both channels closed");
+                    break
+                }
+            }
+        }
+        self.endpoint.close(0u32.into(), b"shutdown");
+        Ok(())
+    }
+}
+
+async fn handle_connection(connecting: Connecting, incoming: mpsc::Sender<Incoming>) {
+    let connection = match connecting.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("QUIC handshake failed: {e:?}");
+            return;
+        }
+    };
+    let remote_addr = connection.remote_address();
+    debug!("Got new QUIC connection {remote_addr:?}");
+    let encoding = frame_encoding(&connection);
+    debug!("Frame encoding of the connection:{encoding:?}");
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(
+                ConnectionError::ApplicationClosed(_)
+                | ConnectionError::LocallyClosed
+                | ConnectionError::TimedOut,
+            ) => break,
+            Err(e) => {
+                warn!("Error accepting a stream from {remote_addr:?}: {e:?}");
+                break;
+            }
+        };
+        let stream = Stream::new(Box::new(send), Box::new(recv), encoding);
+        if incoming
+            .send(Incoming {
+                stream,
+                remote_addr,
+            })
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    debug!("QUIC connection {remote_addr:?} closed");
+}
+
+/// The frame encoding agreed on with ALPN during the connection handshake.
+fn frame_encoding(connection: &Connection) -> FrameEncoding {
+    connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.protocol)
+        .and_then(|protocol| FrameEncoding::from_alpn(&protocol))
+        .unwrap_or_default()
+}
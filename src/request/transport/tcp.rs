@@ -0,0 +1,552 @@
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use log::{debug, warn};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::{broadcast, mpsc, oneshot},
+    time::timeout,
+};
+use tokio_rustls::{rustls::ServerName, TlsAcceptor, TlsConnector, TlsStream};
+
+use crate::request::{
+    endpoint::{client_crypto, endpoint_addr, server_crypto},
+    error::RequestError,
+    protocol::FrameEncoding,
+    transport::{Incoming, Stream, Transport, TransportKind},
+    Lane,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Idle connections kept to a peer, more are closed once their stream ends.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+/// Bytes of a stream sent in one chunk at most.
+const CHUNK_MAX_LEN: usize = 64 * 1024;
+
+type Connection = TlsStream<TcpStream>;
+
+/// Streams over TCP+TLS, for networks that block or limit UDP.
+///
+/// TCP has no streams of its own, so a stream takes a whole connection. Its content is sent in
+/// chunks and an empty one ends it, then the connection is kept for the next stream to the
+/// peer. Lanes are only kept apart by their streams being on different connections.
+pub struct TcpTransport {
+    listener: TcpListener,
+    connector: TlsConnector,
+    acceptor: TlsAcceptor,
+    /// Connections whose stream ended, by peer.
+    idle: Arc<Mutex<HashMap<SocketAddr, Vec<Connection>>>>,
+}
+
+impl TcpTransport {
+    /// Listens on the TCP `port`, the same number as the QUIC endpoint's UDP port.
+    pub async fn bind(port: u16) -> Result<Self> {
+        let listener = TcpListener::bind(endpoint_addr(port)).await?;
+        Ok(Self {
+            listener,
+            connector: Arc::new(client_crypto()).into(),
+            acceptor: Arc::new(server_crypto()?).into(),
+            idle: Arc::default(),
+        })
+    }
+
+    async fn connect(&self, remote_addr: SocketAddr) -> Result<Connection> {
+        let tcp = timeout(CONNECT_TIMEOUT, TcpStream::connect(remote_addr))
+            .await
+            .context("TCP connect timed out")??;
+        tcp.set_nodelay(true)?;
+        let server_name = ServerName::try_from("localhost")?;
+        Ok(self.connector.connect(server_name, tcp).await?.into())
+    }
+
+    /// Takes an idle connection to `remote_addr` the peer hasn't closed.
+    async fn take_idle(&self, remote_addr: SocketAddr) -> Option<Connection> {
+        loop {
+            let mut connection = self
+                .idle
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_mut(&remote_addr)?
+                .pop()?;
+            // The peer sends nothing on an idle connection, anything it reads means it's closed.
+            let mut byte = [0u8; 1];
+            let mut read = ReadBuf::new(&mut byte);
+            let polled =
+                poll_fn(|cx| Poll::Ready(Pin::new(&mut connection).poll_read(cx, &mut read))).await;
+            if polled.is_pending() {
+                return Some(connection);
+            }
+            debug!("Dropped a closed TCP connection to {remote_addr:?}");
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Tcp
+    }
+
+    /// Opens a stream on an idle connection to the peer, or on a new one.
+    async fn open_stream(
+        &self,
+        remote_addr: SocketAddr,
+        _lane: Lane,
+    ) -> Result<Stream, RequestError> {
+        let connection = match self.take_idle(remote_addr).await {
+            Some(connection) => connection,
+            None => {
+                debug!("Connecting TCP server:{remote_addr:?}");
+                self.connect(remote_addr)
+                    .await
+                    .map_err(RequestError::Refused)?
+            }
+        };
+        let idle = self.idle.clone();
+        Ok(chunked_stream(connection, None, move |connection| {
+            let mut idle = idle.lock().unwrap_or_else(PoisonError::into_inner);
+            let idle = idle.entry(remote_addr).or_default();
+            if idle.len() < MAX_IDLE_CONNECTIONS {
+                idle.push(connection);
+            }
+        }))
+    }
+
+    async fn forget(&self, remote_addr: SocketAddr, _lane: Lane) {
+        self.idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&remote_addr);
+    }
+
+    async fn serve(
+        self: Arc<Self>,
+        incoming: mpsc::Sender<Incoming>,
+        mut shutdown: broadcast::Receiver<()>,
+    ) -> Result<()> {
+        debug!("Start TCP server on:{:?}", self.listener.local_addr());
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((tcp, remote_addr)) => {
+                        spawn(accept_streams(self.acceptor.clone(), tcp, remote_addr, incoming.clone()));
+                    }
+                    Err(e) => warn!("Error accepting a TCP connection: {e:?}"),
+                },
+                res = shutdown.recv() => {
+                    debug!("Got {res:?} for shutdown the TCP server");
+                    break
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for TcpTransport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TcpTransport")
+            .field("listener", &self.listener)
+            .finish()
+    }
+}
+
+/// Passes the streams a peer opens on the connection to `incoming`, one after the other.
+async fn accept_streams(
+    acceptor: TlsAcceptor,
+    tcp: TcpStream,
+    remote_addr: SocketAddr,
+    incoming: mpsc::Sender<Incoming>,
+) {
+    let _ = tcp.set_nodelay(true);
+    let mut connection: Connection = match acceptor.accept(tcp).await {
+        Ok(tls) => tls.into(),
+        Err(e) => {
+            warn!("TLS handshake with {remote_addr:?} failed: {e:?}");
+            return;
+        }
+    };
+    loop {
+        // A stream starts with its first chunk, the peer may close the connection before.
+        let first_len = match connection.read_u32().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+            Err(e) => {
+                debug!("Error reading a TCP connection from {remote_addr:?}: {e:?}");
+                return;
+            }
+        };
+        let (ended, reused) = oneshot::channel();
+        let stream = chunked_stream(connection, Some(first_len), move |connection| {
+            let _ = ended.send(connection);
+        });
+        let accepted = Incoming {
+            stream,
+            remote_addr,
+        };
+        if incoming.send(accepted).await.is_err() {
+            return;
+        }
+        connection = match reused.await {
+            Ok(connection) => connection,
+            // The stream didn't end cleanly, the connection is closed with it.
+            Err(_) => return,
+        };
+    }
+}
+
+fn alpn_encoding(protocol: Option<&[u8]>) -> FrameEncoding {
+    protocol
+        .and_then(FrameEncoding::from_alpn)
+        .unwrap_or_default()
+}
+
+/// A stream on the whole `connection`, handed to `reuse` once both of its halves ended.
+///
+/// `first_len` is the len of the first chunk, if it was already read.
+fn chunked_stream(
+    connection: Connection,
+    first_len: Option<u32>,
+    reuse: impl FnOnce(Connection) + Send + 'static,
+) -> Stream {
+    let encoding = alpn_encoding(connection.get_ref().1.alpn_protocol());
+    let (read, write) = split(connection);
+    let ends = Arc::new(Mutex::new(Ends {
+        read: None,
+        write: None,
+        reuse: Some(Box::new(reuse)),
+    }));
+    let recv = ChunkedRecv {
+        read: Some(read),
+        state: match first_len {
+            None => RecvState::header(),
+            Some(len) => RecvState::after_header(len),
+        },
+        ends: ends.clone(),
+    };
+    let send = ChunkedSend {
+        write: Some(write),
+        pending: Vec::new(),
+        written: 0,
+        state: SendState::Open,
+        ends,
+    };
+    Stream::new(Box::new(send), Box::new(recv), encoding)
+}
+
+/// The halves of a connection whose stream ended on their side.
+struct Ends {
+    read: Option<ReadHalf<Connection>>,
+    write: Option<WriteHalf<Connection>>,
+    reuse: Option<Box<dyn FnOnce(Connection) + Send>>,
+}
+
+impl Ends {
+    /// Keeps a half that ended, the connection is reused once both did.
+    fn ended(
+        ends: &Mutex<Ends>,
+        read: Option<ReadHalf<Connection>>,
+        write: Option<WriteHalf<Connection>>,
+    ) {
+        let mut ends = ends.lock().unwrap_or_else(PoisonError::into_inner);
+        ends.read = ends.read.take().or(read);
+        ends.write = ends.write.take().or(write);
+        if ends.read.is_some() && ends.write.is_some() {
+            let read = ends.read.take().unwrap();
+            let connection = read.unsplit(ends.write.take().unwrap());
+            if let Some(reuse) = ends.reuse.take() {
+                reuse(connection);
+            }
+        }
+    }
+}
+
+enum RecvState {
+    Header { header: [u8; 4], filled: usize },
+    Content { left: usize },
+    Ended,
+}
+
+impl RecvState {
+    fn header() -> Self {
+        RecvState::Header {
+            header: [0; 4],
+            filled: 0,
+        }
+    }
+
+    fn after_header(len: u32) -> Self {
+        match len {
+            0 => RecvState::Ended,
+            len => RecvState::Content { left: len as usize },
+        }
+    }
+}
+
+/// Reads the chunks of a stream, it ends with the empty one.
+struct ChunkedRecv {
+    read: Option<ReadHalf<Connection>>,
+    state: RecvState,
+    ends: Arc<Mutex<Ends>>,
+}
+
+impl AsyncRead for ChunkedRecv {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(read) = this.read.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        loop {
+            match &mut this.state {
+                RecvState::Ended => return Poll::Ready(Ok(())),
+                RecvState::Header { header, filled } => {
+                    let mut rest = ReadBuf::new(&mut header[*filled..]);
+                    ready!(Pin::new(&mut *read).poll_read(cx, &mut rest))?;
+                    let n = rest.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    *filled += n;
+                    if *filled == header.len() {
+                        this.state = RecvState::after_header(u32::from_be_bytes(*header));
+                    }
+                }
+                RecvState::Content { left } => {
+                    let len = (*left).min(buf.remaining());
+                    if len == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+                    let mut content = ReadBuf::new(buf.initialize_unfilled_to(len));
+                    ready!(Pin::new(&mut *read).poll_read(cx, &mut content))?;
+                    let n = content.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    buf.advance(n);
+                    *left -= n;
+                    if *left == 0 {
+                        this.state = RecvState::header();
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ChunkedRecv {
+    fn drop(&mut self) {
+        if let RecvState::Ended = self.state {
+            Ends::ended(&self.ends, self.read.take(), None);
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum SendState {
+    Open,
+    Ending,
+    Ended,
+}
+
+/// Writes a stream in chunks, shutting it down sends the empty one and keeps the connection.
+struct ChunkedSend {
+    write: Option<WriteHalf<Connection>>,
+    /// Chunks not written yet, from `written` on.
+    pending: Vec<u8>,
+    written: usize,
+    state: SendState,
+    ends: Arc<Mutex<Ends>>,
+}
+
+impl ChunkedSend {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(write) = self.write.as_mut() else {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        };
+        while self.written < self.pending.len() {
+            let n = ready!(Pin::new(&mut *write).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn push_chunk(&mut self, content: &[u8]) {
+        self.pending
+            .extend_from_slice(&(content.len() as u32).to_be_bytes());
+        self.pending.extend_from_slice(content);
+    }
+}
+
+impl AsyncWrite for ChunkedSend {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.state != SendState::Open {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        ready!(this.poll_pending(cx))?;
+        let len = buf.len().min(CHUNK_MAX_LEN);
+        if len == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        this.push_chunk(&buf[..len]);
+        // Taken as written, the rest of the chunk goes with the next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        match this.write.as_mut() {
+            Some(write) => Pin::new(write).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.state == SendState::Open {
+            this.push_chunk(&[]);
+            this.state = SendState::Ending;
+        }
+        if this.state == SendState::Ending {
+            ready!(Pin::new(&mut *this).poll_flush(cx))?;
+            this.state = SendState::Ended;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for ChunkedSend {
+    fn drop(&mut self) {
+        if self.state == SendState::Ended {
+            Ends::ended(&self.ends, None, self.write.take());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        spawn,
+        sync::{broadcast, mpsc},
+    };
+
+    use crate::request::{
+        protocol::FrameEncoding,
+        transport::{tcp::TcpTransport, Incoming, Transport},
+        Lane,
+    };
+
+    #[tokio::test]
+    async fn streams_round_trip_over_tls() {
+        let transport = Arc::new(TcpTransport::bind(0).await.unwrap());
+        let port = transport.listener.local_addr().unwrap().port();
+        let (incoming_s, mut incoming) = mpsc::channel(1);
+        let (shutdown_s, shutdown) = broadcast::channel(1);
+        let serve = spawn(transport.clone().serve(incoming_s, shutdown));
+
+        let remote_addr = format!("127.0.0.1:{port}").parse().unwrap();
+        let mut stream = transport
+            .open_stream(remote_addr, Lane::Bulk)
+            .await
+            .unwrap();
+        assert_eq!(stream.encoding, FrameEncoding::Binary);
+        stream.send.write_all(b"ping").await.unwrap();
+        stream.send.shutdown().await.unwrap();
+
+        let mut accepted = incoming.recv().await.unwrap().stream;
+        let mut received = vec![];
+        accepted.recv.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ping");
+        accepted.send.write_all(b"pong").await.unwrap();
+        accepted.send.shutdown().await.unwrap();
+
+        let mut received = vec![];
+        stream.recv.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"pong");
+
+        shutdown_s.send(()).unwrap();
+        serve.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused_once_their_stream_ends() {
+        let transport = Arc::new(TcpTransport::bind(0).await.unwrap());
+        let port = transport.listener.local_addr().unwrap().port();
+        let (incoming_s, mut incoming) = mpsc::channel(1);
+        let (shutdown_s, shutdown) = broadcast::channel(1);
+        let serve = spawn(transport.clone().serve(incoming_s, shutdown));
+
+        let remote_addr = format!("127.0.0.1:{port}").parse().unwrap();
+        let mut client_addrs = vec![];
+        for message in [&b"first"[..], b"second"] {
+            let mut stream = transport
+                .open_stream(remote_addr, Lane::Control)
+                .await
+                .unwrap();
+            stream.send.write_all(message).await.unwrap();
+            stream.send.shutdown().await.unwrap();
+
+            let Incoming {
+                stream: mut accepted,
+                remote_addr: client_addr,
+            } = incoming.recv().await.unwrap();
+            client_addrs.push(client_addr);
+            let mut received = vec![];
+            accepted.recv.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, message);
+            accepted.send.shutdown().await.unwrap();
+
+            let mut received = vec![];
+            stream.recv.read_to_end(&mut received).await.unwrap();
+            assert!(received.is_empty());
+        }
+        // Both streams went over the same connection.
+        assert_eq!(client_addrs[0], client_addrs[1]);
+        assert_eq!(transport.idle.lock().unwrap()[&remote_addr].len(), 1);
+
+        // One that doesn't end cleanly closes its connection.
+        let stream = transport
+            .open_stream(remote_addr, Lane::Control)
+            .await
+            .unwrap();
+        drop(stream);
+        assert!(transport.idle.lock().unwrap()[&remote_addr].is_empty());
+
+        shutdown_s.send(()).unwrap();
+        serve.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,572 @@
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::{
+    fs,
+    future::Future,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use mojika::{
+    app::{
+        config::{AppConfig, Network},
+        App,
+    },
+    chat::Content,
+    request::{
+        file::{OutgoingState, TransferAction},
+        progress::{Direction, TransferState},
+        transport::memory::{LinkConditions, MemoryNetwork},
+    },
+};
+use tokio::{runtime::Runtime, time::sleep};
+use uuid::Uuid;
+
+/// The apps keep time on runtimes of their own, so these tests wait on the real clock.
+const WAIT_LIMIT: Duration = Duration::from_secs(20);
+
+/// Peers on one in-memory network, each with a download dir of its own.
+struct Peers {
+    network: MemoryNetwork,
+    apps: Vec<Arc<App>>,
+    dir: PathBuf,
+    runtime: Runtime,
+}
+
+impl Peers {
+    fn start(count: usize) -> Self {
+        let network = MemoryNetwork::new(42);
+        let dir = std::env::temp_dir().join(format!("mojika-test-{}", Uuid::new_v4()));
+        let apps: Vec<_> = (0..count)
+            .map(|i| {
+                let config = AppConfig {
+                    download_dir: Some(dir.join(i.to_string())),
+                    state_dir: Some(dir.join(format!("{i}-state"))),
+                    network: Network::Memory(network.clone()),
+                    ..AppConfig::default()
+                };
+                let app = Arc::new(App::new(config).unwrap());
+                app.clone().start_headless();
+                app
+            })
+            .collect();
+        let runtime = Runtime::new().unwrap();
+        let peers = Self {
+            network,
+            apps,
+            dir,
+            runtime,
+        };
+        peers.wait_until("everyone is connected to everyone", || async {
+            for app in peers.apps.iter() {
+                let known = app.peers.read().await.all().await;
+                // A peer's requests are only taken once it has connected with its secret.
+                if known.len() != count - 1 || known.iter().any(|p| p.secret.is_empty()) {
+                    return false;
+                }
+            }
+            true
+        });
+        peers
+    }
+
+    fn id(&self, i: usize) -> String {
+        self.apps[i].self_peer().id.clone()
+    }
+
+    /// Polls `condition` until it holds, failing the test after [`WAIT_LIMIT`].
+    fn wait_until<F, Fut>(&self, what: &str, condition: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        self.runtime.block_on(async {
+            let start = Instant::now();
+            while !condition().await {
+                assert!(
+                    start.elapsed() < WAIT_LIMIT,
+                    "timed out waiting until {what}"
+                );
+                sleep(Duration::from_millis(20)).await;
+            }
+        });
+    }
+
+    /// The texts `receiver` got from `sender`.
+    async fn texts(&self, receiver: usize, sender: usize) -> Vec<String> {
+        let sender_id = self.id(sender);
+        let peers = self.apps[receiver].peers.read().await;
+        let peer = peers.find_by_id(&sender_id).await.unwrap();
+        peer.chat
+            .messages
+            .into_iter()
+            .filter(|m| m.sender == sender_id)
+            .filter_map(|m| match m.content {
+                Content::Text { text } => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The names of the files in the chat of `app` with `peer`.
+    async fn filenames(&self, app: usize, peer: usize) -> Vec<String> {
+        let peer_id = self.id(peer);
+        let peers = self.apps[app].peers.read().await;
+        let peer = peers.find_by_id(&peer_id).await.unwrap();
+        peer.chat
+            .messages
+            .into_iter()
+            .filter_map(|m| match m.content {
+                Content::File { filename, .. } => Some(filename),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// What is left of the transfers in the state dir of `app`.
+    fn leftovers(&self, app: usize) -> Vec<String> {
+        fs::read_dir(self.apps[app].get_state_dir())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".mojika"))
+            .collect()
+    }
+
+    fn send_chat(&self, from: usize, to: usize, text: &str) {
+        let from_id = self.id(from);
+        self.apps[from].send_chat(&self.id(to), &from_id, text.to_string());
+    }
+}
+
+impl Drop for Peers {
+    fn drop(&mut self) {
+        for app in self.apps.iter() {
+            app.shutdown();
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn many_peers_discover_each_other_and_chat() {
+    let peers = Peers::start(6);
+    for to in 1..6 {
+        peers.send_chat(0, to, &format!("hello {to}"));
+    }
+    peers.wait_until("every chat arrives", || async {
+        for to in 1..6 {
+            if peers.texts(to, 0).await != [format!("hello {to}")] {
+                return false;
+            }
+        }
+        true
+    });
+}
+
+#[test]
+fn file_arrives_complete_over_a_slow_link() {
+    let peers = Peers::start(3);
+    peers.network.set_conditions(LinkConditions {
+        loss: 0.0,
+        delay: Duration::from_millis(5),
+    });
+    let content: Vec<u8> = (0..700_000u32).map(|i| (i % 251) as u8).collect();
+    let file_path = peers.dir.join("to-send.bin");
+    fs::write(&file_path, &content).unwrap();
+
+    let sender_id = peers.id(0);
+    peers.apps[0].send_file(&peers.id(2), &sender_id, file_path);
+
+    let received = peers.apps[2].get_mojika_dir().join("to-send.bin");
+    peers.wait_until("the file is finished", || async { received.exists() });
+    assert_eq!(fs::read(&received).unwrap(), content);
+}
+
+#[test]
+fn taken_name_is_renamed_and_told_to_the_sender() {
+    let peers = Peers::start(2);
+    let mojika_dir = peers.apps[1].get_mojika_dir();
+    fs::write(mojika_dir.join("report.txt"), "kept").unwrap();
+    let file_path = peers.dir.join("report.txt");
+    fs::write(&file_path, "sent").unwrap();
+    peers.apps[0].send_file(&peers.id(1), &peers.id(0), file_path);
+
+    peers.wait_until("both chats show the new name", || async {
+        peers.filenames(0, 1).await == ["report (1).txt"]
+            && peers.filenames(1, 0).await == ["report (1).txt"]
+    });
+    assert_eq!(
+        fs::read_to_string(mojika_dir.join("report.txt")).unwrap(),
+        "kept"
+    );
+    let renamed = mojika_dir.join("report (1).txt");
+    assert_eq!(fs::read_to_string(renamed).unwrap(), "sent");
+}
+
+#[test]
+fn simultaneous_files_get_ids_of_their_own() {
+    let peers = Peers::start(3);
+    let receiver_id = peers.id(2);
+    let mut sent = Vec::new();
+    for from in 0..2 {
+        for i in 0..3 {
+            let filename = format!("from-{from}-{i}.bin");
+            let content = vec![(from * 3 + i) as u8; 50_000 + i * 1000];
+            let file_path = peers.dir.join(&filename);
+            fs::write(&file_path, &content).unwrap();
+            peers.apps[from].send_file(&receiver_id, &peers.id(from), file_path);
+            sent.push((from, filename, content));
+        }
+    }
+
+    let peers = &peers;
+    let file_ids = |app: usize, peer: usize| async move {
+        let app_peers = peers.apps[app].peers.read().await;
+        let peer = app_peers.find_by_id(&peers.id(peer)).await.unwrap();
+        let mut ids: Vec<_> = peer
+            .chat
+            .messages
+            .into_iter()
+            .filter_map(|m| match m.content {
+                Content::File { file_id, .. } => Some(file_id),
+                _ => None,
+            })
+            .collect();
+        ids.sort();
+        ids
+    };
+    let mojika_dir = peers.apps[2].get_mojika_dir();
+    peers.wait_until("every file is finished", || async {
+        sent.iter()
+            .all(|(_, filename, _)| mojika_dir.join(filename).exists())
+    });
+    for (_, filename, content) in sent.iter() {
+        assert_eq!(&fs::read(mojika_dir.join(filename)).unwrap(), content);
+    }
+    peers.runtime.block_on(async {
+        let mut all_ids = Vec::new();
+        for from in 0..2 {
+            // The sender knows each file by the id the receiver gave it.
+            let sender_ids = file_ids(from, 2).await;
+            assert_eq!(sender_ids.len(), 3);
+            assert_eq!(sender_ids, file_ids(2, from).await);
+            all_ids.extend(sender_ids);
+        }
+        all_ids.sort();
+        all_ids.dedup();
+        assert_eq!(all_ids.len(), 6);
+    });
+}
+
+#[test]
+fn disconnected_peer_gets_chats_after_reconnecting() {
+    let peers = Peers::start(3);
+    let address = peers.apps[1].self_peer().address;
+    peers.network.disconnect(address);
+    peers.send_chat(0, 1, "lost");
+    peers.send_chat(0, 2, "delivered");
+    peers.wait_until("the connected peer gets its chat", || async {
+        peers.texts(2, 0).await == ["delivered"]
+    });
+    peers.runtime.block_on(async {
+        // Let the retries of the lost chat run out.
+        sleep(Duration::from_secs(3)).await;
+        assert!(peers.texts(1, 0).await.is_empty());
+    });
+
+    peers.network.reconnect(address);
+    peers.send_chat(0, 1, "after");
+    peers.wait_until("the reconnected peer gets the new chat", || async {
+        peers.texts(1, 0).await == ["after"]
+    });
+}
+
+#[test]
+fn interrupted_file_resumes_where_the_receiver_left_off() {
+    let peers = Peers::start(2);
+    peers.network.set_conditions(LinkConditions {
+        loss: 0.0,
+        delay: Duration::from_millis(100),
+    });
+    let content: Vec<u8> = (0..700_000u32).map(|i| (i % 251) as u8).collect();
+    let file_path = peers.dir.join("to-resume.bin");
+    fs::write(&file_path, &content).unwrap();
+    let outgoing = peers.apps[0].watch_outgoing_files();
+
+    let sender_id = peers.id(0);
+    peers.apps[0].send_file(&peers.id(1), &sender_id, file_path);
+    peers.wait_until("the file is being sent", || async {
+        !outgoing.borrow().is_empty()
+    });
+    let file_id = outgoing.borrow().keys().next().unwrap().clone();
+    let partial = peers.apps[1]
+        .get_state_dir()
+        .join(format!("{file_id}.mojika"));
+    peers.wait_until("the first chunk is written", || async {
+        fs::metadata(&partial).is_ok_and(|m| m.len() > 0)
+    });
+
+    let address = peers.apps[1].self_peer().address;
+    peers.network.disconnect(address);
+    peers.wait_until("the transfer is interrupted", || async {
+        outgoing.borrow()[&file_id].state == OutgoingState::Interrupted
+    });
+    let received = peers.apps[1].get_mojika_dir().join("to-resume.bin");
+    assert!(!received.exists());
+
+    peers.network.reconnect(address);
+    peers.apps[0].control_outgoing(&file_id, TransferAction::Resume);
+    peers.wait_until("the resumed file is finished", || async {
+        outgoing.borrow().is_empty()
+    });
+    assert_eq!(fs::read(&received).unwrap(), content);
+}
+
+#[test]
+fn folder_arrives_with_its_tree() {
+    let peers = Peers::start(2);
+    let folder = peers.dir.join("project");
+    fs::create_dir_all(folder.join("src/empty")).unwrap();
+    let big: Vec<u8> = (0..450_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(folder.join("README.md"), "hello").unwrap();
+    fs::write(folder.join("src/main.rs"), "fn main() {}").unwrap();
+    fs::write(folder.join("src/data.bin"), &big).unwrap();
+    fs::write(folder.join("src/empty.txt"), "").unwrap();
+    #[cfg(unix)]
+    fs::set_permissions(folder.join("src"), fs::Permissions::from_mode(0o555)).unwrap();
+
+    let sender_id = peers.id(0);
+    peers.apps[0].send_file(&peers.id(1), &sender_id, folder);
+
+    let received = peers.apps[1].get_mojika_dir().join("project");
+    let outgoing = peers.apps[0].watch_outgoing_files();
+    peers.wait_until("every file of the folder is finished", || async {
+        received.join("src/data.bin").exists() && outgoing.borrow().is_empty()
+    });
+    assert_eq!(fs::read(received.join("README.md")).unwrap(), b"hello");
+    assert_eq!(
+        fs::read(received.join("src/main.rs")).unwrap(),
+        b"fn main() {}"
+    );
+    assert_eq!(fs::read(received.join("src/data.bin")).unwrap(), big);
+    assert!(fs::read(received.join("src/empty.txt")).unwrap().is_empty());
+    assert!(received.join("src/empty").is_dir());
+    #[cfg(unix)]
+    {
+        let src = received.join("src");
+        let mode = fs::metadata(&src).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o555);
+        // Lets the test dir be removed.
+        let writable = fs::Permissions::from_mode(0o755);
+        fs::set_permissions(src, writable.clone()).unwrap();
+        fs::set_permissions(peers.dir.join("project/src"), writable).unwrap();
+    }
+    let leftovers = peers.leftovers(1);
+    assert!(leftovers.is_empty(), "{leftovers:?}");
+}
+
+#[test]
+fn batch_is_sent_only_once_accepted() {
+    let peers = Peers::start(2);
+    let selected = peers.dir.join("selected");
+    fs::create_dir_all(&selected).unwrap();
+    let big: Vec<u8> = (0..300_000u32).map(|i| (i % 241) as u8).collect();
+    fs::write(selected.join("a.txt"), "first").unwrap();
+    fs::write(selected.join("b.bin"), &big).unwrap();
+    fs::write(selected.join("empty.txt"), "").unwrap();
+    let paths: Vec<_> = ["a.txt", "b.bin", "empty.txt"]
+        .iter()
+        .map(|name| selected.join(name))
+        .collect();
+    let sender_id = peers.id(0);
+    let receiver_id = peers.id(1);
+    let offers = peers.apps[1].watch_offers();
+    let received = peers.apps[1].get_mojika_dir().clone();
+
+    peers.apps[0].send_files(&receiver_id, &sender_id, paths.clone());
+    peers.wait_until("the batch is offered", || async {
+        !offers.borrow().is_empty()
+    });
+    let (batch_id, offer) = offers
+        .borrow()
+        .iter()
+        .next()
+        .map(|(id, o)| (id.clone(), o.clone()))
+        .unwrap();
+    assert_eq!(offer.peer_id, sender_id);
+    assert_eq!(offer.batch.files.len(), 3);
+    peers.apps[1].answer_offer(&batch_id, false);
+    peers.wait_until("the sender knows it is declined", || async {
+        let sender_peers = peers.apps[0].peers.read().await;
+        let receiver = sender_peers.find_by_id(&receiver_id).await.unwrap();
+        receiver.chat.messages.iter().any(
+            |m| matches!(&m.content, Content::File { progress, .. } if progress == "Declined."),
+        )
+    });
+    assert!(offers.borrow().is_empty());
+    assert!(!received.join("a.txt").exists());
+    assert!(!received.join("empty.txt").exists());
+
+    peers.apps[0].send_files(&receiver_id, &sender_id, paths);
+    peers.wait_until("the batch is offered again", || async {
+        !offers.borrow().is_empty()
+    });
+    let batch_id = offers.borrow().keys().next().unwrap().clone();
+    peers.apps[1].answer_offer(&batch_id, true);
+    let outgoing = peers.apps[0].watch_outgoing_files();
+    peers.wait_until("every file of the batch is finished", || async {
+        received.join("b.bin").exists() && outgoing.borrow().is_empty()
+    });
+    assert_eq!(fs::read(received.join("a.txt")).unwrap(), b"first");
+    assert_eq!(fs::read(received.join("b.bin")).unwrap(), big);
+    assert!(fs::read(received.join("empty.txt")).unwrap().is_empty());
+}
+
+#[test]
+fn small_file_is_not_held_back_by_a_big_one() {
+    let peers = Peers::start(3);
+    peers.network.set_conditions(LinkConditions {
+        loss: 0.0,
+        delay: Duration::from_millis(10),
+    });
+    let big: Vec<u8> = (0..8_000_000u32).map(|i| (i % 251) as u8).collect();
+    let big_path = peers.dir.join("big.bin");
+    fs::write(&big_path, &big).unwrap();
+    let small_path = peers.dir.join("small.txt");
+    fs::write(&small_path, "small").unwrap();
+
+    let sender_id = peers.id(0);
+    peers.apps[0].send_file(&peers.id(1), &sender_id, big_path);
+    let outgoing = peers.apps[0].watch_outgoing_files();
+    peers.wait_until("the big file is being sent", || async {
+        !outgoing.borrow().is_empty()
+    });
+    peers.apps[0].send_file(&peers.id(2), &sender_id, small_path);
+
+    let big_received = peers.apps[1].get_mojika_dir().join("big.bin");
+    let small_received = peers.apps[2].get_mojika_dir().join("small.txt");
+    peers.wait_until("the small file is finished", || async {
+        small_received.exists()
+    });
+    assert!(!big_received.exists());
+    peers.wait_until("the big file is finished", || async {
+        big_received.exists()
+    });
+    assert_eq!(fs::read(&big_received).unwrap(), big);
+}
+
+#[test]
+fn transfers_are_paused_and_cancelled_from_either_side() {
+    let peers = Peers::start(2);
+    peers.network.set_conditions(LinkConditions {
+        loss: 0.0,
+        delay: Duration::from_millis(20),
+    });
+    let content: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+    let file_path = peers.dir.join("controlled.bin");
+    fs::write(&file_path, &content).unwrap();
+    let outgoing = peers.apps[0].watch_outgoing_files();
+    let incoming = peers.apps[1].watch_incoming_files();
+    let received = peers.apps[1].get_mojika_dir().join("controlled.bin");
+    let sender_id = peers.id(0);
+    let offset = |file_id: &str| incoming.borrow().get(file_id).map(|i| i.content_offset);
+
+    peers.apps[0].send_file(&peers.id(1), &sender_id, file_path.clone());
+    peers.wait_until("the file is being received", || async {
+        incoming.borrow().values().any(|i| i.content_offset > 0)
+    });
+    let file_id = incoming.borrow().keys().next().unwrap().clone();
+    peers.apps[1].control_incoming(&file_id, TransferAction::Pause);
+    peers.wait_until("the sender knows it is paused", || async {
+        outgoing.borrow()[&file_id].state == OutgoingState::Paused
+    });
+    let paused_at = offset(&file_id);
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(offset(&file_id), paused_at);
+    assert!(incoming.borrow()[&file_id].paused);
+
+    peers.apps[0].control_outgoing(&file_id, TransferAction::Resume);
+    peers.wait_until("the resumed file is finished", || async {
+        outgoing.borrow().is_empty()
+    });
+    assert_eq!(fs::read(&received).unwrap(), content);
+    fs::remove_file(&received).unwrap();
+
+    peers.apps[0].send_file(&peers.id(1), &sender_id, file_path);
+    peers.wait_until("the file is being received again", || async {
+        incoming.borrow().values().any(|i| i.content_offset > 0)
+    });
+    let file_id = incoming.borrow().keys().next().unwrap().clone();
+    peers.apps[0].control_outgoing(&file_id, TransferAction::Cancel);
+    peers.wait_until("the receiver drops the file", || async {
+        incoming.borrow().is_empty() && outgoing.borrow().is_empty()
+    });
+    assert!(!received.exists());
+    let leftovers = peers.leftovers(1);
+    assert!(leftovers.is_empty(), "{leftovers:?}");
+}
+
+#[test]
+fn progress_of_a_received_file_is_shown_in_the_chat() {
+    let peers = Peers::start(2);
+    peers.network.set_conditions(LinkConditions {
+        loss: 0.0,
+        delay: Duration::from_millis(20),
+    });
+    let content: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    let file_path = peers.dir.join("watched.bin");
+    fs::write(&file_path, &content).unwrap();
+    let sender_id = peers.id(0);
+    peers.apps[0].send_file(&peers.id(1), &sender_id, file_path);
+
+    let transfer = || async {
+        let receiver_peers = peers.apps[1].peers.read().await;
+        let sender = receiver_peers.find_by_id(&sender_id).await.unwrap();
+        sender.chat.messages.iter().find_map(|m| match &m.content {
+            Content::File { transfer, .. } => transfer.clone(),
+            _ => None,
+        })
+    };
+    peers.wait_until("the file is received at some rate", || async {
+        transfer()
+            .await
+            .is_some_and(|t| t.state == TransferState::Running && t.rate > 0.0)
+    });
+    let running = peers.runtime.block_on(transfer()).unwrap();
+    assert_eq!(running.direction, Direction::Receiving);
+    assert_eq!(running.bytes_total, content.len() as u64);
+    assert!(running.fraction() < 1.0);
+    peers.wait_until("the file is completed", || async {
+        transfer()
+            .await
+            .is_some_and(|t| t.state == TransferState::Completed)
+    });
+}
+
+#[test]
+fn restarted_app_keeps_its_id() {
+    let network = MemoryNetwork::new(7);
+    let dir = std::env::temp_dir().join(format!("mojika-test-{}", Uuid::new_v4()));
+    let config = AppConfig {
+        download_dir: Some(dir.clone()),
+        state_dir: Some(dir.join("state")),
+        network: Network::Memory(network),
+        ..AppConfig::default()
+    };
+    let first = App::new(config.clone()).unwrap().self_peer().clone();
+    let restarted = App::new(config.clone()).unwrap().self_peer().clone();
+    assert_eq!(restarted.id, first.id);
+    assert_eq!(restarted.secret, first.secret);
+    #[cfg(unix)]
+    {
+        let identity = fs::metadata(dir.join("state").join("identity.ron")).unwrap();
+        assert_eq!(identity.permissions().mode() & 0o777, 0o600);
+    }
+
+    // An identity that can't be read is replaced instead of stopping the app.
+    fs::write(dir.join("state").join("identity.ron"), "garbage").unwrap();
+    let renewed = App::new(config).unwrap().self_peer().clone();
+    assert_ne!(renewed.id, first.id);
+    fs::remove_dir_all(dir).unwrap();
+}